@@ -0,0 +1,32 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use jmux_proxy::FilteringRule;
+
+fn large_rule_set(size: usize) -> FilteringRule {
+    (0..size).fold(FilteringRule::Deny, |rule, idx| {
+        rule.or(FilteringRule::host_and_port(format!("host-{idx}.example.com"), 443))
+            .or(FilteringRule::wildcard_host(format!("*.zone-{idx}.example.net")))
+    })
+}
+
+fn filtering_benchmark(c: &mut Criterion) {
+    let rule = large_rule_set(5000);
+    let compiled = rule.compile();
+
+    // Worst case for the linear matcher: the matching rule is the last one.
+    let destination = "tcp://some-host.zone-4999.example.net:443";
+
+    let mut group = c.benchmark_group("filtering");
+
+    group.bench_function("linear", |b| {
+        b.iter(|| rule.validate_destination_str(black_box(destination)).is_ok())
+    });
+
+    group.bench_function("compiled", |b| {
+        b.iter(|| compiled.validate_destination_str(black_box(destination)).is_ok())
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, filtering_benchmark);
+criterion_main!(benches);
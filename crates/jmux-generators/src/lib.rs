@@ -11,6 +11,15 @@ pub fn distant_channel_id() -> impl Strategy<Value = DistantChannelId> {
 }
 
 pub fn destination_url_parts() -> impl Strategy<Value = (String, String, u16)> {
+    (
+        "[a-zA-Z][a-zA-Z0-9+.-]{0,4}",
+        "[a-zA-Z0-9._~!$&'()*+,;=-]{1,10}",
+        any::<u16>(),
+    )
+}
+
+/// Parts of a destination URL which are only structurally valid (any charset)
+pub fn lenient_destination_url_parts() -> impl Strategy<Value = (String, String, u16)> {
     (".{1,5}", ".{1,10}", any::<u16>())
 }
 
@@ -46,8 +55,16 @@ pub fn message_window_adjust() -> impl Strategy<Value = Message> {
 }
 
 pub fn message_data() -> impl Strategy<Value = Message> {
-    (distant_channel_id(), vec(any::<u8>(), 0..512))
-        .prop_map(|(distant_id, data)| Message::data(distant_id, Bytes::from(data)))
+    (
+        distant_channel_id(),
+        proptest::option::of(any::<u32>()),
+        vec(any::<u8>(), 0..512),
+    )
+        .prop_map(|(distant_id, sequence_number, data)| {
+            let mut data = ChannelData::new(distant_id, Bytes::from(data));
+            data.sequence_number = sequence_number;
+            Message::Data(data)
+        })
 }
 
 pub fn message_eof() -> impl Strategy<Value = Message> {
@@ -58,8 +75,13 @@ pub fn message_close() -> impl Strategy<Value = Message> {
     distant_channel_id().prop_map(Message::close)
 }
 
+pub fn message_capabilities() -> impl Strategy<Value = Message> {
+    any::<u32>().prop_map(Message::capabilities)
+}
+
 pub fn any_message() -> impl Strategy<Value = Message> {
     prop_oneof![
+        message_capabilities(),
         message_close(),
         message_eof(),
         message_window_adjust(),
@@ -1,7 +1,18 @@
 //! [Specification document][source]
 //!
 //! [source]: https://github.com/Devolutions/devolutions-gateway/blob/master/docs/JMUX-spec.md
+//!
+//! The crate is `no_std` compatible (an allocator is still required) when the default `std` feature is disabled.
+//! Only the conversions from `std::io` errors into reason codes are not available in this case.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
+use alloc::borrow::ToOwned as _;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
 use bytes::{Buf as _, BufMut as _};
 use core::fmt;
 use smol_str::SmolStr;
@@ -55,8 +66,10 @@ impl fmt::Display for LocalChannelId {
 
 /// JMUX destination URL
 ///
-/// Note that this is not checking for allowed charset specified by RFC 3986 but merely validating
-/// the inner string is formatted such as: <scheme>://<host>:<port>
+/// The inner string is formatted such as: <scheme>://<host>:<port>
+///
+/// When parsed using [`DestinationUrl::parse_str`], the scheme and the host are restricted to the charset
+/// specified by RFC 3986. Use [`DestinationUrl::parse_str_lenient`] to only validate the structure.
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct DestinationUrl {
     inner: SmolStr,
@@ -75,7 +88,74 @@ impl DestinationUrl {
         }
     }
 
+    /// Parses a destination URL, rejecting schemes and hosts with characters not allowed by RFC 3986
+    ///
+    /// In particular, spaces and control characters are rejected.
     pub fn parse_str(s: &str) -> Result<Self, Error> {
+        let url = Self::parse_str_lenient(s)?;
+
+        if !is_valid_scheme(&url.scheme) {
+            return Err(Error::InvalidDestinationUrl {
+                value: s.to_owned(),
+                reason: "invalid character in scheme",
+            });
+        }
+
+        if !is_valid_host(&url.host) {
+            return Err(Error::InvalidDestinationUrl {
+                value: s.to_owned(),
+                reason: "invalid character in host",
+            });
+        }
+
+        Ok(url)
+    }
+
+    /// Same as [`DestinationUrl::parse_str`], but for raw bytes such as the ones received from the peer
+    ///
+    /// Bytes that are not valid UTF-8 are rejected with the same error kind as the other defects.
+    pub fn parse_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let s = core::str::from_utf8(bytes).map_err(|_| Error::InvalidDestinationUrl {
+            value: String::from_utf8_lossy(bytes).into_owned(),
+            reason: "not valid UTF-8",
+        })?;
+
+        Self::parse_str(s)
+    }
+
+    /// Same as [`DestinationUrl::parse_str`], but the port may be omitted when the scheme has a default port
+    ///
+    /// This is meant for user input (e.g.: `ssh://devolutions.net` is parsed as `ssh://devolutions.net:22`).
+    pub fn parse_with_defaults(s: &str, default_ports: &DefaultPorts) -> Result<Self, Error> {
+        let scheme_end_idx = s.find("://").ok_or_else(|| Error::InvalidDestinationUrl {
+            value: s.to_owned(),
+            reason: "scheme is missing",
+        })?;
+        let scheme = &s[..scheme_end_idx];
+        let rest = &s[scheme_end_idx + "://".len()..];
+
+        // The colons of an IPv6 literal are not a port separator.
+        let has_port = match rest.strip_prefix('[') {
+            Some(ip_literal_and_rest) => ip_literal_and_rest
+                .find(']')
+                .is_some_and(|end_idx| ip_literal_and_rest[end_idx + 1..].starts_with(':')),
+            None => rest.contains(':'),
+        };
+
+        if has_port {
+            return Self::parse_str(s);
+        }
+
+        let port = default_ports.get(scheme).ok_or_else(|| Error::InvalidDestinationUrl {
+            value: s.to_owned(),
+            reason: "port is missing and there is no default port for this scheme",
+        })?;
+
+        Self::parse_str(&format!("{s}:{port}"))
+    }
+
+    /// Parses a destination URL, only validating its structure
+    pub fn parse_str_lenient(s: &str) -> Result<Self, Error> {
         let scheme_end_idx = s.find("://").ok_or_else(|| Error::InvalidDestinationUrl {
             value: s.to_owned(),
             reason: "scheme is missing",
@@ -127,12 +207,140 @@ impl DestinationUrl {
     }
 }
 
+/// Ports implied by the schemes when a destination URL specifies none (e.g.: `https` → 443)
+///
+/// The default table covers the well-known application protocols, and entries can be added or overridden.
+///
+/// ```
+/// use jmux_proto::{DefaultPorts, DestinationUrl};
+///
+/// let default_ports = DefaultPorts::default().with("ssh", 2222);
+///
+/// let url = DestinationUrl::parse_with_defaults("ssh://devolutions.net", &default_ports).unwrap();
+/// assert_eq!(url.port(), 2222);
+///
+/// let url = DestinationUrl::parse_with_defaults("https://devolutions.net", &default_ports).unwrap();
+/// assert_eq!(url.port(), 443);
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DefaultPorts {
+    entries: Vec<(SmolStr, u16)>,
+}
+
+impl Default for DefaultPorts {
+    fn default() -> Self {
+        Self {
+            entries: Self::WELL_KNOWN
+                .iter()
+                .map(|(scheme, port)| (SmolStr::new_static(scheme), *port))
+                .collect(),
+        }
+    }
+}
+
+impl DefaultPorts {
+    /// Default ports of the application protocols known by the Devolutions Gateway.
+    pub const WELL_KNOWN: &'static [(&'static str, u16)] = &[
+        ("wayk", 12876),
+        ("rdp", 3389),
+        ("ard", 5900),
+        ("vnc", 5900),
+        ("ssh", 22),
+        ("ssh-pwsh", 22),
+        ("sftp", 22),
+        ("scp", 22),
+        ("telnet", 23),
+        ("winrm-http-pwsh", 5985),
+        ("winrm-https-pwsh", 5986),
+        ("http", 80),
+        ("https", 443),
+        ("ldap", 389),
+        ("ldaps", 636),
+    ];
+
+    /// A table without any entry.
+    pub fn empty() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    /// Sets the default port of `scheme`, replacing the previous one if any.
+    #[must_use]
+    pub fn with(mut self, scheme: &str, port: u16) -> Self {
+        match self.entry_mut(scheme) {
+            Some(entry) => entry.1 = port,
+            None => self.entries.push((SmolStr::new(scheme.to_ascii_lowercase()), port)),
+        }
+
+        self
+    }
+
+    /// Returns the default port of `scheme`, matched case-insensitively.
+    pub fn get(&self, scheme: &str) -> Option<u16> {
+        self.entries
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(scheme))
+            .map(|(_, port)| *port)
+    }
+
+    fn entry_mut(&mut self, scheme: &str) -> Option<&mut (SmolStr, u16)> {
+        self.entries
+            .iter_mut()
+            .find(|(known, _)| known.eq_ignore_ascii_case(scheme))
+    }
+}
+
+/// scheme = ALPHA *( ALPHA / DIGIT / "+" / "-" / "." )
+fn is_valid_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() => {
+            chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }
+        _ => false,
+    }
+}
+
+/// host = IP-literal / IPv4address / reg-name
+///
+/// IPv4 addresses are a subset of reg-name, and IP literals are only checked for the allowed characters.
+fn is_valid_host(host: &str) -> bool {
+    if let Some(ip_literal) = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        return !ip_literal.is_empty()
+            && ip_literal
+                .chars()
+                .all(|c| c.is_ascii_hexdigit() || matches!(c, ':' | '.'));
+    }
+
+    // reg-name = *( unreserved / pct-encoded / sub-delims )
+    host.chars().all(|c| {
+        c.is_ascii_alphanumeric()
+            || matches!(
+                c,
+                // unreserved
+                '-' | '.' | '_' | '~'
+                // pct-encoded
+                | '%'
+                // sub-delims
+                | '!' | '$' | '&' | '\'' | '(' | ')' | '*' | '+' | ',' | ';' | '='
+            )
+    })
+}
+
 impl fmt::Display for DestinationUrl {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.inner.fmt(f)
     }
 }
 
+impl TryFrom<&[u8]> for DestinationUrl {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Self::parse_bytes(bytes)
+    }
+}
+
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum Error {
@@ -156,7 +364,7 @@ pub enum Error {
     },
 }
 
-impl std::error::Error for Error {}
+impl core::error::Error for Error {}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -211,6 +419,7 @@ pub enum Message {
     Data(ChannelData),
     Eof(ChannelEof),
     Close(ChannelClose),
+    Capabilities(Capabilities),
 }
 
 impl Message {
@@ -252,6 +461,10 @@ impl Message {
         Self::Close(ChannelClose::new(distant_id))
     }
 
+    pub fn capabilities(features: u32) -> Self {
+        Self::Capabilities(Capabilities::new(features))
+    }
+
     pub fn size(&self) -> usize {
         match self {
             Message::Open(msg) => Header::SIZE + msg.size(),
@@ -261,12 +474,33 @@ impl Message {
             Message::Data(msg) => Header::SIZE + msg.size(),
             Message::Eof(_) => Header::SIZE + ChannelEof::SIZE,
             Message::Close(_) => Header::SIZE + ChannelClose::SIZE,
+            Message::Capabilities(_) => Header::SIZE + Capabilities::SIZE,
         }
     }
 
+    /// Number of bytes required to encode this message (header included).
+    pub fn encoded_len(&self) -> usize {
+        self.size()
+    }
+
+    /// Encodes this message into a new buffer allocated with the exact required size.
+    pub fn encode_to_vec(&self) -> Result<Bytes, Error> {
+        let mut buf = BytesMut::with_capacity(self.size());
+        self.encode(&mut buf)?;
+        Ok(buf.freeze())
+    }
+
     pub fn encode(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        self.encode_with_max_url_size(buf, ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE)
+    }
+
+    /// Same as [`Message::encode`], but with a custom maximum size for the destination URL.
+    pub fn encode_with_max_url_size(&self, buf: &mut BytesMut, max_destination_url_size: usize) -> Result<(), Error> {
         macro_rules! reserve_and_encode_header {
             ($buf:ident, $len:expr, $ty:expr) => {
+                reserve_and_encode_header!($buf, $len, $ty, 0);
+            };
+            ($buf:ident, $len:expr, $ty:expr, $flags:expr) => {
                 let len = $len;
                 if $buf.len() < len {
                     $buf.reserve(len - $buf.len());
@@ -277,7 +511,7 @@ impl Message {
                         packet_size: len,
                         max: usize::from(u16::MAX),
                     })?,
-                    flags: 0,
+                    flags: $flags,
                 };
                 header.encode(buf);
             };
@@ -285,6 +519,14 @@ impl Message {
 
         match self {
             Message::Open(msg) => {
+                if msg.destination_url.as_bytes().len() > max_destination_url_size {
+                    return Err(Error::InvalidPacket {
+                        name: ChannelOpen::NAME,
+                        field: "destinationUrl",
+                        reason: "too long",
+                    });
+                }
+
                 reserve_and_encode_header!(buf, Header::SIZE + msg.size(), MessageType::Open);
                 msg.encode(buf)
             }
@@ -301,7 +543,13 @@ impl Message {
                 msg.encode(buf)
             }
             Message::Data(msg) => {
-                reserve_and_encode_header!(buf, Header::SIZE + msg.size(), MessageType::Data);
+                let flags = if msg.sequence_number.is_some() {
+                    Header::FLAG_SEQUENCED
+                } else {
+                    0
+                };
+
+                reserve_and_encode_header!(buf, Header::SIZE + msg.size(), MessageType::Data, flags);
                 msg.encode(buf)
             }
             Message::Eof(msg) => {
@@ -312,12 +560,69 @@ impl Message {
                 reserve_and_encode_header!(buf, Header::SIZE + ChannelClose::SIZE, MessageType::Close);
                 msg.encode(buf)
             }
+            Message::Capabilities(msg) => {
+                reserve_and_encode_header!(buf, Header::SIZE + Capabilities::SIZE, MessageType::Capabilities);
+                msg.encode(buf)
+            }
         }
 
         Ok(())
     }
 
-    pub fn decode(mut buf: Bytes) -> Result<Self, Error> {
+    /// Same as [`Message::encode`], but the destination URL of CHANNEL OPEN messages and the description
+    /// of CHANNEL OPEN FAILURE messages are compressed when it makes the message smaller.
+    ///
+    /// Only meant to be used when the peer is known to support compressed fields (see [`Header::FLAG_COMPRESSED`]).
+    /// Other messages are encoded as usual.
+    pub fn encode_compressed(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        self.encode_compressed_with_max_url_size(buf, ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE)
+    }
+
+    /// Same as [`Message::encode_compressed`], but with a custom maximum size for the destination URL.
+    pub fn encode_compressed_with_max_url_size(
+        &self,
+        buf: &mut BytesMut,
+        max_destination_url_size: usize,
+    ) -> Result<(), Error> {
+        let (ty, field) = match self {
+            Message::Open(msg) => (MessageType::Open, msg.destination_url.as_bytes()),
+            Message::OpenFailure(msg) => (MessageType::OpenFailure, msg.description.as_bytes()),
+            _ => return self.encode_with_max_url_size(buf, max_destination_url_size),
+        };
+
+        let compressed = miniz_oxide::deflate::compress_to_vec(field, COMPRESSION_LEVEL);
+
+        if compressed.len() >= field.len() {
+            return self.encode_with_max_url_size(buf, max_destination_url_size);
+        }
+
+        // Checks the size limits on the uncompressed message.
+        let mut uncompressed = BytesMut::new();
+        self.encode_with_max_url_size(&mut uncompressed, max_destination_url_size)?;
+
+        let fixed_part_end = uncompressed.len() - field.len();
+        let len = fixed_part_end + compressed.len();
+
+        let header = Header {
+            ty,
+            size: u16::try_from(len).expect("smaller than the uncompressed message"),
+            flags: Header::FLAG_COMPRESSED,
+        };
+
+        buf.reserve(len);
+        header.encode(buf);
+        buf.put(&uncompressed[Header::SIZE..fixed_part_end]);
+        buf.put(compressed.as_slice());
+
+        Ok(())
+    }
+
+    pub fn decode(buf: Bytes) -> Result<Self, Error> {
+        Self::decode_with_max_url_size(buf, ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE)
+    }
+
+    /// Same as [`Message::decode`], but with a custom maximum size for the destination URL.
+    pub fn decode_with_max_url_size(mut buf: Bytes, max_destination_url_size: usize) -> Result<Self, Error> {
         ensure_size!(plain Header in buf);
 
         let header = Header::decode(buf.split_to(Header::SIZE))?;
@@ -330,20 +635,140 @@ impl Message {
         })?;
 
         ensure_size!(buf[body_size] for "BODY");
-        let body_bytes = buf.split_to(body_size);
+        let mut body_bytes = buf.split_to(body_size);
+
+        if header.flags & Header::FLAG_COMPRESSED != 0 {
+            body_bytes = match header.ty {
+                MessageType::Open => decompress_field(
+                    body_bytes,
+                    ChannelOpen::NAME,
+                    ChannelOpen::FIXED_PART_SIZE,
+                    "destinationUrl",
+                    max_destination_url_size,
+                )?,
+                MessageType::OpenFailure => decompress_field(
+                    body_bytes,
+                    ChannelOpenFailure::NAME,
+                    ChannelOpenFailure::FIXED_PART_SIZE,
+                    "description",
+                    usize::from(u16::MAX),
+                )?,
+                // The flag is meaningless for the other messages.
+                _ => body_bytes,
+            };
+        }
 
         let message = match header.ty {
-            MessageType::Open => Self::Open(ChannelOpen::decode(body_bytes)?),
+            MessageType::Open => Self::Open(ChannelOpen::decode_with_max_url_size(
+                body_bytes,
+                max_destination_url_size,
+            )?),
+            MessageType::Data if header.flags & Header::FLAG_SEQUENCED != 0 => {
+                Self::Data(ChannelData::decode_sequenced(body_bytes)?)
+            }
             MessageType::Data => Self::Data(ChannelData::decode(body_bytes)?),
             MessageType::OpenSuccess => Self::OpenSuccess(ChannelOpenSuccess::decode(body_bytes)?),
             MessageType::OpenFailure => Self::OpenFailure(ChannelOpenFailure::decode(body_bytes)?),
             MessageType::WindowAdjust => Self::WindowAdjust(ChannelWindowAdjust::decode(body_bytes)?),
             MessageType::Eof => Self::Eof(ChannelEof::decode(body_bytes)?),
             MessageType::Close => Self::Close(ChannelClose::decode(body_bytes)?),
+            MessageType::Capabilities => Self::Capabilities(Capabilities::decode(body_bytes)?),
         };
 
         Ok(message)
     }
+
+    /// Decodes all the messages concatenated in `buf`.
+    ///
+    /// See [`MessageDecoder`].
+    pub fn decode_all(buf: Bytes) -> MessageDecoder {
+        MessageDecoder::new(buf)
+    }
+}
+
+/// Compression level used for the compressed fields (between 0 and 10)
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// Returns the body with its trailing variable-length field decompressed.
+fn decompress_field(
+    mut body: Bytes,
+    name: &'static str,
+    fixed_part_size: usize,
+    field: &'static str,
+    max_size: usize,
+) -> Result<Bytes, Error> {
+    ensure_size!(body[fixed_part_size] for name);
+
+    let compressed = body.split_off(fixed_part_size);
+
+    let decompressed = miniz_oxide::inflate::decompress_to_vec_with_limit(&compressed, max_size).map_err(|_| {
+        Error::InvalidPacket {
+            name,
+            field,
+            reason: "invalid or too large compressed data",
+        }
+    })?;
+
+    let mut out = BytesMut::with_capacity(fixed_part_size + decompressed.len());
+    out.put(body);
+    out.put(decompressed.as_slice());
+
+    Ok(out.freeze())
+}
+
+/// Iterator over back-to-back JMUX messages held in a single buffer
+///
+/// Decoding stops at the first incomplete frame, which can be retrieved using [`MessageDecoder::remaining`].
+/// A frame with a valid size but an invalid content yields an error, and decoding resumes with the next frame.
+/// A frame whose size is smaller than the header is unrecoverable: an error is yielded, and the iteration ends.
+#[derive(Debug, Clone)]
+pub struct MessageDecoder {
+    buf: Bytes,
+    fused: bool,
+}
+
+impl MessageDecoder {
+    pub fn new(buf: Bytes) -> Self {
+        Self { buf, fused: false }
+    }
+
+    /// Bytes which were not decoded yet (e.g.: a partial frame at the end of the buffer).
+    pub fn remaining(&self) -> &Bytes {
+        &self.buf
+    }
+
+    pub fn into_remaining(self) -> Bytes {
+        self.buf
+    }
+}
+
+impl Iterator for MessageDecoder {
+    type Item = Result<Message, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.fused || self.buf.len() < Header::SIZE {
+            return None;
+        }
+
+        let total_size = usize::from(u16::from_be_bytes([self.buf[1], self.buf[2]]));
+
+        if total_size < Header::SIZE {
+            self.fused = true;
+            return Some(Err(Error::InvalidPacket {
+                name: Header::NAME,
+                field: "msgSize",
+                reason: "too small",
+            }));
+        }
+
+        if self.buf.len() < total_size {
+            return None;
+        }
+
+        let frame = self.buf.split_to(total_size);
+
+        Some(Message::decode(frame))
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -359,7 +784,9 @@ impl fmt::Display for ReasonCode {
             0x05 => "CONNECTION_REFUSED",
             0x06 => "TTL_EXPIRED",
             0x08 => "ADDRESS_TYPE_NOT_SUPPORTED",
-            0x00 | 0x07 | 0x09.. => "OTHER",
+            0x100 => "AUTHORIZATION_REVOKED",
+            0x101 => "SESSION_EXPIRED",
+            _ => "OTHER",
         };
         write!(f, "{} (0x{:08X})", desc, self.0)
     }
@@ -386,8 +813,56 @@ impl ReasonCode {
 
     /// Address type is not supported
     pub const ADDRESS_TYPE_NOT_SUPPORTED: Self = ReasonCode(0x08);
+
+    // Codes starting from 0x100 are not borrowed from SOCKS.
+    // Older peers are displaying them as "OTHER".
+
+    /// The authorization for this session was revoked
+    pub const AUTHORIZATION_REVOKED: Self = ReasonCode(0x100);
+
+    /// The session reached its maximum lifetime (e.g.: the token is expired)
+    pub const SESSION_EXPIRED: Self = ReasonCode(0x101);
+
+    /// Returns true when the failure is likely to be transient, and opening the channel again may succeed
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            Self::NETWORK_UNREACHABLE | Self::HOST_UNREACHABLE | Self::CONNECTION_REFUSED | Self::TTL_EXPIRED
+        )
+    }
+
+    /// Walks the source chain of `error` to find the most specific reason code
+    ///
+    /// The `std::io::Error`s found in the chain (including the ones wrapped by another `std::io::Error`) are mapped
+    /// using their kind. The innermost error mapping to something else than `GENERAL_FAILURE` wins.
+    #[cfg(feature = "std")]
+    pub fn from_error_chain(error: &(dyn std::error::Error + 'static)) -> ReasonCode {
+        let mut reason_code = ReasonCode::GENERAL_FAILURE;
+        let mut dyn_error: Option<&(dyn std::error::Error + 'static)> = Some(error);
+
+        while let Some(source_error) = dyn_error.take() {
+            if let Some(io_error) = source_error.downcast_ref::<std::io::Error>() {
+                let io_reason_code = ReasonCode::from(io_error.kind());
+
+                if io_reason_code != ReasonCode::GENERAL_FAILURE {
+                    reason_code = io_reason_code;
+                }
+
+                // `std::io::Error::source` is skipping the wrapped error itself.
+                if let Some(inner) = io_error.get_ref() {
+                    dyn_error = Some(inner);
+                    continue;
+                }
+            }
+
+            dyn_error = source_error.source();
+        }
+
+        reason_code
+    }
 }
 
+#[cfg(feature = "std")]
 impl From<std::io::ErrorKind> for ReasonCode {
     fn from(kind: std::io::ErrorKind) -> ReasonCode {
         match kind {
@@ -402,12 +877,14 @@ impl From<std::io::ErrorKind> for ReasonCode {
     }
 }
 
+#[cfg(feature = "std")]
 impl From<std::io::Error> for ReasonCode {
     fn from(e: std::io::Error) -> ReasonCode {
         ReasonCode::from(e.kind())
     }
 }
 
+#[cfg(feature = "std")]
 impl From<&std::io::Error> for ReasonCode {
     fn from(e: &std::io::Error) -> ReasonCode {
         ReasonCode::from(e.kind())
@@ -424,6 +901,7 @@ pub enum MessageType {
     Data = 104,
     Eof = 105,
     Close = 106,
+    Capabilities = 107,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -438,6 +916,7 @@ impl TryFrom<u8> for MessageType {
             104 => Ok(MessageType::Data),
             105 => Ok(MessageType::Eof),
             106 => Ok(MessageType::Close),
+            107 => Ok(MessageType::Capabilities),
             _ => Err(Error::InvalidPacket {
                 name: Header::NAME,
                 field: "msgType",
@@ -458,10 +937,23 @@ impl Header {
     pub const NAME: &'static str = "HEADER";
     pub const SIZE: usize = 1 /* msgType */ + 2 /* msgSize */ + 1 /* msgFlags */;
 
+    /// The trailing variable-length field of the message is compressed using DEFLATE.
+    ///
+    /// Only meaningful for CHANNEL OPEN (destination URL) and CHANNEL OPEN FAILURE (description) messages.
+    /// Peers not aware of this flag can't decode such messages, so it must only be set when the peer supports it.
+    pub const FLAG_COMPRESSED: u8 = 0x01;
+
+    /// A sequence number is inserted before the transfer data.
+    ///
+    /// Only meaningful for CHANNEL DATA messages, as a diagnostic to detect reordered or duplicated messages.
+    /// Peers not aware of this flag would consider the sequence number as transfer data, so it must only be set
+    /// when the peer supports it.
+    pub const FLAG_SEQUENCED: u8 = 0x02;
+
     pub fn encode(&self, buf: &mut BytesMut) {
         buf.put_u8(self.ty as u8);
         buf.put_u16(self.size);
-        buf.put_u8(0);
+        buf.put_u8(self.flags);
     }
 
     pub fn decode(mut buf: Bytes) -> Result<Self, Error> {
@@ -486,6 +978,10 @@ impl ChannelOpen {
     pub const NAME: &'static str = "CHANNEL OPEN";
     pub const DEFAULT_INITIAL_WINDOW_SIZE: u32 = 64 * 1024 * 1024; // 64 MiB
     pub const FIXED_PART_SIZE: usize = 4 /* senderChannelId */ + 4 /* initialWindowSize */ + 2 /* maximumPacketSize */;
+    /// Default maximum size for the destination URL, in bytes.
+    ///
+    /// This is much more than what any legitimate destination URL requires.
+    pub const DEFAULT_MAX_DESTINATION_URL_SIZE: usize = 4 * 1024; // 4 kiB
 
     pub fn new(id: LocalChannelId, maximum_packet_size: u16, destination_url: DestinationUrl) -> Self {
         Self {
@@ -507,19 +1003,34 @@ impl ChannelOpen {
         buf.put(self.destination_url.as_bytes());
     }
 
-    pub fn decode(mut buf: Bytes) -> Result<Self, Error> {
+    pub fn decode(buf: Bytes) -> Result<Self, Error> {
+        Self::decode_with_max_url_size(buf, Self::DEFAULT_MAX_DESTINATION_URL_SIZE)
+    }
+
+    /// Same as [`ChannelOpen::decode`], but with a custom maximum size for the destination URL.
+    pub fn decode_with_max_url_size(mut buf: Bytes, max_destination_url_size: usize) -> Result<Self, Error> {
         ensure_size!(fixed Self in buf);
 
         let sender_channel_id = buf.get_u32();
         let initial_window_size = buf.get_u32();
         let maximum_packet_size = buf.get_u16();
 
-        let destination_url = std::str::from_utf8(&buf).map_err(|_| Error::InvalidPacket {
-            name: Self::NAME,
-            field: "destinationUrl",
-            reason: "not valid UTF-8",
+        if buf.len() > max_destination_url_size {
+            return Err(Error::InvalidPacket {
+                name: Self::NAME,
+                field: "destinationUrl",
+                reason: "too long",
+            });
+        }
+
+        let destination_url = DestinationUrl::parse_bytes(&buf).map_err(|e| match e {
+            Error::InvalidDestinationUrl { reason, .. } => Error::InvalidPacket {
+                name: Self::NAME,
+                field: "destinationUrl",
+                reason,
+            },
+            e => e,
         })?;
-        let destination_url = DestinationUrl::parse_str(destination_url)?;
 
         Ok(Self {
             sender_channel_id,
@@ -609,7 +1120,7 @@ impl ChannelOpenFailure {
 
         let recipient_channel_id = buf.get_u32();
         let reason_code = ReasonCode(buf.get_u32());
-        let description = std::str::from_utf8(&buf)
+        let description = core::str::from_utf8(&buf)
             .map_err(|_| Error::InvalidPacket {
                 name: Self::NAME,
                 field: "description",
@@ -659,6 +1170,8 @@ impl ChannelWindowAdjust {
 #[derive(PartialEq, Eq)]
 pub struct ChannelData {
     pub recipient_channel_id: u32,
+    /// Position of this message among the CHANNEL DATA messages sent for the channel (see [`Header::FLAG_SEQUENCED`])
+    pub sequence_number: Option<u32>,
     pub transfer_data: Bytes,
 }
 
@@ -667,6 +1180,7 @@ impl fmt::Debug for ChannelData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ChannelData")
             .field("recipient_channel_id", &self.recipient_channel_id)
+            .field("sequence_number", &self.sequence_number)
             .field("transfer_data.len()", &self.transfer_data.len())
             .finish_non_exhaustive()
     }
@@ -676,19 +1190,58 @@ impl ChannelData {
     pub const NAME: &'static str = "CHANNEL DATA";
     pub const FIXED_PART_SIZE: usize = 4 /*recipientChannelId*/;
 
+    /// Size of the sequence number, when present
+    pub const SEQUENCE_NUMBER_SIZE: usize = 4;
+
     pub fn new(id: DistantChannelId, data: Bytes) -> Self {
         ChannelData {
             recipient_channel_id: u32::from(id),
+            sequence_number: None,
             transfer_data: data,
         }
     }
 
+    #[must_use]
+    pub fn with_sequence_number(mut self, sequence_number: u32) -> Self {
+        self.sequence_number = Some(sequence_number);
+        self
+    }
+
+    /// Splits `data` into CHANNEL DATA messages, each one fitting in a packet of `max_packet_size` bytes
+    ///
+    /// Each message is carrying at least one byte, even when `max_packet_size` is too small.
+    /// No message is produced for empty data.
+    pub fn chunk(id: DistantChannelId, mut data: Bytes, max_packet_size: u16) -> impl Iterator<Item = ChannelData> {
+        let chunk_size = usize::from(max_packet_size)
+            .saturating_sub(Header::SIZE + Self::FIXED_PART_SIZE)
+            .max(1);
+
+        core::iter::from_fn(move || {
+            if data.is_empty() {
+                return None;
+            }
+
+            let split_at = core::cmp::min(chunk_size, data.len());
+
+            Some(ChannelData::new(id, data.split_to(split_at)))
+        })
+    }
+
     pub fn size(&self) -> usize {
-        Self::FIXED_PART_SIZE + self.transfer_data.len()
+        let sequence_number_size = if self.sequence_number.is_some() {
+            Self::SEQUENCE_NUMBER_SIZE
+        } else {
+            0
+        };
+
+        Self::FIXED_PART_SIZE + sequence_number_size + self.transfer_data.len()
     }
 
     pub fn encode(&self, buf: &mut BytesMut) {
         buf.put_u32(self.recipient_channel_id);
+        if let Some(sequence_number) = self.sequence_number {
+            buf.put_u32(sequence_number);
+        }
         buf.put(self.transfer_data.slice(..));
     }
 
@@ -696,6 +1249,17 @@ impl ChannelData {
         ensure_size!(fixed Self in buf);
         Ok(Self {
             recipient_channel_id: buf.get_u32(),
+            sequence_number: None,
+            transfer_data: buf,
+        })
+    }
+
+    /// Decodes a CHANNEL DATA message flagged with [`Header::FLAG_SEQUENCED`]
+    pub fn decode_sequenced(mut buf: Bytes) -> Result<Self, Error> {
+        ensure_size!(buf[Self::FIXED_PART_SIZE + Self::SEQUENCE_NUMBER_SIZE] for Self::NAME);
+        Ok(Self {
+            recipient_channel_id: buf.get_u32(),
+            sequence_number: Some(buf.get_u32()),
             transfer_data: buf,
         })
     }
@@ -754,3 +1318,50 @@ impl ChannelClose {
         })
     }
 }
+
+/// Optional features supported by a peer, advertised once right after the pipe is established
+///
+/// A feature is only used when advertised by both peers. Unknown features must be ignored.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Capabilities {
+    pub features: u32,
+}
+
+impl Capabilities {
+    pub const NAME: &'static str = "CAPABILITIES";
+    pub const SIZE: usize = 4 /*features*/;
+
+    /// Compressed fields can be decoded (see [`Header::FLAG_COMPRESSED`])
+    pub const COMPRESSED_FIELDS: u32 = 0x01;
+
+    /// Sequence numbers in CHANNEL DATA messages can be decoded (see [`Header::FLAG_SEQUENCED`])
+    pub const SEQUENCE_NUMBERS: u32 = 0x02;
+
+    /// The window-based flow control can be skipped for the channels advertising an unlimited window
+    pub const UNLIMITED_WINDOW: u32 = 0x04;
+
+    pub fn new(features: u32) -> Self {
+        Self { features }
+    }
+
+    pub fn supports(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    /// Features supported by both `self` and `other`
+    #[must_use]
+    pub fn intersection(&self, other: &Capabilities) -> Self {
+        Self::new(self.features & other.features)
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.features);
+    }
+
+    pub fn decode(mut buf: Bytes) -> Result<Self, Error> {
+        ensure_size!(plain Self in buf);
+        Ok(Self {
+            features: buf.get_u32(),
+        })
+    }
+}
@@ -1,4 +1,4 @@
-use jmux_generators::destination_url_parts;
+use jmux_generators::{destination_url_parts, lenient_destination_url_parts};
 use jmux_proto::*;
 use proptest::prelude::*;
 
@@ -26,3 +26,129 @@ fn format() {
         prop_assert_eq!(expected, actual);
     })
 }
+
+#[test]
+fn parse_lenient() {
+    proptest!(|(
+        (scheme, host, port) in lenient_destination_url_parts()
+    )| {
+        let s = format!("{scheme}://{host}:{port}");
+        let parsed = DestinationUrl::parse_str_lenient(&s).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        let reparsed = DestinationUrl::parse_str_lenient(parsed.as_str()).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        prop_assert_eq!(parsed, reparsed);
+    })
+}
+
+#[test]
+fn parse_valid_hosts() {
+    for s in [
+        "tcp://devolutions.net:443",
+        "tcp://192.168.1.1:22",
+        "tcp://[::1]:3389",
+        "tcp://[fe80::1ff:fe23:4567:890a]:80",
+        "ws+tls://my_host-01.local:8080",
+        "tcp://*:80",
+        "tcp://caf%C3%A9.example:80",
+    ] {
+        DestinationUrl::parse_str(s).expect(s);
+    }
+}
+
+#[test]
+fn parse_rejects_invalid_characters() {
+    for (s, reason) in [
+        ("tcp://devolutions net:443", "invalid character in host"),
+        ("tcp://devolutions.net\n:443", "invalid character in host"),
+        (
+            "tcp://devolutions.net\r\nINFO forged log:443",
+            "invalid character in host",
+        ),
+        ("tcp://devo\x00lutions.net:443", "invalid character in host"),
+        ("tcp://devo\x1blutions.net:443", "invalid character in host"),
+        ("tcp://dévolutions.net:443", "invalid character in host"),
+        ("tcp://[::1 ]:443", "invalid character in host"),
+        ("t cp://devolutions.net:443", "invalid character in scheme"),
+        ("1tcp://devolutions.net:443", "invalid character in scheme"),
+        ("://devolutions.net:443", "invalid character in scheme"),
+    ] {
+        match DestinationUrl::parse_str(s) {
+            Err(Error::InvalidDestinationUrl { reason: actual, .. }) => assert_eq!(actual, reason, "{s:?}"),
+            other => panic!("unexpected result for {s:?}: {other:?}"),
+        }
+
+        // The lenient parser only checks the structure.
+        DestinationUrl::parse_str_lenient(s).expect(s);
+    }
+}
+
+#[test]
+fn parse_with_default_ports() {
+    let default_ports = DefaultPorts::default();
+
+    for (s, expected) in [
+        ("https://devolutions.net", "https://devolutions.net:443"),
+        ("http://devolutions.net", "http://devolutions.net:80"),
+        ("ssh://192.168.1.1", "ssh://192.168.1.1:22"),
+        ("SSH://192.168.1.1", "SSH://192.168.1.1:22"),
+        ("rdp://[::1]", "rdp://[::1]:3389"),
+        ("ldap://dc.ad.it-help.ninja", "ldap://dc.ad.it-help.ninja:389"),
+        ("ldaps://dc.ad.it-help.ninja", "ldaps://dc.ad.it-help.ninja:636"),
+        (
+            "winrm-https-pwsh://devolutions.net",
+            "winrm-https-pwsh://devolutions.net:5986",
+        ),
+        // An explicit port always wins.
+        ("https://devolutions.net:8443", "https://devolutions.net:8443"),
+        ("rdp://[::1]:3390", "rdp://[::1]:3390"),
+    ] {
+        let url = DestinationUrl::parse_with_defaults(s, &default_ports).expect(s);
+        assert_eq!(url.as_str(), expected);
+    }
+}
+
+#[test]
+fn parse_with_overridden_default_ports() {
+    let default_ports = DefaultPorts::default().with("SSH", 2222).with("tcp", 8080);
+
+    let url = DestinationUrl::parse_with_defaults("ssh://devolutions.net", &default_ports).expect("overridden port");
+    assert_eq!(url.port(), 2222);
+
+    let url = DestinationUrl::parse_with_defaults("tcp://devolutions.net", &default_ports).expect("added port");
+    assert_eq!(url.port(), 8080);
+
+    for s in ["tcp://devolutions.net", "tcp://[::1]"] {
+        match DestinationUrl::parse_with_defaults(s, &DefaultPorts::empty()) {
+            Err(Error::InvalidDestinationUrl { reason, .. }) => {
+                assert_eq!(reason, "port is missing and there is no default port for this scheme")
+            }
+            other => panic!("unexpected result for {s:?}: {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn parse_bytes() {
+    let url = DestinationUrl::parse_bytes(b"tcp://devolutions.net:443").expect("valid URL");
+    assert_eq!(
+        url,
+        DestinationUrl::parse_str("tcp://devolutions.net:443").expect("valid URL")
+    );
+
+    let url = DestinationUrl::try_from(b"tcp://[::1]:3389".as_slice()).expect("valid URL");
+    assert_eq!(url.port(), 3389);
+
+    for (bytes, reason) in [
+        (b"tcp://devolutions\xFF.net:443".as_slice(), "not valid UTF-8"),
+        (b"tcp://\xC3\x28:443".as_slice(), "not valid UTF-8"),
+        (b"tcp//devolutions.net:443".as_slice(), "scheme is missing"),
+        (b"tcp://devolutions.net".as_slice(), "port is missing"),
+        (b"tcp://devolutions.net:https".as_slice(), "bad port"),
+        (b"tcp://devolutions net:443".as_slice(), "invalid character in host"),
+        (b"t cp://devolutions.net:443".as_slice(), "invalid character in scheme"),
+    ] {
+        match DestinationUrl::parse_bytes(bytes) {
+            Err(Error::InvalidDestinationUrl { reason: actual, .. }) => assert_eq!(actual, reason, "{bytes:?}"),
+            other => panic!("unexpected result for {bytes:?}: {other:?}"),
+        }
+    }
+}
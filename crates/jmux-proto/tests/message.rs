@@ -1,4 +1,4 @@
-use bytes::{Bytes, BytesMut};
+use bytes::{BufMut as _, Bytes, BytesMut};
 use jmux_proto::*;
 
 fn check_encode_decode(sample_msg: Message, raw_msg: &[u8]) {
@@ -27,7 +27,7 @@ fn message_type_try_err_on_invalid_bytes() {
     let msg_type_res = MessageType::try_from(99);
     assert!(msg_type_res.is_err());
 
-    let msg_type_res = MessageType::try_from(107);
+    let msg_type_res = MessageType::try_from(108);
     assert!(msg_type_res.is_err());
 }
 
@@ -89,6 +89,64 @@ fn channel_open() {
     check_encode_decode(Message::Open(msg_sample), raw_msg);
 }
 
+#[test]
+fn channel_open_oversized_destination_url() {
+    let host = "a".repeat(ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE);
+    let destination_url = DestinationUrl::parse_str(&format!("tcp://{host}:443")).unwrap();
+
+    let mut raw_body = BytesMut::new();
+    raw_body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 4, 0, 4, 0]);
+    raw_body.extend_from_slice(destination_url.as_bytes());
+    let raw_body = raw_body.freeze();
+
+    let err = ChannelOpen::decode(raw_body.clone()).err().unwrap();
+    assert_eq!("invalid `destinationUrl` in CHANNEL OPEN: too long", err.to_string());
+
+    // The limit is configurable.
+    let decoded = ChannelOpen::decode_with_max_url_size(raw_body, 8 * 1024).unwrap();
+    assert_eq!(decoded.destination_url, destination_url);
+
+    let msg = Message::open(LocalChannelId::from(1), 4096, destination_url);
+    let err = msg.encode(&mut BytesMut::new()).err().unwrap();
+    assert_eq!("invalid `destinationUrl` in CHANNEL OPEN: too long", err.to_string());
+
+    // The same limit is used on both sides.
+    let mut buf = BytesMut::new();
+    msg.encode_with_max_url_size(&mut buf, 8 * 1024).unwrap();
+    let err = Message::decode(buf.clone().freeze()).err().unwrap();
+    assert_eq!("invalid `destinationUrl` in CHANNEL OPEN: too long", err.to_string());
+    let decoded = Message::decode_with_max_url_size(buf.freeze(), 8 * 1024).unwrap();
+    assert_eq!(decoded, msg);
+}
+
+#[test]
+fn channel_open_invalid_destination_url() {
+    for (destination_url, reason) in [
+        (b"tcp://devolutions\xFF.net:443".as_slice(), "not valid UTF-8"),
+        (b"tcp//devolutions.net:443".as_slice(), "scheme is missing"),
+        (b"tcp://devolutions.net".as_slice(), "port is missing"),
+        (b"tcp://devolutions.net:99999".as_slice(), "bad port"),
+        (b"tcp://devolutions.net\r\n:443".as_slice(), "invalid character in host"),
+    ] {
+        let mut raw_body = BytesMut::new();
+        raw_body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 4, 0, 4, 0]);
+        raw_body.extend_from_slice(destination_url);
+
+        match ChannelOpen::decode(raw_body.freeze()) {
+            Err(Error::InvalidPacket {
+                name,
+                field,
+                reason: actual,
+            }) => {
+                assert_eq!(name, "CHANNEL OPEN");
+                assert_eq!(field, "destinationUrl");
+                assert_eq!(actual, reason, "{destination_url:?}");
+            }
+            other => panic!("unexpected result for {destination_url:?}: {other:?}"),
+        }
+    }
+}
+
 #[test]
 pub fn channel_open_success() {
     let raw_msg = &[
@@ -131,6 +189,81 @@ pub fn channel_open_failure() {
     check_encode_decode(Message::OpenFailure(msg_example), raw_msg);
 }
 
+#[test]
+fn reason_code_display() {
+    assert_eq!(
+        ReasonCode::CONNECTION_REFUSED.to_string(),
+        "CONNECTION_REFUSED (0x00000005)"
+    );
+    assert_eq!(
+        ReasonCode::AUTHORIZATION_REVOKED.to_string(),
+        "AUTHORIZATION_REVOKED (0x00000100)"
+    );
+    assert_eq!(ReasonCode::SESSION_EXPIRED.to_string(), "SESSION_EXPIRED (0x00000101)");
+    assert_eq!(ReasonCode(0x07).to_string(), "OTHER (0x00000007)");
+    assert_eq!(ReasonCode(0x102).to_string(), "OTHER (0x00000102)");
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn reason_code_from_error_chain() {
+    use std::{error, fmt, io};
+
+    #[derive(Debug)]
+    struct Layer(Box<dyn error::Error + Send + Sync>);
+
+    impl fmt::Display for Layer {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("layer")
+        }
+    }
+
+    impl error::Error for Layer {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            Some(self.0.as_ref())
+        }
+    }
+
+    // The specific kind is found behind a generic I/O error and a custom error.
+    let error = io::Error::other(Layer(Box::new(io::Error::from(io::ErrorKind::ConnectionRefused))));
+    assert_eq!(ReasonCode::from(error.kind()), ReasonCode::GENERAL_FAILURE);
+    assert_eq!(ReasonCode::from_error_chain(&error), ReasonCode::CONNECTION_REFUSED);
+
+    let error = Layer(Box::new(io::Error::new(
+        io::ErrorKind::TimedOut,
+        io::Error::from(io::ErrorKind::ConnectionRefused),
+    )));
+    assert_eq!(ReasonCode::from_error_chain(&error), ReasonCode::CONNECTION_REFUSED);
+
+    let error = io::Error::new(
+        io::ErrorKind::TimedOut,
+        Layer(Box::new(io::Error::other("dns failure"))),
+    );
+    assert_eq!(ReasonCode::from_error_chain(&error), ReasonCode::TTL_EXPIRED);
+
+    let error = Layer(Box::new(io::Error::other("dns failure")));
+    assert_eq!(ReasonCode::from_error_chain(&error), ReasonCode::GENERAL_FAILURE);
+}
+
+#[test]
+pub fn channel_open_failure_session_expired() {
+    let raw_msg = &[
+        102, // msg type
+        0, 12, // msg size
+        0,  // msg flags
+        0, 0, 0, 1, // recipient channel id
+        0, 0, 1, 1, // reason code
+    ];
+
+    let msg_example = ChannelOpenFailure {
+        recipient_channel_id: 1,
+        reason_code: ReasonCode::SESSION_EXPIRED,
+        description: String::new(),
+    };
+
+    check_encode_decode(Message::OpenFailure(msg_example), raw_msg);
+}
+
 #[test]
 pub fn channel_window_adjust() {
     let raw_msg = &[
@@ -171,12 +304,91 @@ pub fn channel_data() {
 
     let msg_example = ChannelData {
         recipient_channel_id: 1,
+        sequence_number: None,
         transfer_data: vec![11, 12, 13, 14].into(),
     };
 
     check_encode_decode(Message::Data(msg_example), raw_msg);
 }
 
+#[test]
+pub fn sequenced_channel_data() {
+    let raw_msg = &[
+        104, // msg type
+        0, 16, // msg size
+        2,  // msg flags
+        0, 0, 0, 1, // recipient channel id
+        0, 0, 1, 2, // sequence number
+        11, 12, 13, 14, // transfer data
+    ];
+
+    let msg_example = ChannelData {
+        recipient_channel_id: 1,
+        sequence_number: Some(0x0102),
+        transfer_data: vec![11, 12, 13, 14].into(),
+    };
+
+    check_encode_decode(Message::Data(msg_example), raw_msg);
+}
+
+#[test]
+pub fn sequenced_channel_data_without_sequence_number() {
+    let raw_msg = Bytes::from_static(&[
+        104, // msg type
+        0, 10, // msg size
+        2,  // msg flags
+        0, 0, 0, 1, // recipient channel id
+        0, 0, // truncated sequence number
+    ]);
+
+    let err = Message::decode(raw_msg).err().unwrap();
+    assert_eq!(
+        "not enough bytes provided to decode CHANNEL DATA: received 6 bytes, expected 8 bytes",
+        err.to_string()
+    );
+}
+
+#[test]
+fn channel_data_chunk() {
+    const MAX_PACKET_SIZE: u16 = 32;
+    const MAX_DATA_SIZE: usize = MAX_PACKET_SIZE as usize - Header::SIZE - ChannelData::FIXED_PART_SIZE;
+
+    let id = DistantChannelId::from(1);
+    let chunk_sizes = |data: Vec<u8>, max_packet_size: u16| {
+        ChannelData::chunk(id, data.into(), max_packet_size)
+            .map(|chunk| {
+                assert_eq!(chunk.recipient_channel_id, 1);
+                chunk.transfer_data.len()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Exact fit.
+    assert_eq!(chunk_sizes(vec![0; MAX_DATA_SIZE], MAX_PACKET_SIZE), [MAX_DATA_SIZE]);
+
+    // Under fit.
+    assert_eq!(chunk_sizes(vec![0; 3], MAX_PACKET_SIZE), [3]);
+
+    // Multiple chunks.
+    assert_eq!(
+        chunk_sizes(vec![0; MAX_DATA_SIZE * 2 + 1], MAX_PACKET_SIZE),
+        [MAX_DATA_SIZE, MAX_DATA_SIZE, 1]
+    );
+
+    // Nothing to send.
+    assert!(chunk_sizes(Vec::new(), MAX_PACKET_SIZE).is_empty());
+
+    // The packet size is too small to hold any data.
+    assert_eq!(chunk_sizes(vec![0; 2], 4), [1, 1]);
+
+    // The data is not altered.
+    let data: Vec<u8> = (0..=255).collect();
+    let rebuilt = ChannelData::chunk(id, data.clone().into(), MAX_PACKET_SIZE)
+        .flat_map(|chunk| chunk.transfer_data)
+        .collect::<Vec<_>>();
+    assert_eq!(rebuilt, data);
+}
+
 #[test]
 pub fn channel_eof() {
     let raw_msg = &[
@@ -209,6 +421,85 @@ pub fn channel_close() {
     check_encode_decode(Message::Close(msg_example), raw_msg);
 }
 
+#[test]
+pub fn capabilities() {
+    let raw_msg = &[
+        107, // msg type
+        0, 8, // msg size
+        0, // msg flags
+        0, 0, 0, 6, // features
+    ];
+
+    let msg_example = Capabilities {
+        features: Capabilities::SEQUENCE_NUMBERS | Capabilities::UNLIMITED_WINDOW,
+    };
+
+    check_encode_decode(Message::Capabilities(msg_example), raw_msg);
+}
+
+#[test]
+fn capabilities_intersection() {
+    let local = Capabilities::new(Capabilities::COMPRESSED_FIELDS | Capabilities::SEQUENCE_NUMBERS);
+    // Unknown features are advertised by newer peers.
+    let peer = Capabilities::new(Capabilities::SEQUENCE_NUMBERS | Capabilities::UNLIMITED_WINDOW | 0x8000_0000);
+
+    let negotiated = local.intersection(&peer);
+
+    assert_eq!(negotiated, Capabilities::new(Capabilities::SEQUENCE_NUMBERS));
+    assert!(negotiated.supports(Capabilities::SEQUENCE_NUMBERS));
+    assert!(!negotiated.supports(Capabilities::COMPRESSED_FIELDS));
+    assert!(!negotiated.supports(Capabilities::UNLIMITED_WINDOW));
+}
+
+#[test]
+fn decode_all_concatenated_messages() {
+    let messages = [
+        Message::open(
+            LocalChannelId::from(1),
+            4096,
+            DestinationUrl::parse_str("tcp://google.com:443").unwrap(),
+        ),
+        Message::data(DistantChannelId::from(2), Bytes::from_static(b"hello")),
+        Message::close(DistantChannelId::from(3)),
+    ];
+
+    let mut buf = BytesMut::new();
+    for message in &messages {
+        message.encode(&mut buf).unwrap();
+    }
+
+    // Partial frame at the end of the buffer.
+    let partial = Message::eof(DistantChannelId::from(4)).encode_to_vec().unwrap();
+    buf.extend_from_slice(&partial[..5]);
+
+    let mut decoder = Message::decode_all(buf.freeze());
+    let decoded = decoder.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(decoded, messages);
+    assert_eq!(decoder.remaining()[..], partial[..5]);
+}
+
+#[test]
+fn decode_all_continues_after_invalid_frame() {
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(&[
+        99, // invalid msg type
+        0, 8, // msg size
+        0, // msg flags
+        0, 0, 0, 1, // recipient channel id
+    ]);
+    Message::close(DistantChannelId::from(1)).encode(&mut buf).unwrap();
+
+    let mut decoder = Message::decode_all(buf.freeze());
+    assert!(decoder.next().unwrap().is_err());
+    assert_eq!(
+        decoder.next().unwrap().unwrap(),
+        Message::close(DistantChannelId::from(1))
+    );
+    assert!(decoder.next().is_none());
+    assert!(decoder.remaining().is_empty());
+}
+
 /// Check that the original data is equal to the result of the round-trip.
 #[test]
 fn lossless_round_trip() {
@@ -225,3 +516,154 @@ fn lossless_round_trip() {
         prop_assert_eq!(message, decoded);
     })
 }
+
+#[test]
+fn encode_to_vec_matches_encode() {
+    use jmux_generators::*;
+    use proptest::prelude::*;
+
+    proptest!(|(
+        message in any_message(),
+    )| {
+        let mut buf = BytesMut::new();
+        message.encode(&mut buf).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        let encoded = message.encode_to_vec().map_err(|e| TestCaseError::fail(e.to_string()))?;
+        prop_assert_eq!(&buf[..], &encoded[..]);
+        prop_assert_eq!(message.encoded_len(), encoded.len());
+    })
+}
+
+#[test]
+fn compressed_fields_decode_to_the_same_message() {
+    fn destination_url() -> DestinationUrl {
+        DestinationUrl::parse_str(&format!("tcp://{}.example.com:443", "ab".repeat(500))).unwrap()
+    }
+
+    fn open() -> Message {
+        Message::open(LocalChannelId::from(1), 4096, destination_url())
+    }
+
+    fn open_failure() -> Message {
+        let description = "connection refused; ".repeat(100);
+        Message::open_failure(DistantChannelId::from(1), ReasonCode::CONNECTION_REFUSED, description)
+    }
+
+    for message in [open, open_failure] {
+        let mut uncompressed = BytesMut::new();
+        message().encode(&mut uncompressed).unwrap();
+
+        let mut compressed = BytesMut::new();
+        message().encode_compressed(&mut compressed).unwrap();
+
+        assert!(compressed.len() < uncompressed.len() / 4);
+        assert_eq!(compressed[3], Header::FLAG_COMPRESSED);
+
+        assert_eq!(Message::decode(compressed.freeze()).unwrap(), message());
+        assert_eq!(Message::decode(uncompressed.freeze()).unwrap(), message());
+    }
+}
+
+#[test]
+fn short_fields_are_not_compressed() {
+    let message = Message::open(
+        LocalChannelId::from(1),
+        4096,
+        DestinationUrl::parse_str("tcp://google.com:443").unwrap(),
+    );
+
+    let mut compressed = BytesMut::new();
+    message.encode_compressed(&mut compressed).unwrap();
+
+    assert_eq!(compressed, message.encode_to_vec().unwrap());
+}
+
+#[test]
+fn invalid_compressed_field() {
+    let raw_msg = &[
+        100, // msg type
+        0, 18, // msg size
+        1,  // msg flags: compressed
+        0, 0, 0, 1, // sender channel id
+        0, 0, 4, 0, // initial window size
+        4, 0, // maximum packet size
+        1, 2, 3, 4, // garbage instead of the compressed destination url
+    ];
+
+    let err = Message::decode(Bytes::from_static(raw_msg)).err().unwrap();
+    assert_eq!(
+        "invalid `destinationUrl` in CHANNEL OPEN: invalid or too large compressed data",
+        err.to_string()
+    );
+}
+
+#[test]
+fn compressed_round_trip() {
+    use jmux_generators::*;
+    use proptest::prelude::*;
+
+    proptest!(|(
+        message in any_message(),
+    )| {
+        let mut buf = BytesMut::new();
+        message.encode_compressed(&mut buf).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        let decoded = Message::decode(buf.freeze()).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        prop_assert_eq!(message, decoded);
+    })
+}
+
+/// Decoding arbitrary bytes must never panic.
+#[test]
+fn decode_arbitrary_bytes() {
+    use proptest::prelude::*;
+
+    proptest!(|(
+        bytes in proptest::collection::vec(any::<u8>(), 0..512),
+    )| {
+        let _ = Message::decode(Bytes::from(bytes));
+    })
+}
+
+/// Decoding a frame whose header doesn't match its body must never panic.
+#[test]
+fn decode_frame_with_arbitrary_header() {
+    use proptest::prelude::*;
+
+    proptest!(|(
+        msg_type in 100u8..=106,
+        msg_size in any::<u16>(),
+        msg_flags in any::<u8>(),
+        body in proptest::collection::vec(any::<u8>(), 0..512),
+    )| {
+        let mut buf = BytesMut::new();
+        buf.put_u8(msg_type);
+        buf.put_u16(msg_size);
+        buf.put_u8(msg_flags);
+        buf.put_slice(&body);
+        let _ = Message::decode(buf.freeze());
+    })
+}
+
+/// Decoding a valid message altered by a single byte, or truncated, must never panic.
+#[test]
+fn decode_altered_message() {
+    use jmux_generators::*;
+    use proptest::prelude::*;
+
+    proptest!(|(
+        message in any_message(),
+        idx in any::<prop::sample::Index>(),
+        value in any::<u8>(),
+        truncate in any::<bool>(),
+    )| {
+        let mut buf = message.encode_to_vec().map_err(|e| TestCaseError::fail(e.to_string()))?.to_vec();
+        let idx = idx.index(buf.len());
+
+        if truncate {
+            buf.truncate(idx);
+        } else {
+            buf[idx] = value;
+        }
+
+        let _ = Message::decode(Bytes::from(buf));
+    })
+}
@@ -1,10 +1,38 @@
 use std::io;
 
 use bytes::BytesMut;
-use jmux_proto::{Header, Message};
+use jmux_proto::{ChannelOpen, Header, Message};
 use tokio_util::codec::{Decoder, Encoder};
 
-pub(crate) struct JmuxCodec;
+/// Framing of JMUX messages, for use with [`FramedRead`](tokio_util::codec::FramedRead) and
+/// [`FramedWrite`](tokio_util::codec::FramedWrite).
+///
+/// Runtimes other than tokio can use [`encode_message`] and [`decode_message`] directly.
+#[derive(Debug, Clone, Copy)]
+pub struct JmuxCodec {
+    max_destination_url_size: usize,
+}
+
+impl Default for JmuxCodec {
+    fn default() -> Self {
+        Self {
+            max_destination_url_size: ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE,
+        }
+    }
+}
+
+impl JmuxCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum size of the destination URLs, for both the messages encoded and decoded.
+    #[must_use]
+    pub fn with_max_destination_url_size(mut self, max_destination_url_size: usize) -> Self {
+        self.max_destination_url_size = max_destination_url_size;
+        self
+    }
+}
 
 impl Decoder for JmuxCodec {
     type Item = Message;
@@ -12,52 +40,75 @@ impl Decoder for JmuxCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        const MAX_RESERVE_CHUNK_IN_BYTES: usize = 8 * 1024; // 8 kiB
-
-        if src.len() < Header::SIZE {
-            // Not enough data to read length marker.
-            return Ok(None);
-        }
+        decode_message_impl(src, self.max_destination_url_size)
+    }
+}
 
-        // Read length marker
-        let mut length_bytes = [0u8; 2];
-        length_bytes.copy_from_slice(&src[1..3]);
-        let length = u16::from_be_bytes(length_bytes) as usize;
+impl Encoder<Message> for JmuxCodec {
+    type Error = io::Error;
 
-        if src.len() < length {
-            // The full packet has not arrived yet.
-            // Reserve more space in the buffer (good performance-wise).
-            let additional = core::cmp::min(MAX_RESERVE_CHUNK_IN_BYTES, length - src.len());
-            src.reserve(additional);
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.encode_with_max_url_size(dst, self.max_destination_url_size)
+            .map_err(io::Error::other)
+    }
+}
 
-            // Inform the Framed that more bytes are required to form the next frame.
-            return Ok(None);
-        }
+/// Appends the encoded `message` to `dst`.
+///
+/// The default maximum destination URL size is used. Configure a [`JmuxCodec`] to use another one.
+pub fn encode_message(message: &Message, dst: &mut BytesMut) -> io::Result<()> {
+    message.encode(dst).map_err(io::Error::other)
+}
 
-        // `split_to` is modifying src such that it no longer contains this frame (`advance` could have been used as well)
-        let packet_bytes = src.split_to(length).freeze();
+/// Decodes the first message found in `src`, removing its bytes from the buffer.
+///
+/// Returns `None` when `src` does not contain a full message yet; more bytes should be read into it before retrying.
+/// The default maximum destination URL size is used. Configure a [`JmuxCodec`] to use another one.
+pub fn decode_message(src: &mut BytesMut) -> io::Result<Option<Message>> {
+    decode_message_impl(src, ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE)
+}
 
-        // Parse the JMUX packet contained in this frame
-        let packet = Message::decode(packet_bytes).map_err(io::Error::other)?;
+fn decode_message_impl(src: &mut BytesMut, max_destination_url_size: usize) -> io::Result<Option<Message>> {
+    const MAX_RESERVE_CHUNK_IN_BYTES: usize = 8 * 1024; // 8 kiB
 
-        // Hands the frame
-        Ok(Some(packet))
+    if src.len() < Header::SIZE {
+        // Not enough data to read length marker.
+        return Ok(None);
     }
-}
 
-impl Encoder<Message> for JmuxCodec {
-    type Error = io::Error;
+    // Read length marker
+    let mut length_bytes = [0u8; 2];
+    length_bytes.copy_from_slice(&src[1..3]);
+    let length = u16::from_be_bytes(length_bytes) as usize;
 
-    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        item.encode(dst).map_err(io::Error::other)
+    if src.len() < length {
+        // The full packet has not arrived yet.
+        // Reserve more space in the buffer (good performance-wise).
+        let additional = core::cmp::min(MAX_RESERVE_CHUNK_IN_BYTES, length - src.len());
+        src.reserve(additional);
+
+        // Inform the caller that more bytes are required to form the next frame.
+        return Ok(None);
     }
+
+    // `split_to` is modifying src such that it no longer contains this frame (`advance` could have been used as well)
+    let packet_bytes = src.split_to(length).freeze();
+
+    // Parse the JMUX packet contained in this frame
+    let packet = Message::decode_with_max_url_size(packet_bytes, max_destination_url_size).map_err(io::Error::other)?;
+
+    // Hands the frame
+    Ok(Some(packet))
 }
 
 #[cfg(test)]
 mod tests {
+    #![allow(clippy::unwrap_used)]
+
     use super::*;
     use bytes::Bytes;
     use futures_util::StreamExt;
+    use jmux_proto::{DestinationUrl, DistantChannelId, LocalChannelId};
     use std::pin::Pin;
     use std::task::{Context, Poll};
     use tokio::io::{AsyncRead, ReadBuf};
@@ -98,9 +149,69 @@ mod tests {
         let reader = MockAsyncReader {
             raw_msg: raw_msg.to_vec(),
         };
-        let mut framed_reader = FramedRead::new(reader, JmuxCodec);
+        let mut framed_reader = FramedRead::new(reader, JmuxCodec::new());
         let frame = framed_reader.next().await.unwrap().unwrap();
 
         assert_eq!(expected_message, frame);
     }
+
+    #[test]
+    fn messages_are_framed_without_a_runtime() {
+        let open = || {
+            Message::open(
+                LocalChannelId::from(1),
+                1024,
+                DestinationUrl::parse_str("tcp://google.com:443").unwrap(),
+            )
+        };
+        let data = || Message::data(DistantChannelId::from(2), Bytes::from_static(b"hello"));
+
+        let mut buf = BytesMut::new();
+        encode_message(&open(), &mut buf).unwrap();
+        JmuxCodec::new().encode(data(), &mut buf).unwrap();
+        let encoded = buf.freeze();
+
+        // Bytes are fed one at a time, as they would with a non-blocking reader.
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded.iter() {
+            src.extend_from_slice(&[*byte]);
+            while let Some(message) = decode_message(&mut src).unwrap() {
+                decoded.push(message);
+            }
+        }
+
+        assert_eq!(decoded, [open(), data()]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn destination_url_size_limit_is_configurable() {
+        let destination_url = format!(
+            "tcp://{}:443",
+            "a".repeat(ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE)
+        );
+        let open = || {
+            Message::open(
+                LocalChannelId::from(1),
+                1024,
+                DestinationUrl::parse_str(&destination_url).unwrap(),
+            )
+        };
+
+        let mut buf = BytesMut::new();
+        encode_message(&open(), &mut buf).unwrap_err();
+
+        let mut codec = JmuxCodec::new().with_max_destination_url_size(8 * 1024);
+        codec.encode(open(), &mut buf).unwrap();
+        decode_message(&mut buf.clone()).unwrap_err();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(open()));
+    }
+
+    #[test]
+    fn malformed_message_is_an_error() {
+        let mut src = BytesMut::from(&[0xff, 0, 4, 0][..]);
+        let error = JmuxCodec::new().decode(&mut src).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+    }
 }
@@ -1,17 +1,187 @@
+use crate::matcher::CompiledFilteringRule;
 use anyhow::Context;
-use jmux_proto::DestinationUrl;
+use futures_util::future::BoxFuture;
+use jmux_proto::{ChannelOpen, DestinationUrl, ReasonCode};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, io};
+use tokio::net::TcpStream;
 
 /// JMUX proxy configuration struct.
 ///
 /// All parameters are designed to be opt-in rather than opt-out: default values are conservatives
 /// and always safe (whitelist approach).
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone)]
 pub struct JmuxConfig {
     /// Rule to use when filtering requests.
     pub filtering: FilteringRule,
+    /// Number of data payloads buffered for each channel before backpressure is applied.
+    pub channel_data_buffer_size: ChannelDataBufferSize,
+    /// Limit on the connection attempts in flight for the same destination (unlimited when `None`).
+    pub connect_concurrency_limit: Option<ConnectConcurrencyLimit>,
+    /// Limit on the channel openings requested by the peer being resolved at the same time,
+    /// regardless of the destination (unlimited when `None`).
+    pub resolver_concurrency_limit: Option<ResolverConcurrencyLimit>,
+    /// Limit on the channels requested by the peer existing at the same time, including the ones being opened
+    /// (unlimited when `None`).
+    ///
+    /// Protects the targets against connection storms. Channels requested through the API are not accounted.
+    pub max_concurrent_channels: Option<usize>,
+    /// What happens to the channel openings requested by the peer once `max_concurrent_channels` is reached.
+    pub channel_limit_policy: ChannelLimitPolicy,
+    /// Delay before racing the next resolved address when connecting to a target ("Happy Eyeballs").
+    ///
+    /// When zero, all the addresses are attempted immediately in parallel.
+    pub happy_eyeballs_delay: Duration,
+    /// Maximum number of resolved addresses attempted when connecting to a target.
+    ///
+    /// Address families are alternated, so both IPv4 and IPv6 addresses are attempted when available.
+    /// At least one address is always attempted.
+    pub max_addresses_per_resolution: usize,
+    /// Grace period after which an accepted channel is closed if the peer neither sent data nor closed it.
+    ///
+    /// Disabled when `None`.
+    pub accept_idle_timeout: Option<Duration>,
+    /// Duration without any data exchanged on a channel, in either direction, after which it is closed abnormally.
+    ///
+    /// Protects against targets accepting the connection but then never sending nor receiving anything.
+    /// Disabled when `None`.
+    pub idle_timeout: Option<Duration>,
+    /// Maximum time given to the peer to close the channels once a shutdown is requested through the API.
+    ///
+    /// The remaining channels are closed abruptly after this delay.
+    pub shutdown_grace_period: Duration,
+    /// Duration after a channel is closed locally during which data received for it is dropped silently.
+    ///
+    /// The peer may have sent data before learning about the closure, so it is expected to arrive late.
+    /// Past this delay, such data is reported as being sent to an unknown channel.
+    pub late_data_grace_period: Duration,
+    /// Replaces the destination hosts by a stable hash in the logs.
+    ///
+    /// Useful when hosts are considered personally identifiable information.
+    pub redact_destination_in_logs: bool,
+    /// Maximum number of characters written in the logs for a user-controlled value (e.g.: a destination URL).
+    ///
+    /// Longer values are truncated. Control characters are escaped regardless of this setting.
+    pub max_logged_value_len: usize,
+    /// TCP keepalive applied to the proxied streams, so dead connections are eventually detected.
+    ///
+    /// The operating system defaults are used when `None`.
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// Capacity of the channel used by the internal tasks to signal the scheduler.
+    pub internal_channel_size: usize,
+    /// Schemes substituted in the requested destination URLs before connecting (e.g.: `ssh` → `tcp`).
+    ///
+    /// Keys are matched case-insensitively, and the filtering rule is applied on the original URL.
+    pub scheme_aliases: HashMap<String, String>,
+    /// Destinations substituted for the requested ones before connecting (e.g.: for split DNS or renamed services).
+    ///
+    /// The first entry whose pattern matches the requested host wins. The filtering rule and the admission hook
+    /// are applied on the requested URL, and the channel is logged under the requested URL as well.
+    pub destination_rewrites: Vec<(HostPattern, DestinationUrl)>,
+    /// Skips the window-based flow control, relying on the backpressure of the internal queues alone.
+    ///
+    /// Only meant for fast and reliable pipes in controlled environments. Both peers must enable this option along
+    /// with `advertise_capabilities`: the peer opening a channel requests it by advertising an unlimited window,
+    /// and flow control is only skipped for the channels where both the negotiated features and the advertised
+    /// window agree.
+    pub disable_flow_control: bool,
+    /// Numbers the CHANNEL DATA messages sent, so the peer can detect reordered or duplicated messages.
+    ///
+    /// This is a diagnostic feature for suspected corruption (e.g.: a buggy intermediary). It requires
+    /// `advertise_capabilities`, and is only used once the peer advertised its support for it.
+    /// Sequence numbers received are always verified, and a channel is closed on the first gap or duplicate.
+    pub sequence_data: bool,
+    /// Sends a CAPABILITIES message advertising the optional features to the peer when the pipe is established.
+    ///
+    /// Optional features (e.g.: `sequence_data`) are only used once both peers advertised them, so a peer not sending
+    /// this message is limited to the baseline features, and so is the session when this option is disabled.
+    ///
+    /// **This is not compatible with peers predating this message**: they fail to decode it, and end the session.
+    /// Only enable this option when the peer is known to support it.
+    pub advertise_capabilities: bool,
+    /// Ceiling on the initial window size accepted from the peer, in bytes.
+    ///
+    /// Larger windows advertised by the peer are clamped, and only the clamped value is advertised back.
+    pub max_initial_window_size: u32,
+    /// Maximum size of the destination URLs, in bytes.
+    ///
+    /// CHANNEL OPEN messages received with a longer destination are rejected as malformed, and channels requested
+    /// through the API with a longer destination fail without being sent. The peer should use the same limit.
+    pub max_destination_url_size: usize,
+    /// Soft cap on the channel data held in memory by the proxy, in bytes (unlimited when `None`).
+    ///
+    /// Above this value, the proxy stops reading from the targets until enough data is written out. It also stops
+    /// reading from the JMUX pipe (so new data and new channel openings are paused) while the data received from the
+    /// peer alone is above this value; the data waiting to be sent to the peer doesn't pause it, as the messages
+    /// letting it through are received from the pipe. Data already read is still queued, hence a soft cap.
+    pub max_outstanding_bytes: Option<u64>,
+    /// Bandwidth allowed to each channel for the data read from its target and sent to the peer, in bytes per second
+    /// (unlimited when `None`).
+    ///
+    /// Bursts of up to a tenth of a second of traffic are allowed.
+    pub max_channel_tx_rate: Option<u64>,
+    /// Bandwidth allowed to each channel for the data received from the peer and written to its target, in bytes per
+    /// second (unlimited when `None`).
+    ///
+    /// The peer is slowed down as well, as the window is only adjusted once the data is written.
+    pub max_channel_rx_rate: Option<u64>,
+    /// Policy applied when a channel requested through the API fails to open (never retried when `None`).
+    pub open_retry_policy: Option<OpenRetryPolicy>,
+    /// Hook consulted for each channel opening requested by the peer and allowed by the filtering rule.
+    pub open_admission: Option<OpenAdmission>,
+}
+
+impl Default for JmuxConfig {
+    fn default() -> Self {
+        Self {
+            filtering: FilteringRule::default(),
+            channel_data_buffer_size: ChannelDataBufferSize::default(),
+            connect_concurrency_limit: None,
+            resolver_concurrency_limit: None,
+            max_concurrent_channels: None,
+            channel_limit_policy: ChannelLimitPolicy::default(),
+            happy_eyeballs_delay: Self::DEFAULT_HAPPY_EYEBALLS_DELAY,
+            max_addresses_per_resolution: Self::DEFAULT_MAX_ADDRESSES_PER_RESOLUTION,
+            accept_idle_timeout: None,
+            idle_timeout: None,
+            shutdown_grace_period: Self::DEFAULT_SHUTDOWN_GRACE_PERIOD,
+            late_data_grace_period: Self::DEFAULT_LATE_DATA_GRACE_PERIOD,
+            redact_destination_in_logs: false,
+            max_logged_value_len: Self::DEFAULT_MAX_LOGGED_VALUE_LEN,
+            tcp_keepalive: None,
+            internal_channel_size: Self::DEFAULT_INTERNAL_CHANNEL_SIZE,
+            scheme_aliases: HashMap::new(),
+            destination_rewrites: Vec::new(),
+            disable_flow_control: false,
+            sequence_data: false,
+            advertise_capabilities: false,
+            max_initial_window_size: ChannelOpen::DEFAULT_INITIAL_WINDOW_SIZE,
+            max_destination_url_size: ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE,
+            max_outstanding_bytes: None,
+            max_channel_tx_rate: None,
+            max_channel_rx_rate: None,
+            open_retry_policy: None,
+            open_admission: None,
+        }
+    }
 }
 
 impl JmuxConfig {
+    /// Recommended value for the "Connection Attempt Delay" by RFC 8305.
+    pub const DEFAULT_HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+    pub const DEFAULT_INTERNAL_CHANNEL_SIZE: usize = 32;
+
+    pub const DEFAULT_MAX_ADDRESSES_PER_RESOLUTION: usize = 4;
+
+    pub const DEFAULT_MAX_LOGGED_VALUE_LEN: usize = 256;
+
+    pub const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+    pub const DEFAULT_LATE_DATA_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
     /// A safe default JMUX configuration.
     pub fn new() -> Self {
         Self::default()
@@ -21,6 +191,83 @@ impl JmuxConfig {
     pub fn permissive() -> Self {
         Self {
             filtering: FilteringRule::Allow,
+            ..Self::default()
+        }
+    }
+
+    /// Checks the consistency of this configuration.
+    ///
+    /// Inconsistencies would otherwise only show up at runtime, as stalled or rejected channels.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let ChannelDataBufferSize::Adaptive { min, max } = self.channel_data_buffer_size {
+            if min > max {
+                return Err(ConfigError::InvalidAdaptiveBufferSize { min, max });
+            }
+        }
+
+        if self
+            .connect_concurrency_limit
+            .is_some_and(|limit| limit.per_destination == 0)
+        {
+            return Err(ConfigError::NoConnectSlot);
+        }
+
+        if self
+            .resolver_concurrency_limit
+            .is_some_and(|limit| limit.max_in_flight == 0)
+        {
+            return Err(ConfigError::NoResolverSlot);
+        }
+
+        if self.max_concurrent_channels == Some(0) {
+            return Err(ConfigError::NoChannelSlot);
+        }
+
+        if self.max_initial_window_size == 0 {
+            return Err(ConfigError::NoInitialWindow);
+        }
+
+        if self.max_channel_tx_rate == Some(0) || self.max_channel_rx_rate == Some(0) {
+            return Err(ConfigError::NoChannelRate);
+        }
+
+        for (alias, target) in &self.scheme_aliases {
+            if !SUPPORTED_SCHEMES.contains(&target.as_str()) {
+                return Err(ConfigError::UnsupportedSchemeAlias {
+                    alias: alias.clone(),
+                    target: target.clone(),
+                });
+            }
+        }
+
+        check_satisfiable(&self.filtering)?;
+
+        Ok(())
+    }
+
+    /// Returns the destination URL with its scheme substituted according to the alias table.
+    pub(crate) fn normalize_scheme(&self, url: DestinationUrl) -> DestinationUrl {
+        let alias = self
+            .scheme_aliases
+            .iter()
+            .find(|(scheme, _)| scheme.eq_ignore_ascii_case(url.scheme()));
+
+        match alias {
+            Some((_, target)) => DestinationUrl::new(target, url.host(), url.port()),
+            None => url,
+        }
+    }
+
+    /// Returns the destination to connect to, according to the rewrite table.
+    pub(crate) fn rewrite_destination(&self, url: DestinationUrl) -> DestinationUrl {
+        let rewrite = self
+            .destination_rewrites
+            .iter()
+            .find(|(pattern, _)| pattern.matches(url.host()));
+
+        match rewrite {
+            Some((_, target)) => target.clone(),
+            None => url,
         }
     }
 
@@ -31,10 +278,349 @@ impl JmuxConfig {
     pub fn client() -> Self {
         Self {
             filtering: FilteringRule::Deny,
+            ..Self::default()
+        }
+    }
+
+    /// A safe default configuration, only allowing the given hosts (e.g.: the ones authorized by a JMUX token).
+    ///
+    /// Each host is specified either as `host` (any port) or as `host:port`, and IPv6 addresses must be enclosed
+    /// in brackets to specify a port. The host may be `*` to allow any host, or contain wildcard labels such as
+    /// `*.example.com`. Everything else is denied.
+    pub fn from_allowed_hosts(primary: &str, additional: &[&str]) -> Self {
+        let rules = core::iter::once(primary)
+            .chain(additional.iter().copied())
+            .map(allowed_host_rule)
+            .collect();
+
+        Self {
+            filtering: FilteringRule::Any(rules),
+            ..Self::default()
+        }
+    }
+
+    /// Sets the hook deciding dynamically whether a channel opening requested by the peer is admitted.
+    #[must_use]
+    pub fn with_open_admission(mut self, hook: Arc<OpenAdmissionFn>) -> Self {
+        self.open_admission = Some(OpenAdmission(hook));
+        self
+    }
+}
+
+fn allowed_host_rule(spec: &str) -> FilteringRule {
+    // Unbracketed IPv6 addresses can't have a port.
+    let (host, port) = match spec.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => match port.parse::<u16>() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => (spec, None),
+        },
+        _ => (spec, None),
+    };
+
+    match (host, port) {
+        ("*", Some(port)) => FilteringRule::port(port),
+        ("*", None) => FilteringRule::Allow,
+        (host, Some(port)) if host.contains('*') => FilteringRule::wildcard_host(host).and(FilteringRule::port(port)),
+        (host, None) if host.contains('*') => FilteringRule::wildcard_host(host),
+        (host, Some(port)) => FilteringRule::host_and_port(host, port),
+        (host, None) => FilteringRule::host(host),
+    }
+}
+
+/// Schemes handled when connecting to a target.
+pub(crate) const SUPPORTED_SCHEMES: &[&str] = &["tcp", "udp"];
+
+/// Inconsistency found by [`JmuxConfig::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The minimum size of an adaptive channel data buffer is greater than its maximum size.
+    InvalidAdaptiveBufferSize { min: usize, max: usize },
+    /// The per-destination connect concurrency limit is zero: no connection is ever attempted.
+    NoConnectSlot,
+    /// The resolver concurrency limit is zero: no channel opening requested by the peer is ever processed.
+    NoResolverSlot,
+    /// The maximum number of concurrent channels is zero: no channel opening requested by the peer is ever accepted.
+    NoChannelSlot,
+    /// The maximum initial window size is zero: no data is ever sent through the channels.
+    NoInitialWindow,
+    /// A channel rate limit is zero: no data is ever forwarded through the channels in this direction.
+    NoChannelRate,
+    /// A scheme alias is targeting a scheme which is not supported.
+    UnsupportedSchemeAlias { alias: String, target: String },
+    /// A sub-rule of the filtering rule requires different values for the same property at once.
+    UnsatisfiableFilteringRule { rule: String },
+}
+
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidAdaptiveBufferSize { min, max } => {
+                write!(
+                    f,
+                    "adaptive channel data buffer size: min ({min}) is greater than max ({max})"
+                )
+            }
+            ConfigError::NoConnectSlot => write!(f, "connect concurrency limit: per destination limit is zero"),
+            ConfigError::NoResolverSlot => write!(f, "resolver concurrency limit: max in flight is zero"),
+            ConfigError::NoChannelSlot => write!(f, "maximum concurrent channels is zero"),
+            ConfigError::NoInitialWindow => write!(f, "maximum initial window size is zero"),
+            ConfigError::NoChannelRate => write!(f, "maximum channel rate is zero"),
+            ConfigError::UnsupportedSchemeAlias { alias, target } => {
+                write!(
+                    f,
+                    "scheme alias `{alias}` is targeting an unsupported scheme: `{target}`"
+                )
+            }
+            ConfigError::UnsatisfiableFilteringRule { rule } => {
+                write!(f, "filtering rule can never be fulfilled: {rule}")
+            }
         }
     }
 }
 
+/// Finds the `All` rules requiring two different hosts, ports or schemes at the same time.
+fn check_satisfiable(rule: &FilteringRule) -> Result<(), ConfigError> {
+    match rule {
+        FilteringRule::Not(rule) => check_satisfiable(rule),
+        FilteringRule::Any(rules) => rules.iter().try_for_each(check_satisfiable),
+        FilteringRule::All(rules) => {
+            let mut host: Option<&str> = None;
+            let mut port: Option<u16> = None;
+            let mut scheme: Option<&str> = None;
+
+            let mut conflict = false;
+
+            for sub_rule in rules {
+                check_satisfiable(sub_rule)?;
+
+                let (sub_rule_host, sub_rule_port, sub_rule_scheme) = match sub_rule {
+                    FilteringRule::Host(host) => (Some(host.as_str()), None, None),
+                    FilteringRule::Port(port) => (None, Some(*port), None),
+                    FilteringRule::Scheme(scheme) => (None, None, Some(scheme.as_str())),
+                    FilteringRule::HostAndPort { host, port } => (Some(host.as_str()), Some(*port), None),
+                    _ => (None, None, None),
+                };
+
+                if let Some(sub_rule_host) = sub_rule_host {
+                    conflict |= host.is_some_and(|host| !host.eq_ignore_ascii_case(sub_rule_host));
+                    host = Some(sub_rule_host);
+                }
+
+                if let Some(sub_rule_port) = sub_rule_port {
+                    conflict |= port.is_some_and(|port| port != sub_rule_port);
+                    port = Some(sub_rule_port);
+                }
+
+                if let Some(sub_rule_scheme) = sub_rule_scheme {
+                    conflict |= scheme.is_some_and(|scheme| !scheme.eq_ignore_ascii_case(sub_rule_scheme));
+                    scheme = Some(sub_rule_scheme);
+                }
+            }
+
+            if conflict {
+                Err(ConfigError::UnsatisfiableFilteringRule {
+                    rule: format!("{rule:?}"),
+                })
+            } else {
+                Ok(())
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sizing strategy for the per-channel data buffer.
+///
+/// Each payload is at most one JMUX packet, so the memory kept alive by a single channel is
+/// bounded by `size × maximum packet size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelDataBufferSize {
+    /// Every channel gets the same buffer size, regardless of how many channels are open.
+    Fixed(usize),
+    /// Channels opened while many others are already open get a smaller buffer.
+    ///
+    /// Up to [`ChannelDataBufferSize::ADAPTIVE_THRESHOLD`] open channels, the `max` size is used.
+    /// Past this point, the size is scaled down so the total number of buffered payloads stays roughly
+    /// constant, but never goes below `min`.
+    Adaptive { min: usize, max: usize },
+}
+
+impl Default for ChannelDataBufferSize {
+    fn default() -> Self {
+        Self::Fixed(256)
+    }
+}
+
+impl ChannelDataBufferSize {
+    pub const ADAPTIVE_THRESHOLD: usize = 16;
+
+    /// Returns the buffer size to use for a new channel, given the number of channels already open.
+    pub fn for_open_channels(self, open_channels: usize) -> usize {
+        let size = match self {
+            Self::Fixed(size) => size,
+            Self::Adaptive { min, max } => {
+                if open_channels <= Self::ADAPTIVE_THRESHOLD {
+                    max
+                } else {
+                    (max.saturating_mul(Self::ADAPTIVE_THRESHOLD) / open_channels).clamp(min, max)
+                }
+            }
+        };
+
+        // A zero-sized mpsc channel is not allowed.
+        core::cmp::max(size, 1)
+    }
+}
+
+/// Limit on the connection attempts to the same `host:port` performed at the same time.
+///
+/// Extra attempts are queued until a slot is available. When the queue timeout is elapsed,
+/// the channel opening is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectConcurrencyLimit {
+    /// Maximum number of connection attempts in flight for the same destination.
+    pub per_destination: usize,
+    /// Maximum duration a connection attempt waits in the queue.
+    pub queue_timeout: Duration,
+}
+
+/// Limit on the resolver tasks (name resolution and connection) running at the same time for a session.
+///
+/// Extra channel openings are queued until a slot is available. When the queue is full,
+/// the channel opening is rejected right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolverConcurrencyLimit {
+    /// Maximum number of resolver tasks in flight.
+    pub max_in_flight: usize,
+    /// Maximum number of channel openings waiting for a resolver slot.
+    pub max_queued: usize,
+}
+
+/// Handling of the channel openings requested by the peer above [`JmuxConfig::max_concurrent_channels`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelLimitPolicy {
+    /// The channel opening is rejected right away.
+    #[default]
+    Reject,
+    /// The channel opening waits until another channel is closed.
+    ///
+    /// When the timeout is elapsed, or when the queue is full, the channel opening is rejected.
+    Queue {
+        /// Maximum duration a channel opening waits in the queue.
+        timeout: Duration,
+        /// Maximum number of channel openings waiting in the queue.
+        max_queued: usize,
+    },
+}
+
+/// Host pattern of a destination rewrite (see [`JmuxConfig::destination_rewrites`]).
+///
+/// Matching is case-insensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    /// Matches this host only.
+    Exact(String),
+    /// Matches this domain and all its subdomains (e.g.: `example.com` matches `public.example.com`).
+    Suffix(String),
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::Exact(pattern) => host.eq_ignore_ascii_case(pattern),
+            Self::Suffix(domain) => {
+                let Some(prefix_len) = host.len().checked_sub(domain.len()) else {
+                    return false;
+                };
+
+                let Some((prefix, suffix)) = host.split_at_checked(prefix_len) else {
+                    return false;
+                };
+
+                suffix.eq_ignore_ascii_case(domain) && (prefix.is_empty() || prefix.ends_with('.'))
+            }
+        }
+    }
+}
+
+/// Retry policy for the channels requested through the API.
+///
+/// Only the failures with a retryable reason code are retried (see `ReasonCode::is_retryable`).
+/// Other failures, such as a denial by the rule set, are reported immediately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenRetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after each subsequent failure.
+    pub backoff: Duration,
+}
+
+impl OpenRetryPolicy {
+    /// Returns the delay to wait after the given number of failed attempts.
+    pub(crate) fn delay(&self, failed_attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(failed_attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.backoff.saturating_mul(factor)
+    }
+}
+
+/// Signature of the admission hooks (see [`OpenAdmission`]).
+pub type OpenAdmissionFn = dyn Fn(&DestinationUrl) -> BoxFuture<'_, Result<(), ReasonCode>> + Send + Sync;
+
+/// Asynchronous admission hook for the channel openings requested by the peer.
+///
+/// Useful for dynamic decisions such as checking a quota service or a revocation list.
+/// The opening is rejected with the returned reason code when the hook denies it.
+/// The hook is awaited outside of the scheduler, so a slow hook only delays the channel being opened.
+#[derive(Clone)]
+pub struct OpenAdmission(pub Arc<OpenAdmissionFn>);
+
+impl OpenAdmission {
+    pub(crate) async fn check(&self, destination_url: &DestinationUrl) -> Result<(), ReasonCode> {
+        (self.0)(destination_url).await
+    }
+}
+
+impl fmt::Debug for OpenAdmission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OpenAdmission(..)")
+    }
+}
+
+/// TCP keepalive parameters (`SO_KEEPALIVE`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// Duration the connection stays idle before the first keepalive probe is sent.
+    pub idle: Duration,
+    /// Interval between two keepalive probes.
+    ///
+    /// Only supported on Windows, Linux and macOS, ignored elsewhere.
+    pub interval: Option<Duration>,
+    /// Number of unanswered probes before the connection is considered dead.
+    ///
+    /// Only supported on Linux and macOS, ignored elsewhere.
+    pub retries: Option<u32>,
+}
+
+impl TcpKeepalive {
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let mut keepalive = socket2::TcpKeepalive::new().with_time(self.idle);
+
+        #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+        if let Some(interval) = self.interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if let Some(retries) = self.retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+
+        socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
+}
+
 /// Filtering rule for JMUX requests.
 ///
 /// ```
@@ -203,7 +789,16 @@ impl FilteringRule {
         }
     }
 
+    /// Builds a [`CompiledFilteringRule`] taking the same decisions as this rule, but faster to evaluate.
+    ///
+    /// Prefer this over calling [`FilteringRule::validate_destination`] repeatedly with a large rule set.
+    pub fn compile(&self) -> CompiledFilteringRule {
+        CompiledFilteringRule::new(self)
+    }
+
     pub fn validate_destination(&self, destination_url: &DestinationUrl) -> anyhow::Result<()> {
+        ensure_connectable_port(destination_url.port())?;
+
         if is_valid(
             self,
             destination_url.scheme(),
@@ -227,6 +822,7 @@ fn validate_destination_str_impl(rule: &FilteringRule, destination_url: &str) ->
         .context("invalid destination URL format")?;
     let (host, port) = target.rsplit_once(':').context("invalid target format")?;
     let port = port.parse().context("invalid port value")?;
+    ensure_connectable_port(port)?;
 
     if is_valid(rule, scheme, host, port) {
         Ok(())
@@ -235,6 +831,12 @@ fn validate_destination_str_impl(rule: &FilteringRule, destination_url: &str) ->
     }
 }
 
+/// Port 0 is never a valid connection target, regardless of the filtering rule.
+pub(crate) fn ensure_connectable_port(port: u16) -> anyhow::Result<()> {
+    anyhow::ensure!(port != 0, "port 0 is not a valid target");
+    Ok(())
+}
+
 fn is_valid(rule: &FilteringRule, target_scheme: &str, target_host: &str, target_port: u16) -> bool {
     match rule {
         FilteringRule::Deny => false,
@@ -264,3 +866,226 @@ fn is_valid(rule: &FilteringRule, target_scheme: &str, target_host: &str, target
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    #[test]
+    fn fixed_channel_data_buffer_size() {
+        let size = ChannelDataBufferSize::Fixed(32);
+        assert_eq!(size.for_open_channels(0), 32);
+        assert_eq!(size.for_open_channels(10_000), 32);
+        assert_eq!(ChannelDataBufferSize::Fixed(0).for_open_channels(0), 1);
+    }
+
+    #[test]
+    fn adaptive_channel_data_buffer_size() {
+        let size = ChannelDataBufferSize::Adaptive { min: 8, max: 256 };
+        assert_eq!(size.for_open_channels(0), 256);
+        assert_eq!(size.for_open_channels(ChannelDataBufferSize::ADAPTIVE_THRESHOLD), 256);
+        assert_eq!(
+            size.for_open_channels(ChannelDataBufferSize::ADAPTIVE_THRESHOLD * 2),
+            128
+        );
+        assert_eq!(
+            size.for_open_channels(ChannelDataBufferSize::ADAPTIVE_THRESHOLD * 4),
+            64
+        );
+        assert_eq!(size.for_open_channels(100_000), 8);
+    }
+
+    #[test]
+    fn open_retry_delay_is_doubled() {
+        let policy = OpenRetryPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_millis(100),
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(64), Duration::from_millis(100) * u32::MAX);
+    }
+
+    #[test]
+    fn only_allowed_hosts_are_valid_destinations() {
+        let config = JmuxConfig::from_allowed_hosts(
+            "devolutions.net:443",
+            &["*.ad.it-help.ninja:3389", "192.168.1.10", "[::1]:22", "*:8080"],
+        );
+        config.validate().unwrap();
+
+        let filtering = config.filtering.compile();
+
+        for allowed in [
+            "tcp://devolutions.net:443",
+            "tcp://DEVOLUTIONS.NET:443",
+            "tcp://dc.ad.it-help.ninja:3389",
+            "tcp://192.168.1.10:22",
+            "tcp://192.168.1.10:5985",
+            "tcp://[::1]:22",
+            "tcp://anything:8080",
+        ] {
+            config.filtering.validate_destination_str(allowed).expect(allowed);
+            filtering.validate_destination_str(allowed).expect(allowed);
+        }
+
+        for denied in [
+            "tcp://devolutions.net:80",
+            "tcp://www.devolutions.net:443",
+            "tcp://ad.it-help.ninja:3389",
+            "tcp://dc.ad.it-help.ninja:22",
+            "tcp://192.168.1.11:22",
+            "tcp://[::1]:23",
+            "tcp://anything:8081",
+        ] {
+            assert!(config.filtering.validate_destination_str(denied).is_err(), "{denied}");
+            assert!(filtering.validate_destination_str(denied).is_err(), "{denied}");
+        }
+    }
+
+    #[test]
+    fn default_configs_are_valid() {
+        JmuxConfig::default().validate().unwrap();
+        JmuxConfig::permissive().validate().unwrap();
+        JmuxConfig::client().validate().unwrap();
+    }
+
+    #[test]
+    fn invalid_adaptive_buffer_size() {
+        let config = JmuxConfig {
+            channel_data_buffer_size: ChannelDataBufferSize::Adaptive { min: 64, max: 8 },
+            ..JmuxConfig::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidAdaptiveBufferSize { min: 64, max: 8 })
+        );
+    }
+
+    #[test]
+    fn no_connect_slot() {
+        let config = JmuxConfig {
+            connect_concurrency_limit: Some(ConnectConcurrencyLimit {
+                per_destination: 0,
+                queue_timeout: Duration::from_secs(1),
+            }),
+            ..JmuxConfig::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::NoConnectSlot));
+    }
+
+    #[test]
+    fn no_resolver_slot() {
+        let config = JmuxConfig {
+            resolver_concurrency_limit: Some(ResolverConcurrencyLimit {
+                max_in_flight: 0,
+                max_queued: 16,
+            }),
+            ..JmuxConfig::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::NoResolverSlot));
+    }
+
+    #[test]
+    fn no_channel_slot() {
+        let config = JmuxConfig {
+            max_concurrent_channels: Some(0),
+            channel_limit_policy: ChannelLimitPolicy::Queue {
+                timeout: Duration::from_secs(1),
+                max_queued: 16,
+            },
+            ..JmuxConfig::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::NoChannelSlot));
+    }
+
+    #[test]
+    fn no_channel_rate() {
+        let config = JmuxConfig {
+            max_channel_rx_rate: Some(0),
+            ..JmuxConfig::default()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::NoChannelRate));
+    }
+
+    #[test]
+    fn unsupported_scheme_alias() {
+        let config = JmuxConfig {
+            scheme_aliases: HashMap::from([("ssh".to_owned(), "tcp".to_owned())]),
+            ..JmuxConfig::default()
+        };
+        config.validate().unwrap();
+
+        let config = JmuxConfig {
+            scheme_aliases: HashMap::from([("https".to_owned(), "quic".to_owned())]),
+            ..JmuxConfig::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::UnsupportedSchemeAlias {
+                alias: "https".to_owned(),
+                target: "quic".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_filtering_rule() {
+        let valid_rules = [
+            FilteringRule::port(80).and(FilteringRule::host("devolutions.net")),
+            FilteringRule::host_and_port("devolutions.net", 80).and(FilteringRule::host("DEVOLUTIONS.NET")),
+            FilteringRule::port(80).or(FilteringRule::port(443)),
+        ];
+
+        for rule in valid_rules {
+            let config = JmuxConfig {
+                filtering: rule,
+                ..JmuxConfig::default()
+            };
+            config.validate().unwrap();
+        }
+
+        let invalid_rules = [
+            FilteringRule::port(80).and(FilteringRule::port(443)),
+            FilteringRule::host_and_port("devolutions.net", 80).and(FilteringRule::host("sekai.net")),
+            FilteringRule::Allow.or(FilteringRule::scheme("tcp").and(FilteringRule::scheme("udp")).invert()),
+        ];
+
+        for rule in invalid_rules {
+            let config = JmuxConfig {
+                filtering: rule,
+                ..JmuxConfig::default()
+            };
+            assert!(matches!(
+                config.validate(),
+                Err(ConfigError::UnsatisfiableFilteringRule { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_keepalive_is_applied() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+        let keepalive = TcpKeepalive {
+            idle: Duration::from_secs(30),
+            interval: Some(Duration::from_secs(5)),
+            retries: Some(3),
+        };
+        keepalive.apply(&stream).unwrap();
+
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), keepalive.idle);
+            assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+            assert_eq!(socket.keepalive_retries().unwrap(), 3);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use crate::config::{ChannelLimitPolicy, ConnectConcurrencyLimit, ResolverConcurrencyLimit};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+type Destination = (String, u16);
+
+/// Bounds the number of connection attempts in flight for each destination.
+#[derive(Clone)]
+pub(crate) struct ConnectLimiter {
+    limit: Option<ConnectConcurrencyLimit>,
+    semaphores: Arc<Mutex<HashMap<Destination, Arc<Semaphore>>>>,
+}
+
+impl ConnectLimiter {
+    pub(crate) fn new(limit: Option<ConnectConcurrencyLimit>) -> Self {
+        Self {
+            limit,
+            semaphores: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Waits for the permission to connect to `host:port`.
+    ///
+    /// Returns `None` when no permit could be acquired before the queue timeout.
+    pub(crate) async fn acquire(&self, host: &str, port: u16) -> Option<ConnectPermit> {
+        let Some(limit) = self.limit else {
+            return Some(ConnectPermit { _inner: None });
+        };
+
+        let destination = (host.to_ascii_lowercase(), port);
+
+        let semaphore = Arc::clone(
+            self.semaphores
+                .lock()
+                .entry(destination.clone())
+                .or_insert_with(|| Arc::new(Semaphore::new(limit.per_destination))),
+        );
+
+        let permit = tokio::time::timeout(limit.queue_timeout, Arc::clone(&semaphore).acquire_owned()).await;
+
+        let inner = match permit {
+            Ok(Ok(permit)) => Some(PermitInner {
+                permit: Some(permit),
+                semaphore,
+                destination,
+                semaphores: Arc::clone(&self.semaphores),
+            }),
+            // The semaphore is never closed.
+            Ok(Err(_)) | Err(_) => {
+                release(&self.semaphores, &destination, &semaphore);
+                return None;
+            }
+        };
+
+        Some(ConnectPermit { _inner: inner })
+    }
+}
+
+/// Permission to attempt a connection, released when dropped.
+pub(crate) struct ConnectPermit {
+    _inner: Option<PermitInner>,
+}
+
+struct PermitInner {
+    permit: Option<OwnedSemaphorePermit>,
+    semaphore: Arc<Semaphore>,
+    destination: Destination,
+    semaphores: Arc<Mutex<HashMap<Destination, Arc<Semaphore>>>>,
+}
+
+impl Drop for PermitInner {
+    fn drop(&mut self) {
+        // The permit is also holding a reference on the semaphore.
+        drop(self.permit.take());
+        release(&self.semaphores, &self.destination, &self.semaphore);
+    }
+}
+
+/// Removes the semaphore for this destination once nobody is using or waiting for it anymore.
+fn release(
+    semaphores: &Mutex<HashMap<Destination, Arc<Semaphore>>>,
+    destination: &Destination,
+    semaphore: &Arc<Semaphore>,
+) {
+    let mut semaphores = semaphores.lock();
+
+    // One reference is held by the map, and another one by the caller.
+    // Extra references are held by other permits or waiters: they'll take care of the cleanup later.
+    if Arc::strong_count(semaphore) <= 2 {
+        if let Some(current) = semaphores.get(destination) {
+            if Arc::ptr_eq(current, semaphore) {
+                semaphores.remove(destination);
+            }
+        }
+    }
+}
+
+/// Bounds the number of resolver tasks in flight for the whole session, regardless of the destination.
+pub(crate) struct ResolverLimiter {
+    inner: Option<ResolverLimiterInner>,
+}
+
+struct ResolverLimiterInner {
+    in_flight: Arc<Semaphore>,
+    /// Permits for the tasks in flight and the ones waiting in the queue
+    admitted: Arc<Semaphore>,
+}
+
+impl ResolverLimiter {
+    pub(crate) fn new(limit: Option<ResolverConcurrencyLimit>) -> Self {
+        let inner = limit.map(|limit| ResolverLimiterInner {
+            in_flight: Arc::new(Semaphore::new(limit.max_in_flight)),
+            admitted: Arc::new(Semaphore::new(limit.max_in_flight.saturating_add(limit.max_queued))),
+        });
+
+        Self { inner }
+    }
+
+    /// Admits a new resolver task, possibly queued.
+    ///
+    /// Returns `None` when the queue is full.
+    pub(crate) fn try_admit(&self) -> Option<ResolverAdmission> {
+        let Some(inner) = &self.inner else {
+            return Some(ResolverAdmission { inner: None });
+        };
+
+        let admitted_permit = Arc::clone(&inner.admitted).try_acquire_owned().ok()?;
+
+        Some(ResolverAdmission {
+            inner: Some((admitted_permit, Arc::clone(&inner.in_flight))),
+        })
+    }
+}
+
+/// Place in the resolver queue, released when dropped.
+pub(crate) struct ResolverAdmission {
+    inner: Option<(OwnedSemaphorePermit, Arc<Semaphore>)>,
+}
+
+impl ResolverAdmission {
+    /// Waits until the resolver task is allowed to run.
+    pub(crate) async fn ready(self) -> ResolverPermit {
+        let Some((admitted_permit, in_flight)) = self.inner else {
+            return ResolverPermit { _permits: None };
+        };
+
+        // The semaphore is never closed.
+        let in_flight_permit = in_flight.acquire_owned().await.ok();
+
+        ResolverPermit {
+            _permits: Some((admitted_permit, in_flight_permit)),
+        }
+    }
+}
+
+/// Permission to run a resolver task, released when dropped.
+pub(crate) struct ResolverPermit {
+    _permits: Option<(OwnedSemaphorePermit, Option<OwnedSemaphorePermit>)>,
+}
+
+/// Bounds the number of channels requested by the peer existing at the same time, including the ones being opened.
+pub(crate) struct ChannelLimiter {
+    inner: Option<ChannelLimiterInner>,
+}
+
+struct ChannelLimiterInner {
+    open: Arc<Semaphore>,
+    /// Permits for the open channels and the openings waiting in the queue
+    admitted: Arc<Semaphore>,
+    queue_timeout: Duration,
+}
+
+impl ChannelLimiter {
+    pub(crate) fn new(max_concurrent_channels: Option<usize>, policy: ChannelLimitPolicy) -> Self {
+        let inner = max_concurrent_channels.map(|max_concurrent_channels| {
+            let (max_queued, queue_timeout) = match policy {
+                ChannelLimitPolicy::Reject => (0, Duration::ZERO),
+                ChannelLimitPolicy::Queue { timeout, max_queued } => (max_queued, timeout),
+            };
+
+            ChannelLimiterInner {
+                open: Arc::new(Semaphore::new(max_concurrent_channels)),
+                admitted: Arc::new(Semaphore::new(max_concurrent_channels.saturating_add(max_queued))),
+                queue_timeout,
+            }
+        });
+
+        Self { inner }
+    }
+
+    /// Admits a new channel, possibly queued.
+    ///
+    /// Returns `None` when the limit is reached and the queue is full.
+    pub(crate) fn try_admit(&self) -> Option<ChannelAdmission> {
+        let Some(inner) = &self.inner else {
+            return Some(ChannelAdmission { inner: None });
+        };
+
+        let admitted_permit = Arc::clone(&inner.admitted).try_acquire_owned().ok()?;
+
+        Some(ChannelAdmission {
+            inner: Some((admitted_permit, Arc::clone(&inner.open), inner.queue_timeout)),
+        })
+    }
+}
+
+/// Place in the channel queue, released when dropped.
+pub(crate) struct ChannelAdmission {
+    inner: Option<(OwnedSemaphorePermit, Arc<Semaphore>, Duration)>,
+}
+
+impl ChannelAdmission {
+    /// Waits until the channel is allowed to be opened.
+    ///
+    /// Returns `None` when no other channel was closed before the queue timeout.
+    pub(crate) async fn ready(self) -> Option<ChannelPermit> {
+        let Some((admitted_permit, open, queue_timeout)) = self.inner else {
+            return Some(ChannelPermit { _permits: None });
+        };
+
+        // The semaphore is never closed.
+        let open_permit = tokio::time::timeout(queue_timeout, open.acquire_owned())
+            .await
+            .ok()?
+            .ok()?;
+
+        Some(ChannelPermit {
+            _permits: Some((admitted_permit, open_permit)),
+        })
+    }
+}
+
+/// Place among the open channels, released when dropped.
+#[derive(Debug)]
+pub(crate) struct ChannelPermit {
+    _permits: Option<(OwnedSemaphorePermit, OwnedSemaphorePermit)>,
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn semaphores_are_cleaned_up() {
+        let limiter = ConnectLimiter::new(Some(ConnectConcurrencyLimit {
+            per_destination: 1,
+            queue_timeout: Duration::from_millis(10),
+        }));
+
+        let first = limiter.acquire("devolutions.net", 443).await;
+        assert!(first.is_some());
+
+        // Only one attempt at a time for the same destination.
+        assert!(limiter.acquire("DEVOLUTIONS.NET", 443).await.is_none());
+
+        // But other destinations are not affected.
+        assert!(limiter.acquire("devolutions.net", 80).await.is_some());
+
+        drop(first);
+
+        assert!(limiter.semaphores.lock().is_empty());
+        assert!(limiter.acquire("devolutions.net", 443).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn queued_channel_waits_for_a_closed_channel() {
+        let limiter = ChannelLimiter::new(
+            Some(1),
+            ChannelLimitPolicy::Queue {
+                timeout: Duration::from_millis(50),
+                max_queued: 1,
+            },
+        );
+
+        let first = limiter.try_admit().unwrap().ready().await;
+        assert!(first.is_some());
+
+        let queued = limiter.try_admit().unwrap();
+
+        // The queue is full.
+        assert!(limiter.try_admit().is_none());
+
+        // Times out while the first channel is open.
+        assert!(queued.ready().await.is_none());
+
+        let queued = limiter.try_admit().unwrap();
+        drop(first);
+        assert!(queued.ready().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn channel_above_the_limit_is_rejected() {
+        let limiter = ChannelLimiter::new(Some(1), ChannelLimitPolicy::Reject);
+
+        let first = limiter.try_admit().unwrap().ready().await;
+        assert!(first.is_some());
+        assert!(limiter.try_admit().is_none());
+
+        drop(first);
+        assert!(limiter.try_admit().unwrap().ready().await.is_some());
+    }
+}
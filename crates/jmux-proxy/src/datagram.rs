@@ -0,0 +1,157 @@
+//! Datagrams carried over the byte stream of a JMUX channel (`udp://` destinations)
+//!
+//! CHANNEL DATA messages do not preserve message boundaries, so each datagram is prefixed by its length,
+//! encoded as a big-endian `u16`. The peer reconstructs the datagrams from the stream using this prefix,
+//! regardless of how the stream was split into CHANNEL DATA messages.
+
+use bytes::{Buf as _, BufMut as _, BytesMut};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+
+const LENGTH_PREFIX_SIZE: usize = 2;
+
+/// Largest payload a datagram can have, also the largest length the prefix can encode.
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// Binds a UDP socket and connects it to the first address resolved.
+pub(crate) async fn connect(addrs: &[SocketAddr]) -> io::Result<UdpSocket> {
+    let Some(addr) = addrs.first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        ));
+    };
+
+    let bind_addr = if addr.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+
+    Ok(socket)
+}
+
+pub(crate) fn split(socket: UdpSocket) -> (DatagramReader, DatagramWriter) {
+    let socket = Arc::new(socket);
+
+    let reader = DatagramReader {
+        socket: Arc::clone(&socket),
+        recv_buf: vec![0; MAX_DATAGRAM_SIZE],
+        pending: BytesMut::new(),
+    };
+
+    let writer = DatagramWriter {
+        socket,
+        pending: BytesMut::new(),
+    };
+
+    (reader, writer)
+}
+
+/// Reads the datagrams received on a connected socket as a stream of length-prefixed frames.
+pub(crate) struct DatagramReader {
+    socket: Arc<UdpSocket>,
+    recv_buf: Vec<u8>,
+    /// Frame not yet entirely returned to the caller.
+    pending: BytesMut,
+}
+
+impl AsyncRead for DatagramReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.pending.is_empty() {
+            let mut recv_buf = ReadBuf::new(&mut this.recv_buf);
+
+            match ready!(this.socket.poll_recv(cx, &mut recv_buf)) {
+                Ok(()) => {
+                    let datagram = recv_buf.filled();
+                    let len = u16::try_from(datagram.len()).expect("receive buffer is at most u16::MAX bytes long");
+                    this.pending.reserve(LENGTH_PREFIX_SIZE + datagram.len());
+                    this.pending.put_u16(len);
+                    this.pending.put_slice(datagram);
+                }
+                // An ICMP port unreachable was received for a previous datagram; UDP is lossy, so keep going.
+                Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                    trace!(%error, "Datagram rejected by the target");
+                }
+                Err(error) => return Poll::Ready(Err(error)),
+            }
+        }
+
+        let len = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.pending.split_to(len));
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Sends the length-prefixed frames written to it as datagrams on a connected socket.
+pub(crate) struct DatagramWriter {
+    socket: Arc<UdpSocket>,
+    /// Bytes of the frames not sent yet.
+    pending: BytesMut,
+}
+
+impl DatagramWriter {
+    /// Sends all the complete frames buffered so far.
+    fn poll_send_complete_frames(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let Some(prefix) = self.pending.get(..LENGTH_PREFIX_SIZE) else {
+                return Poll::Ready(Ok(()));
+            };
+
+            let frame_len = LENGTH_PREFIX_SIZE + usize::from(u16::from_be_bytes([prefix[0], prefix[1]]));
+
+            if self.pending.len() < frame_len {
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(self.socket.poll_send(cx, &self.pending[LENGTH_PREFIX_SIZE..frame_len])) {
+                Ok(_) => {}
+                // An ICMP port unreachable was received for a previous datagram; UDP is lossy, so keep going.
+                Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                    trace!(%error, "Datagram rejected by the target");
+                }
+                Err(error) => return Poll::Ready(Err(error)),
+            }
+
+            self.pending.advance(frame_len);
+        }
+    }
+}
+
+impl AsyncWrite for DatagramWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Complete frames are not accumulated: at most one partial frame is kept in memory.
+        ready!(this.poll_send_complete_frames(cx))?;
+
+        this.pending.extend_from_slice(buf);
+
+        // The frames not sent right away are sent on the next write or flush.
+        if let Poll::Ready(Err(error)) = this.poll_send_complete_frames(cx) {
+            return Poll::Ready(Err(error));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_complete_frames(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // There is nothing like a FIN for UDP: only the remaining frames are sent.
+        self.get_mut().poll_send_complete_frames(cx)
+    }
+}
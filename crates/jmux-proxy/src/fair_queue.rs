@@ -0,0 +1,148 @@
+//! Round-robin arbitration between the DATA messages of the channels sharing the JMUX pipe
+//!
+//! Each channel queues its DATA messages in its own small queue, and the sender task takes them in turn.
+//! This way, a bulk transfer saturating the pipe only delays the other channels by one message each round,
+//! instead of filling a shared queue in front of them.
+
+use crate::memory_budget::Reservation;
+use jmux_proto::{DistantChannelId, Message};
+use std::collections::VecDeque;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+/// Number of DATA messages a channel may have waiting for the sender task.
+///
+/// Only one message is taken from each channel at each round, so this does not affect the delay a busy channel
+/// imposes to the others. It only needs to be large enough for a single channel to keep the pipe busy.
+pub(crate) const CHANNEL_DATA_QUEUE_SIZE: usize = 8;
+
+/// DATA message queued by a channel, along with the reservation of its payload in the memory budget.
+pub(crate) type QueuedData = (Message, Reservation);
+
+/// Queue of DATA messages for a channel, along with the ID of the channel on the peer side.
+pub(crate) type ChannelDataQueue = (DistantChannelId, mpsc::Receiver<QueuedData>);
+
+#[derive(Default)]
+pub(crate) struct FairQueue {
+    /// The queue at the front is the next to be served.
+    queues: VecDeque<ChannelDataQueue>,
+}
+
+impl FairQueue {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn register(&mut self, queue: ChannelDataQueue) {
+        self.queues.push_back(queue);
+    }
+
+    /// Takes the next message, visiting the channels in turn.
+    ///
+    /// Never completes when no channel has a message to send.
+    pub(crate) fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<QueuedData> {
+        for _ in 0..self.queues.len() {
+            let Some((distant_id, mut queue)) = self.queues.pop_front() else {
+                break;
+            };
+
+            match queue.poll_recv(cx) {
+                Poll::Ready(Some(msg)) => {
+                    self.queues.push_back((distant_id, queue));
+                    return Poll::Ready(msg);
+                }
+                // The channel is done sending data: the queue is forgotten.
+                Poll::Ready(None) => {}
+                Poll::Pending => self.queues.push_back((distant_id, queue)),
+            }
+        }
+
+        Poll::Pending
+    }
+
+    /// Same as [`FairQueue::poll_next`], but returns `None` instead of waiting.
+    pub(crate) fn try_next(&mut self) -> Option<QueuedData> {
+        for _ in 0..self.queues.len() {
+            let (distant_id, mut queue) = self.queues.pop_front()?;
+
+            match queue.try_recv() {
+                Ok(msg) => {
+                    self.queues.push_back((distant_id, queue));
+                    return Some(msg);
+                }
+                Err(mpsc::error::TryRecvError::Disconnected) => {}
+                Err(mpsc::error::TryRecvError::Empty) => self.queues.push_back((distant_id, queue)),
+            }
+        }
+
+        None
+    }
+
+    /// Takes all the messages already queued for the given channel.
+    ///
+    /// Used before sending an EOF or a CLOSE message, so the data is not sent after it.
+    pub(crate) fn take_channel(&mut self, distant_id: DistantChannelId) -> Vec<QueuedData> {
+        let mut messages = Vec::new();
+
+        for (_, queue) in self.queues.iter_mut().filter(|(id, _)| *id == distant_id) {
+            while let Ok(msg) = queue.try_recv() {
+                messages.push(msg);
+            }
+        }
+
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+    use crate::memory_budget::MemoryBudget;
+    use crate::metrics::DataDirection;
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    fn data(memory_budget: &Arc<MemoryBudget>, distant_id: u32, payload: &'static [u8]) -> QueuedData {
+        let msg = Message::data(DistantChannelId::from(distant_id), Bytes::from_static(payload));
+        (msg, memory_budget.reserve(DataDirection::Tx, payload.len()))
+    }
+
+    fn payload((msg, _): QueuedData) -> Bytes {
+        match msg {
+            Message::Data(msg) => msg.transfer_data,
+            _ => panic!("expected CHANNEL DATA"),
+        }
+    }
+
+    #[tokio::test]
+    async fn channels_are_served_in_turn() {
+        let memory_budget = Arc::new(MemoryBudget::new(None, Arc::default()));
+        let mut fair_queue = FairQueue::new();
+
+        let (bulk_tx, bulk_rx) = mpsc::channel(CHANNEL_DATA_QUEUE_SIZE);
+        let (interactive_tx, interactive_rx) = mpsc::channel(CHANNEL_DATA_QUEUE_SIZE);
+        fair_queue.register((DistantChannelId::from(1), bulk_rx));
+        fair_queue.register((DistantChannelId::from(2), interactive_rx));
+
+        for _ in 0..CHANNEL_DATA_QUEUE_SIZE {
+            bulk_tx.send(data(&memory_budget, 1, b"bulk")).await.unwrap();
+        }
+        interactive_tx.send(data(&memory_budget, 2, b"key")).await.unwrap();
+
+        assert_eq!(payload(fair_queue.try_next().unwrap()), "bulk");
+        assert_eq!(payload(fair_queue.try_next().unwrap()), "key");
+        assert_eq!(payload(fair_queue.try_next().unwrap()), "bulk");
+
+        let remaining = fair_queue.take_channel(DistantChannelId::from(1));
+        assert_eq!(remaining.len(), CHANNEL_DATA_QUEUE_SIZE - 2);
+        assert!(fair_queue.try_next().is_none());
+
+        // Queues of the channels done sending are dropped.
+        drop(bulk_tx);
+        drop(interactive_tx);
+        assert!(fair_queue.try_next().is_none());
+        assert!(fair_queue.queues.is_empty());
+    }
+}
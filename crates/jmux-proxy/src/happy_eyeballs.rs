@@ -0,0 +1,225 @@
+//! Simplified "Happy Eyeballs" connection algorithm ([RFC 8305](https://www.rfc-editor.org/rfc/rfc8305))
+//!
+//! The addresses are tried in turn, but the next attempt is started without waiting for the previous one
+//! to fail once the configured delay is elapsed. The first successful connection wins.
+//!
+//! Address families are alternated, and only the first `max_attempts` addresses of the resulting list are tried.
+
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt as _;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+pub(crate) async fn connect(addrs: &[SocketAddr], delay: Duration, max_attempts: usize) -> io::Result<TcpStream> {
+    connect_with(addrs, delay, max_attempts, TcpStream::connect).await
+}
+
+async fn connect_with<T, F, Fut>(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    max_attempts: usize,
+    connect: F,
+) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut addrs = interleave_families(addrs)
+        .into_iter()
+        .take(max_attempts.max(1))
+        .peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(connect(addr)),
+                None => {
+                    return Err(last_error.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any address")
+                    }))
+                }
+            }
+        }
+
+        let has_more_addrs = addrs.peek().is_some();
+
+        tokio::select! {
+            result = attempts.next() => match result {
+                Some(Ok(stream)) => return Ok(stream),
+                Some(Err(error)) => {
+                    trace!(%error, "Connection attempt failed");
+                    last_error = Some(error);
+
+                    // Start the next attempt right away instead of waiting for the delay.
+                    if let Some(addr) = addrs.next() {
+                        attempts.push(connect(addr));
+                    }
+                }
+                None => {}
+            },
+            () = tokio::time::sleep(delay), if has_more_addrs => {
+                if let Some(addr) = addrs.next() {
+                    attempts.push(connect(addr));
+                }
+            }
+        }
+    }
+}
+
+/// Alternates between address families, starting with the family of the first address.
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+
+    let (preferred, others): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .copied()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+
+    let mut result = Vec::with_capacity(addrs.len());
+    let mut preferred = preferred.into_iter();
+    let mut others = others.into_iter();
+
+    loop {
+        match (preferred.next(), others.next()) {
+            (None, None) => break,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::time::Instant;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn address_families_are_interleaved() {
+        let addrs = [
+            addr("[::1]:80"),
+            addr("[::2]:80"),
+            addr("[::3]:80"),
+            addr("127.0.0.1:80"),
+            addr("127.0.0.2:80"),
+        ];
+
+        let expected = [
+            addr("[::1]:80"),
+            addr("127.0.0.1:80"),
+            addr("[::2]:80"),
+            addr("127.0.0.2:80"),
+            addr("[::3]:80"),
+        ];
+
+        assert_eq!(interleave_families(&addrs), expected);
+    }
+
+    /// The first address never answers, and the second one succeeds immediately.
+    /// Returns the duration between the two attempts.
+    async fn second_attempt_after(delay: Duration) -> Duration {
+        let addrs = [addr("127.0.0.1:1"), addr("127.0.0.1:2")];
+        let started: Mutex<Vec<(SocketAddr, Instant)>> = Mutex::new(Vec::new());
+
+        let connected = connect_with(&addrs, delay, 4, |addr| {
+            started.lock().unwrap().push((addr, Instant::now()));
+
+            async move {
+                if addr.port() == 1 {
+                    std::future::pending::<()>().await;
+                }
+
+                Ok(addr)
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(connected, addrs[1]);
+
+        let started = started.lock().unwrap();
+        assert_eq!(started.len(), 2);
+        started[1].1 - started[0].1
+    }
+
+    #[tokio::test]
+    async fn delay_governs_when_the_next_attempt_starts() {
+        let delay = Duration::from_millis(100);
+        assert!(second_attempt_after(delay).await >= delay);
+
+        assert!(second_attempt_after(Duration::ZERO).await < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn failure_starts_the_next_attempt_immediately() {
+        let addrs = [addr("127.0.0.1:1"), addr("127.0.0.1:2")];
+        let start = Instant::now();
+
+        let connected = connect_with(&addrs, Duration::from_secs(60), 4, |addr| async move {
+            if addr.port() == 1 {
+                Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+            } else {
+                Ok(addr)
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(connected, addrs[1]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn last_error_is_returned() {
+        let addrs = [addr("127.0.0.1:1"), addr("127.0.0.1:2")];
+
+        let error = connect_with(&addrs, Duration::ZERO, 4, |_| async {
+            Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionRefused))
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn number_of_attempts_is_capped() {
+        let v4_addrs = (1..=20).map(|i| addr(&format!("127.0.0.{i}:80")));
+        let v6_addrs = (1..=20).map(|i| addr(&format!("[::{i}]:80")));
+        let addrs = v4_addrs.chain(v6_addrs).collect::<Vec<_>>();
+
+        let attempted: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+
+        connect_with(&addrs, Duration::ZERO, 4, |addr| {
+            attempted.lock().unwrap().push(addr);
+            async { Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionRefused)) }
+        })
+        .await
+        .unwrap_err();
+
+        let attempted = attempted.into_inner().unwrap();
+        assert_eq!(
+            attempted,
+            [
+                addr("127.0.0.1:80"),
+                addr("[::1]:80"),
+                addr("127.0.0.2:80"),
+                addr("[::2]:80"),
+            ]
+        );
+    }
+}
@@ -5,38 +5,76 @@
 #[macro_use]
 extern crate tracing;
 
+// Used by tests.
+#[cfg(test)]
+use {proxy_http as _, tracing_subscriber as _};
+
 mod codec;
 mod config;
+mod connect_limiter;
+mod datagram;
+mod fair_queue;
+mod happy_eyeballs;
 mod id_allocator;
-
-pub use self::config::{FilteringRule, JmuxConfig};
+mod log_safe;
+mod matcher;
+mod memory_budget;
+mod metrics;
+mod rate_limiter;
+mod resolver;
+
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+pub use self::codec::{decode_message, encode_message, JmuxCodec};
+pub use self::config::{
+    ChannelDataBufferSize, ChannelLimitPolicy, ConfigError, ConnectConcurrencyLimit, FilteringRule, HostPattern,
+    JmuxConfig, OpenAdmission, OpenAdmissionFn, OpenRetryPolicy, ResolverConcurrencyLimit, TcpKeepalive,
+};
+pub use self::matcher::CompiledFilteringRule;
+pub use self::metrics::{DataDirection, JmuxMetrics, MetricsRecorder};
+pub use self::resolver::{CachingResolver, Resolver, SystemResolver};
 pub use jmux_proto::DestinationUrl;
 
-use self::codec::JmuxCodec;
+use self::connect_limiter::{
+    ChannelAdmission, ChannelLimiter, ChannelPermit, ConnectLimiter, ResolverAdmission, ResolverLimiter,
+};
+use self::fair_queue::{ChannelDataQueue, FairQueue, QueuedData, CHANNEL_DATA_QUEUE_SIZE};
 use self::id_allocator::IdAllocator;
+use self::log_safe::LogPolicy;
+use self::memory_budget::{MemoryBudget, Reservation};
+use self::metrics::{NoopMetricsRecorder, RecordedChannel};
+use self::rate_limiter::TokenBucket;
 use anyhow::Context as _;
 use bytes::Bytes;
-use jmux_proto::{ChannelData, DistantChannelId, Header, LocalChannelId, Message, ReasonCode};
-use std::collections::{HashMap, HashSet};
+use jmux_proto::{
+    Capabilities, ChannelData, ChannelOpen, DistantChannelId, Header, LocalChannelId, Message, ReasonCode,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::io;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::{mpsc, oneshot, Notify};
-use tokio::task::JoinHandle;
+use tokio::task::{AbortHandle, JoinHandle};
 use tokio_util::codec::FramedRead;
 use tracing::{Instrument as _, Span};
 
-const MAXIMUM_PACKET_SIZE_IN_BYTES: u16 = 4 * 1024; // 4 kiB
+const DEFAULT_MAXIMUM_PACKET_SIZE_IN_BYTES: u16 = 4 * 1024; // 4 kiB
 const WINDOW_ADJUSTMENT_THRESHOLD: u32 = 4 * 1024; // 4 kiB
 
-// The JMUX channel will require at most `MAXIMUM_PACKET_SIZE_IN_BYTES × JMUX_MESSAGE_CHANNEL_SIZE` bytes to be kept alive.
+// Initial window size advertised to request a channel without flow control.
+const UNLIMITED_WINDOW_SIZE: u32 = u32::MAX;
+
+// The JMUX channel will require at most `maximum packet size × JMUX_MESSAGE_MPSC_CHANNEL_SIZE` bytes to be kept alive.
 const JMUX_MESSAGE_MPSC_CHANNEL_SIZE: usize = 512;
-const CHANNEL_DATA_MPSC_CHANNEL_SIZE: usize = 256;
-const INTERNAL_MPSC_CHANNEL_SIZE: usize = 32;
+
+// Messages already queued are coalesced into a single write, up to this size.
+const SENDER_BATCH_SIZE_LIMIT: usize = 64 * 1024; // 64 kiB
 
 pub type ApiResponseSender = oneshot::Sender<JmuxApiResponse>;
 pub type ApiResponseReceiver = oneshot::Receiver<JmuxApiResponse>;
@@ -55,6 +93,33 @@ pub enum JmuxApiRequest {
         /// Leftover bytes to be sent to target
         leftover: Option<Bytes>,
     },
+    /// Takes a snapshot of the open channels
+    QueryChannels {
+        api_response_tx: oneshot::Sender<Vec<ChannelStats>>,
+    },
+    /// Stops accepting new channels and closes the open ones gracefully
+    ///
+    /// EOF is sent on all the open channels, and the data still sent by the peer is forwarded until it closes
+    /// the channels as well. The proxy stops once all the channels are closed, or once the grace period
+    /// (see [`JmuxConfig::shutdown_grace_period`]) is elapsed, in which case the remaining channels are closed abruptly.
+    Shutdown,
+}
+
+/// Snapshot of an open channel, as returned for [`JmuxApiRequest::QueryChannels`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStats {
+    pub local_id: LocalChannelId,
+    pub distant_id: DistantChannelId,
+    /// Host of the requested destination
+    pub target_host: String,
+    /// Port of the requested destination
+    pub target_port: u16,
+    /// Bytes read from the target, to be sent to the peer
+    pub bytes_tx: u64,
+    /// Bytes received from the peer, to be written to the target
+    pub bytes_rx: u64,
+    /// Bytes that can currently be sent to the peer before waiting for a window adjustment
+    pub window_size: usize,
 }
 
 #[derive(Debug)]
@@ -68,9 +133,45 @@ pub enum JmuxApiResponse {
     },
 }
 
+/// JMUX proxy, running over a JMUX transport (reader and writer)
+///
+/// The proxy is built using either [`JmuxProxy::new`] followed by the `with_*` methods, or the builder.
+/// With the builder, the transport is required and all the other options are defaulted.
+///
+/// ```
+/// use jmux_proxy::{JmuxConfig, JmuxProxy};
+/// use std::time::Duration;
+///
+/// let (transport, _peer) = tokio::io::duplex(64 * 1024);
+/// let (reader, writer) = tokio::io::split(transport);
+///
+/// let proxy = JmuxProxy::builder()
+///     .jmux_reader(Box::new(reader))
+///     .jmux_writer(Box::new(writer))
+///     .cfg(JmuxConfig::permissive())
+///     .ttl(Duration::from_secs(60 * 60))
+///     .build();
+/// ```
+#[derive(typed_builder::TypedBuilder)]
 pub struct JmuxProxy {
+    #[builder(default)]
     cfg: JmuxConfig,
+    #[builder(default, setter(strip_option))]
     api_request_rx: Option<ApiRequestReceiver>,
+    #[builder(default = Arc::new(SystemResolver))]
+    resolver: Arc<dyn Resolver>,
+    #[builder(default, setter(strip_option))]
+    ttl: Option<Duration>,
+    #[builder(default)]
+    metrics: Arc<JmuxMetrics>,
+    #[builder(default = Arc::new(NoopMetricsRecorder))]
+    metrics_recorder: Arc<dyn MetricsRecorder>,
+    #[builder(default, setter(strip_option, into))]
+    label: Option<String>,
+    #[builder(default = DEFAULT_MAXIMUM_PACKET_SIZE_IN_BYTES)]
+    maximum_packet_size: u16,
+    #[builder(default = ChannelOpen::DEFAULT_INITIAL_WINDOW_SIZE)]
+    initial_window_size: u32,
     jmux_reader: Box<dyn AsyncRead + Unpin + Send>,
     jmux_writer: Box<dyn AsyncWrite + Unpin + Send>,
 }
@@ -84,6 +185,13 @@ impl JmuxProxy {
         Self {
             cfg: JmuxConfig::default(),
             api_request_rx: None,
+            resolver: Arc::new(SystemResolver),
+            ttl: None,
+            metrics: Arc::new(JmuxMetrics::default()),
+            metrics_recorder: Arc::new(NoopMetricsRecorder),
+            label: None,
+            maximum_packet_size: DEFAULT_MAXIMUM_PACKET_SIZE_IN_BYTES,
+            initial_window_size: ChannelOpen::DEFAULT_INITIAL_WINDOW_SIZE,
             jmux_reader,
             jmux_writer,
         }
@@ -101,27 +209,146 @@ impl JmuxProxy {
         self
     }
 
+    /// Sets the resolver used to find the addresses of the requested targets
+    ///
+    /// By default, the system resolver is used for each new channel (see [`SystemResolver`]).
+    #[must_use]
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Sets the maximum lifetime of the JMUX session
+    ///
+    /// Once this duration is elapsed, all the channels are closed and the proxy stops.
+    /// This is typically used to enforce the time-to-live of the token authorizing the session.
+    #[must_use]
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the counters updated by this proxy
+    #[must_use]
+    pub fn with_metrics(mut self, metrics: Arc<JmuxMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Sets the recorder notified of the protocol events of this proxy (see [`MetricsRecorder`])
+    #[must_use]
+    pub fn with_metrics_recorder(mut self, metrics_recorder: Arc<dyn MetricsRecorder>) -> Self {
+        self.metrics_recorder = metrics_recorder;
+        self
+    }
+
+    /// Sets a label attached to all the logs of this proxy (e.g.: a session ID)
+    ///
+    /// Useful to tell the proxies apart when a process is running many of them.
+    #[must_use]
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Sets the maximum size of the packets this proxy accepts on its channels, header included (4 kiB by default)
+    ///
+    /// The smallest of this value and the one advertised by the peer is used for a channel, in both directions.
+    /// Larger packets improve the throughput on high-latency links, at the cost of memory: each channel buffers up to
+    /// [`JmuxConfig::channel_data_buffer_size`] packets towards its target, and the pipe itself up to 512 packets.
+    /// The value must be larger than the overhead of a CHANNEL DATA message, or the proxy fails to start.
+    #[must_use]
+    pub fn with_maximum_packet_size(mut self, maximum_packet_size: u16) -> Self {
+        self.maximum_packet_size = maximum_packet_size;
+        self
+    }
+
+    /// Sets the initial window size advertised for the channels requested through the API (64 MiB by default)
+    ///
+    /// This is how many bytes the peer may send on a channel before waiting for a window adjustment, so it bounds
+    /// the data in flight per channel. Larger windows are needed to fill high bandwidth-delay product links.
+    /// The peer may clamp the advertised window (see [`JmuxConfig::max_initial_window_size`]).
+    #[must_use]
+    pub fn with_initial_window_size(mut self, initial_window_size: u32) -> Self {
+        self.initial_window_size = initial_window_size;
+        self
+    }
+
     pub async fn run(self) -> anyhow::Result<()> {
-        let span = Span::current();
-        run_proxy_impl(self, span.clone()).instrument(span).await
+        let span = self.root_span();
+        run_proxy_impl(self, span.clone()).instrument(span).await?;
+        Ok(())
+    }
+
+    /// Same as [`JmuxProxy::run`], but the JMUX transport is given back once the proxy is stopped
+    ///
+    /// This is useful to reuse the underlying transport for something else (e.g.: raw passthrough).
+    /// Bytes received from the peer but not yet processed are kept at the front of the returned reader.
+    ///
+    /// The transport can only be reclaimed after a graceful shutdown (the peer closing the pipe or the TTL being
+    /// elapsed). An error is returned when one of the internal tasks failed, and nothing is returned at all if
+    /// the future is dropped before completion.
+    pub async fn run_reclaim(
+        self,
+    ) -> anyhow::Result<(Box<dyn AsyncRead + Unpin + Send>, Box<dyn AsyncWrite + Unpin + Send>)> {
+        let span = self.root_span();
+        run_proxy_impl(self, span.clone())
+            .instrument(span)
+            .await?
+            .context("JMUX session was not shut down gracefully")
+    }
+
+    fn root_span(&self) -> Span {
+        match &self.label {
+            Some(label) => info_span!("jmux", %label),
+            None => Span::current(),
+        }
     }
 }
 
-async fn run_proxy_impl(proxy: JmuxProxy, span: Span) -> anyhow::Result<()> {
+async fn run_proxy_impl(
+    proxy: JmuxProxy,
+    span: Span,
+) -> anyhow::Result<Option<(Box<dyn AsyncRead + Unpin + Send>, Box<dyn AsyncWrite + Unpin + Send>)>> {
     let JmuxProxy {
         cfg,
         api_request_rx,
+        resolver,
+        ttl,
+        metrics,
+        metrics_recorder,
+        label: _,
+        maximum_packet_size,
+        initial_window_size,
         jmux_reader,
         jmux_writer,
     } = proxy;
 
+    cfg.validate().context("invalid JMUX configuration")?;
+
+    let channel_limits = ChannelLimits::new(maximum_packet_size, initial_window_size)?;
+
     let (msg_to_send_tx, msg_to_send_rx) = mpsc::channel::<Message>(JMUX_MESSAGE_MPSC_CHANNEL_SIZE);
+    let sender_shutdown = Arc::new(Notify::new());
+    let log_policy = LogPolicy::new(&cfg);
+    let memory_budget = Arc::new(MemoryBudget::new(cfg.max_outstanding_bytes, Arc::clone(&metrics)));
+    // Unbounded, so the sender task never waits on the scheduler which may itself be waiting on the sender task.
+    let (close_handed_over_tx, close_handed_over_rx) = mpsc::unbounded_channel();
+    let (data_queue_tx, data_queue_rx) = mpsc::unbounded_channel();
+    let compressed_fields = Arc::new(AtomicBool::new(false));
 
-    let jmux_stream = FramedRead::new(jmux_reader, JmuxCodec);
+    let codec = JmuxCodec::new().with_max_destination_url_size(cfg.max_destination_url_size);
+    let jmux_stream = FramedRead::new(jmux_reader, codec);
 
     let sender_task_handle = JmuxSenderTask {
         jmux_writer,
         msg_to_send_rx,
+        data_queue_rx,
+        shutdown: Arc::clone(&sender_shutdown),
+        log_policy,
+        compressed_fields: Arc::clone(&compressed_fields),
+        max_destination_url_size: cfg.max_destination_url_size,
+        close_handed_over_tx,
     }
     .spawn(span.clone());
 
@@ -129,8 +356,18 @@ async fn run_proxy_impl(proxy: JmuxProxy, span: Span) -> anyhow::Result<()> {
 
     let scheduler_task_handle = JmuxSchedulerTask {
         cfg,
+        resolver,
+        ttl,
+        metrics,
+        metrics_recorder,
+        memory_budget,
+        channel_limits,
         jmux_stream,
         msg_to_send_tx,
+        data_queue_tx,
+        close_handed_over_rx,
+        sender_shutdown,
+        compressed_fields,
         api_request_rx,
         parent_span: span,
     }
@@ -143,10 +380,21 @@ async fn run_proxy_impl(proxy: JmuxProxy, span: Span) -> anyhow::Result<()> {
             // Usually, it's only of interest when both tasks are failed.
             anyhow::bail!("both scheduler and sender tasks failed: {} & {}", scheduler_e, sender_e)
         }
-        (Ok(_), Ok(_)) => {}
+        (Ok(mut jmux_stream), Ok(jmux_writer)) => {
+            let read_buf = jmux_stream.read_buffer_mut().split().freeze();
+            let jmux_reader = jmux_stream.into_inner();
+
+            let jmux_reader: Box<dyn AsyncRead + Unpin + Send> = if read_buf.is_empty() {
+                jmux_reader
+            } else {
+                Box::new(io::Cursor::new(read_buf).chain(jmux_reader))
+            };
+
+            return Ok(Some((jmux_reader, jmux_writer)));
+        }
     }
 
-    Ok(())
+    Ok(None)
 }
 
 // === implementation details === //
@@ -172,28 +420,107 @@ struct JmuxChannelCtx {
     remote_window_size: u32,
 
     maximum_packet_size: u16,
+    /// Number of DATA packets dropped for exceeding the maximum packet size
+    dropped_oversized_packets: u64,
+    /// Sequence number expected for the next sequenced DATA packet received
+    next_distant_sequence_number: u32,
+
+    /// Whether the window-based flow control is applied for this channel
+    flow_control: bool,
+
+    /// Requested destination
+    destination_url: DestinationUrl,
+    /// Bytes read from the target, to be sent to the peer
+    bytes_tx: Arc<AtomicU64>,
+    /// Bytes received from the peer, to be written to the target
+    bytes_rx: u64,
+    /// Last time data was seen moving in either direction, as observed by the idle sweep
+    last_activity: tokio::time::Instant,
+    /// Sum of `bytes_tx` and `bytes_rx` when `last_activity` was updated
+    last_activity_bytes: u64,
+
+    /// Used to stop reading from the stream when the channel is forcibly closed
+    reader_task: Option<AbortHandle>,
+    /// Pending accept-idle timer, cancelled as soon as the peer shows some activity
+    idle_timer: Option<AbortHandle>,
+    /// Place among the channels requested by the peer, released when the channel is dropped
+    channel_permit: Option<ChannelPermit>,
+    /// Reports the channel to the metrics recorder as closed when dropped (set once registered)
+    recorded: Option<RecordedChannel>,
 
     span: Span,
 }
 
+impl JmuxChannelCtx {
+    fn cancel_idle_timer(&mut self) {
+        if let Some(idle_timer) = self.idle_timer.take() {
+            idle_timer.abort();
+        }
+    }
+
+    /// Records the activity since the last call, and returns for how long the channel has been idle.
+    fn idle_for(&mut self, now: tokio::time::Instant) -> Duration {
+        let bytes = self.bytes_tx.load(Ordering::Relaxed) + self.bytes_rx;
+
+        if bytes != self.last_activity_bytes {
+            self.last_activity_bytes = bytes;
+            self.last_activity = now;
+        }
+
+        now.saturating_duration_since(self.last_activity)
+    }
+
+    fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            local_id: self.local_id,
+            distant_id: self.distant_id,
+            target_host: self.destination_url.host().to_owned(),
+            target_port: self.destination_url.port(),
+            bytes_tx: self.bytes_tx.load(Ordering::Relaxed),
+            bytes_rx: self.bytes_rx,
+            window_size: self.window_size.load(Ordering::SeqCst),
+        }
+    }
+}
+
 struct JmuxCtx {
     id_allocator: IdAllocator<LocalChannelId>,
     channels: HashMap<LocalChannelId, JmuxChannelCtx>,
+    /// CLOSE messages queued but not yet handed to the sender task
+    closes_in_flight: HashMap<DistantChannelId, LocalChannelId>,
+    /// Channels closed locally within the late data grace period, oldest first
+    recently_closed: VecDeque<(LocalChannelId, tokio::time::Instant)>,
+    late_data_grace_period: Duration,
+    metrics_recorder: Arc<dyn MetricsRecorder>,
 }
 
 impl JmuxCtx {
-    fn new() -> Self {
+    fn new(late_data_grace_period: Duration, metrics_recorder: Arc<dyn MetricsRecorder>) -> Self {
         Self {
             id_allocator: IdAllocator::<LocalChannelId>::new(),
             channels: HashMap::new(),
+            closes_in_flight: HashMap::new(),
+            recently_closed: VecDeque::new(),
+            late_data_grace_period,
+            metrics_recorder,
         }
     }
 
     fn allocate_id(&mut self) -> Option<LocalChannelId> {
-        self.id_allocator.alloc()
+        let id = self.id_allocator.alloc()?;
+
+        // Data received from now on is meant for the new channel.
+        self.recently_closed.retain(|(closed_id, _)| *closed_id != id);
+
+        Some(id)
     }
 
-    fn register_channel(&mut self, channel: JmuxChannelCtx) -> anyhow::Result<()> {
+    fn register_channel(&mut self, mut channel: JmuxChannelCtx) -> anyhow::Result<()> {
+        channel.recorded = Some(RecordedChannel::new(
+            Arc::clone(&self.metrics_recorder),
+            channel.local_id,
+        ));
+
         if let Some(replaced_channel) = self.channels.insert(channel.local_id, channel) {
             anyhow::bail!(
                 "detected two streams with the same local ID {}",
@@ -203,30 +530,242 @@ impl JmuxCtx {
         Ok(())
     }
 
-    fn get_channel(&mut self, id: LocalChannelId) -> Option<&JmuxChannelCtx> {
-        self.channels.get(&id)
-    }
-
     fn get_channel_mut(&mut self, id: LocalChannelId) -> Option<&mut JmuxChannelCtx> {
         self.channels.get_mut(&id)
     }
 
     fn unregister(&mut self, id: LocalChannelId) {
-        self.channels.remove(&id);
+        if let Some(mut channel) = self.channels.remove(&id) {
+            channel.cancel_idle_timer();
+
+            if self.closes_in_flight.get(&channel.distant_id) == Some(&id) {
+                // Freed once the CLOSE message is handed to the sender task.
+                return;
+            }
+        }
         self.id_allocator.free(id);
     }
+
+    /// Keeps the ID of the channel reserved until the CLOSE message queued for it is handed to the sender task.
+    ///
+    /// Otherwise, a new channel could reuse the ID before the peer is even told that the previous one is closed.
+    fn close_queued(&mut self, id: LocalChannelId, distant_id: DistantChannelId) {
+        self.closes_in_flight.insert(distant_id, id);
+
+        let now = tokio::time::Instant::now();
+        self.forget_closed_before(now);
+        self.recently_closed.push_back((id, now));
+    }
+
+    /// Returns true when the channel was closed locally within the late data grace period.
+    ///
+    /// Data received for such a channel was likely sent by the peer before it learned about the closure.
+    fn is_recently_closed(&mut self, id: LocalChannelId) -> bool {
+        self.forget_closed_before(tokio::time::Instant::now());
+        self.recently_closed.iter().any(|(closed_id, _)| *closed_id == id)
+    }
+
+    fn forget_closed_before(&mut self, now: tokio::time::Instant) {
+        while self
+            .recently_closed
+            .front()
+            .is_some_and(|(_, closed_at)| now.saturating_duration_since(*closed_at) >= self.late_data_grace_period)
+        {
+            self.recently_closed.pop_front();
+        }
+    }
+
+    fn close_handed_over(&mut self, distant_id: DistantChannelId) {
+        if let Some(id) = self.closes_in_flight.remove(&distant_id) {
+            if !self.channels.contains_key(&id) {
+                self.id_allocator.free(id);
+            }
+        }
+    }
+}
+
+/// Packet and window sizes advertised by this proxy
+#[derive(Debug, Clone, Copy)]
+struct ChannelLimits {
+    maximum_packet_size: u16,
+    initial_window_size: u32,
+}
+
+impl ChannelLimits {
+    fn new(maximum_packet_size: u16, initial_window_size: u32) -> anyhow::Result<Self> {
+        // Otherwise, a packet couldn't carry any transfer data.
+        let min_packet_size = Header::SIZE + ChannelData::FIXED_PART_SIZE;
+
+        anyhow::ensure!(
+            usize::from(maximum_packet_size) > min_packet_size,
+            "maximum packet size must be larger than {min_packet_size} bytes (got {maximum_packet_size})"
+        );
+        anyhow::ensure!(initial_window_size > 0, "initial window size must not be zero");
+        // This value is reserved to request disabling the flow control.
+        anyhow::ensure!(
+            initial_window_size != UNLIMITED_WINDOW_SIZE,
+            "initial window size must be smaller than {UNLIMITED_WINDOW_SIZE}"
+        );
+
+        Ok(Self {
+            maximum_packet_size,
+            initial_window_size,
+        })
+    }
+
+    /// Maximum packet size for a channel, given the one advertised by the peer
+    fn maximum_packet_size(&self, advertised: u16) -> u16 {
+        advertised.min(self.maximum_packet_size)
+    }
+}
+
+/// Optional features used for the session
+struct SessionCapabilities {
+    /// Capabilities advertised to the peer
+    local: Capabilities,
+    /// Capabilities supported by both peers
+    negotiated: Capabilities,
+    sequence_data: bool,
+    /// Shared with the sender task, which encodes the messages
+    compressed_fields: Arc<AtomicBool>,
+}
+
+impl SessionCapabilities {
+    fn new(cfg: &JmuxConfig, compressed_fields: Arc<AtomicBool>) -> Self {
+        let mut features = Capabilities::COMPRESSED_FIELDS | Capabilities::SEQUENCE_NUMBERS;
+
+        if cfg.disable_flow_control {
+            features |= Capabilities::UNLIMITED_WINDOW;
+        }
+
+        // A feature is only used when advertised by both peers, so nothing is supported when not advertising.
+        let local = if cfg.advertise_capabilities {
+            Capabilities::new(features)
+        } else {
+            Capabilities::new(0)
+        };
+
+        Self {
+            local,
+            // Only the baseline features are used until the peer advertises its capabilities.
+            negotiated: Capabilities::new(0),
+            sequence_data: cfg.sequence_data,
+            compressed_fields,
+        }
+    }
+
+    fn peer_advertised(&mut self, peer: &Capabilities) {
+        self.negotiated = self.local.intersection(peer);
+        self.compressed_fields.store(
+            self.negotiated.supports(Capabilities::COMPRESSED_FIELDS),
+            Ordering::SeqCst,
+        );
+    }
+
+    /// Whether the CHANNEL DATA messages sent are numbered
+    fn sequence_data(&self) -> bool {
+        self.sequence_data && self.negotiated.supports(Capabilities::SEQUENCE_NUMBERS)
+    }
+
+    /// Whether the flow control is skipped for the channels advertising an unlimited window
+    fn unlimited_window(&self) -> bool {
+        self.negotiated.supports(Capabilities::UNLIMITED_WINDOW)
+    }
+}
+
+/// Channel requested through the API, waiting for the peer to answer
+struct PendingChannel {
+    destination_url: DestinationUrl,
+    api_response_tx: ApiResponseSender,
+    failed_attempts: u32,
 }
 
 type MessageReceiver = mpsc::Receiver<Message>;
 type MessageSender = mpsc::Sender<Message>;
-type DataReceiver = mpsc::Receiver<Bytes>;
-type DataSender = mpsc::Sender<Bytes>;
-type InternalMessageSender = mpsc::Sender<InternalMessage>;
+/// Data received from the peer, along with its reservation in the memory budget
+type TargetData = (Bytes, Reservation);
+type DataReceiver = mpsc::Receiver<TargetData>;
+type DataSender = mpsc::Sender<TargetData>;
 
 #[derive(Debug)]
 enum InternalMessage {
-    Eof { id: LocalChannelId },
-    StreamResolved { channel: JmuxChannelCtx, stream: TcpStream },
+    Eof {
+        id: LocalChannelId,
+    },
+    AcceptIdleTimeout {
+        id: LocalChannelId,
+    },
+    RetryOpen {
+        id: LocalChannelId,
+    },
+    OpenAdmitted {
+        id: LocalChannelId,
+    },
+    OpenDenied {
+        id: LocalChannelId,
+        reason: ReasonCode,
+    },
+    StreamResolved {
+        channel: Box<JmuxChannelCtx>,
+        stream: TargetStream,
+    },
+    DataQueueReady {
+        id: LocalChannelId,
+        permit: mpsc::OwnedPermit<TargetData>,
+    },
+}
+
+type TargetReader = Box<dyn AsyncRead + Unpin + Send>;
+type TargetWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Connection to the target of a channel.
+#[derive(Debug)]
+enum TargetStream {
+    Tcp(TcpStream),
+    /// Datagrams are framed over the channel data, see the `datagram` module.
+    Udp(UdpSocket),
+}
+
+impl TargetStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            TargetStream::Tcp(stream) => stream.local_addr(),
+            TargetStream::Udp(socket) => socket.local_addr(),
+        }
+    }
+
+    fn into_split(self) -> (TargetReader, TargetWriter) {
+        match self {
+            TargetStream::Tcp(stream) => {
+                let (reader, writer) = stream.into_split();
+                (Box::new(reader), Box::new(writer))
+            }
+            TargetStream::Udp(socket) => {
+                let (reader, writer) = datagram::split(socket);
+                (Box::new(reader), Box::new(writer))
+            }
+        }
+    }
+}
+
+/// Sender for the internal messages, keeping track of the times the scheduler is not able to follow.
+#[derive(Clone)]
+struct InternalMessageSender {
+    inner: mpsc::Sender<InternalMessage>,
+    metrics: Arc<JmuxMetrics>,
+}
+
+impl InternalMessageSender {
+    async fn send(&self, msg: InternalMessage) -> Result<(), mpsc::error::SendError<InternalMessage>> {
+        match self.inner.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(msg)) => {
+                self.metrics.internal_channel_full.fetch_add(1, Ordering::Relaxed);
+                self.inner.send(msg).await
+            }
+            Err(mpsc::error::TrySendError::Closed(msg)) => Err(mpsc::error::SendError(msg)),
+        }
+    }
 }
 
 // === internal tasks === //
@@ -236,52 +775,232 @@ enum InternalMessage {
 struct JmuxSenderTask<T: AsyncWrite + Unpin + Send + 'static> {
     jmux_writer: T,
     msg_to_send_rx: MessageReceiver,
+    /// DATA queues of the channels, registered as they are started
+    data_queue_rx: mpsc::UnboundedReceiver<ChannelDataQueue>,
+    /// Notified when the scheduler stops the session on its own (e.g.: the TTL is elapsed)
+    shutdown: Arc<Notify>,
+    log_policy: LogPolicy,
+    /// Set by the scheduler once the peer is known to support compressed fields
+    compressed_fields: Arc<AtomicBool>,
+    max_destination_url_size: usize,
+    /// Distant IDs of the CLOSE messages taken from the queue, reported to the scheduler
+    close_handed_over_tx: mpsc::UnboundedSender<DistantChannelId>,
 }
 
 impl<T: AsyncWrite + Unpin + Send + 'static> JmuxSenderTask<T> {
-    fn spawn(self, span: Span) -> ChildTask<anyhow::Result<()>> {
+    fn spawn(self, span: Span) -> ChildTask<anyhow::Result<T>> {
         let fut = self.run().instrument(span);
         ChildTask(tokio::spawn(fut))
     }
 
+    /// Returns the JMUX writer on clean exit.
+    ///
+    /// Messages sent by the scheduler go first, and the DATA messages of the channels are taken in turn.
     #[instrument("sender", skip_all)]
-    async fn run(self) -> anyhow::Result<()> {
+    async fn run(self) -> anyhow::Result<T> {
         let Self {
             jmux_writer,
             mut msg_to_send_rx,
+            mut data_queue_rx,
+            shutdown,
+            log_policy,
+            compressed_fields,
+            max_destination_url_size,
+            close_handed_over_tx,
         } = self;
 
         let mut jmux_writer = tokio::io::BufWriter::with_capacity(16 * 1024, jmux_writer);
-        let mut buf = bytes::BytesMut::new();
+        let mut fair_queue = FairQueue::new();
+        let mut batch = SenderBatch::new(
+            log_policy,
+            &compressed_fields,
+            max_destination_url_size,
+            &close_handed_over_tx,
+        );
         let mut needs_flush = false;
 
         loop {
-            tokio::select! {
+            let msg = tokio::select! {
+                biased;
+
                 msg = msg_to_send_rx.recv() => {
                     let Some(msg) = msg else {
                         break;
                     };
+                    (msg, None)
+                }
+                Some(data_queue) = data_queue_rx.recv() => {
+                    fair_queue.register(data_queue);
+                    continue;
+                }
+                _ = shutdown.notified() => {
+                    // Send the messages already queued (e.g.: CLOSE messages) before stopping.
+                    batch.clear();
 
-                    trace!(?msg, "Send channel message");
+                    while let Ok(msg) = msg_to_send_rx.try_recv() {
+                        batch.push((msg, None), &mut fair_queue, &mut data_queue_rx);
+                    }
 
-                    buf.clear();
-                    msg.encode(&mut buf)?;
+                    jmux_writer.write_all(&batch.buf).await?;
+                    batch.clear();
 
-                    jmux_writer.write_all(&buf).await?;
-                    needs_flush = true;
+                    break;
                 }
-                _ = tokio::time::sleep(core::time::Duration::from_millis(10)), if needs_flush => {
+                (msg, reservation) = core::future::poll_fn(|cx| fair_queue.poll_next(cx)) => (msg, Some(reservation)),
+                _ = tokio::time::sleep(Duration::from_millis(10)), if needs_flush => {
                     jmux_writer.flush().await?;
                     needs_flush = false;
+                    continue;
                 }
+            };
+
+            batch.clear();
+            batch.push(msg, &mut fair_queue, &mut data_queue_rx);
+
+            // Coalesce the messages immediately available to reduce the number of writes.
+            while batch.buf.len() < SENDER_BATCH_SIZE_LIMIT {
+                let Some(msg) = msg_to_send_rx
+                    .try_recv()
+                    .ok()
+                    .map(|msg| (msg, None))
+                    .or_else(|| fair_queue.try_next().map(|(msg, reservation)| (msg, Some(reservation))))
+                else {
+                    break;
+                };
+
+                batch.push(msg, &mut fair_queue, &mut data_queue_rx);
             }
+
+            jmux_writer.write_all(&batch.buf).await?;
+            batch.clear();
+            needs_flush = true;
         }
 
         info!("Closing JMUX sender task...");
 
         jmux_writer.flush().await?;
 
-        Ok(())
+        Ok(jmux_writer.into_inner())
+    }
+}
+
+/// Messages encoded by the sender task for a single write.
+struct SenderBatch<'a> {
+    buf: bytes::BytesMut,
+    /// Reservations of the channel data in the batch, released once written
+    reservations: Vec<Reservation>,
+    log_policy: LogPolicy,
+    compressed_fields: &'a AtomicBool,
+    max_destination_url_size: usize,
+    close_handed_over_tx: &'a mpsc::UnboundedSender<DistantChannelId>,
+}
+
+impl<'a> SenderBatch<'a> {
+    fn new(
+        log_policy: LogPolicy,
+        compressed_fields: &'a AtomicBool,
+        max_destination_url_size: usize,
+        close_handed_over_tx: &'a mpsc::UnboundedSender<DistantChannelId>,
+    ) -> Self {
+        Self {
+            buf: bytes::BytesMut::new(),
+            reservations: Vec::new(),
+            log_policy,
+            compressed_fields,
+            max_destination_url_size,
+            close_handed_over_tx,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.buf.clear();
+        self.reservations.clear();
+    }
+
+    /// Adds the message to the batch, after the data still queued for its channel in case of EOF or CLOSE.
+    fn push(
+        &mut self,
+        (msg, reservation): (Message, Option<Reservation>),
+        fair_queue: &mut FairQueue,
+        data_queue_rx: &mut mpsc::UnboundedReceiver<ChannelDataQueue>,
+    ) {
+        let ends_channel_data = match &msg {
+            Message::Eof(msg) => Some(DistantChannelId::from(msg.recipient_channel_id)),
+            Message::Close(msg) => Some(DistantChannelId::from(msg.recipient_channel_id)),
+            _ => None,
+        };
+
+        if let Some(distant_id) = ends_channel_data {
+            // The queue of the channel may have been registered in the meantime.
+            while let Ok(data_queue) = data_queue_rx.try_recv() {
+                fair_queue.register(data_queue);
+            }
+
+            for (data, reservation) in fair_queue.take_channel(distant_id) {
+                self.encode(data, Some(reservation));
+            }
+        }
+
+        self.encode(msg, reservation);
+    }
+
+    fn encode(&mut self, msg: Message, reservation: Option<Reservation>) {
+        trace!(msg = ?self.log_policy.message(&msg), "Send channel message");
+
+        let compressed_fields = self.compressed_fields.load(Ordering::SeqCst);
+        encode_or_skip(
+            &msg,
+            &mut self.buf,
+            compressed_fields,
+            self.max_destination_url_size,
+            self.log_policy,
+        );
+        report_close(&msg, self.close_handed_over_tx);
+        self.reservations.extend(reservation);
+    }
+}
+
+/// Encodes the message at the end of the buffer, or drops it if it can't be encoded.
+///
+/// Nothing is written for a message failing to encode, so the JMUX stream is not corrupted
+/// and the other channels are not affected.
+///
+/// When the peer supports it, the variable-length field of the CHANNEL OPEN and CHANNEL OPEN FAILURE messages
+/// is compressed.
+fn encode_or_skip(
+    msg: &Message,
+    buf: &mut bytes::BytesMut,
+    compressed_fields: bool,
+    max_destination_url_size: usize,
+    log_policy: LogPolicy,
+) {
+    let len_before = buf.len();
+
+    let result = if compressed_fields {
+        msg.encode_compressed_with_max_url_size(buf, max_destination_url_size)
+    } else {
+        msg.encode_with_max_url_size(buf, max_destination_url_size)
+    };
+
+    if let Err(error) = result {
+        buf.truncate(len_before);
+        error!(%error, msg = ?log_policy.message(msg), "Failed to encode message; skipped");
+    }
+}
+
+/// Tells the scheduler that a CLOSE message was handed to the sender task, so the ID of the channel can be reused.
+fn report_close(msg: &Message, close_handed_over_tx: &mpsc::UnboundedSender<DistantChannelId>) {
+    if let Message::Close(msg) = msg {
+        // The scheduler is already stopped when this fails.
+        let _ = close_handed_over_tx.send(DistantChannelId::from(msg.recipient_channel_id));
+    }
+}
+
+/// Size of the channel data carried by the message, as accounted by the [`MemoryBudget`].
+fn transfer_data_len(msg: &Message) -> usize {
+    match msg {
+        Message::Data(msg) => msg.transfer_data.len(),
+        _ => 0,
     }
 }
 
@@ -289,42 +1008,100 @@ impl<T: AsyncWrite + Unpin + Send + 'static> JmuxSenderTask<T> {
 
 struct JmuxSchedulerTask<T: AsyncRead + Unpin + Send + 'static> {
     cfg: JmuxConfig,
+    resolver: Arc<dyn Resolver>,
+    ttl: Option<Duration>,
+    metrics: Arc<JmuxMetrics>,
+    metrics_recorder: Arc<dyn MetricsRecorder>,
+    memory_budget: Arc<MemoryBudget>,
+    channel_limits: ChannelLimits,
     jmux_stream: FramedRead<T, JmuxCodec>,
     msg_to_send_tx: MessageSender,
+    data_queue_tx: mpsc::UnboundedSender<ChannelDataQueue>,
+    close_handed_over_rx: mpsc::UnboundedReceiver<DistantChannelId>,
+    sender_shutdown: Arc<Notify>,
+    compressed_fields: Arc<AtomicBool>,
     api_request_rx: ApiRequestReceiver,
     parent_span: Span,
 }
 
 impl<T: AsyncRead + Unpin + Send + 'static> JmuxSchedulerTask<T> {
-    fn spawn(self) -> ChildTask<anyhow::Result<()>> {
+    fn spawn(self) -> ChildTask<anyhow::Result<FramedRead<T, JmuxCodec>>> {
         let parent_span = self.parent_span.clone();
         let fut = scheduler_task_impl(self).instrument(parent_span);
         ChildTask(tokio::spawn(fut))
     }
 }
 
+/// Returns the JMUX stream on clean exit.
 #[instrument("scheduler", skip_all)]
-async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSchedulerTask<T>) -> anyhow::Result<()> {
+async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(
+    task: JmuxSchedulerTask<T>,
+) -> anyhow::Result<FramedRead<T, JmuxCodec>> {
     use futures_util::StreamExt as _;
 
     let JmuxSchedulerTask {
         cfg,
+        resolver,
+        ttl,
+        metrics,
+        metrics_recorder,
+        memory_budget,
+        channel_limits,
         mut jmux_stream,
         msg_to_send_tx,
+        data_queue_tx,
+        mut close_handed_over_rx,
+        sender_shutdown,
+        compressed_fields,
         mut api_request_rx,
         parent_span,
     } = task;
 
-    let mut jmux_ctx = JmuxCtx::new();
-    let mut data_senders: HashMap<LocalChannelId, DataSender> = HashMap::new();
-    let mut pending_channels: HashMap<LocalChannelId, (DestinationUrl, ApiResponseSender)> = HashMap::new();
+    let filtering = cfg.filtering.compile();
+    let log_policy = LogPolicy::new(&cfg);
+    let connect_limiter = ConnectLimiter::new(cfg.connect_concurrency_limit);
+    let resolver_limiter = ResolverLimiter::new(cfg.resolver_concurrency_limit);
+    let channel_limiter = ChannelLimiter::new(cfg.max_concurrent_channels, cfg.channel_limit_policy);
+    let mut jmux_ctx = JmuxCtx::new(cfg.late_data_grace_period, Arc::clone(&metrics_recorder));
+    let mut capabilities = SessionCapabilities::new(&cfg, compressed_fields);
+    let mut data_senders: HashMap<LocalChannelId, ChannelDataSender> = HashMap::new();
+    let mut pending_channels: HashMap<LocalChannelId, PendingChannel> = HashMap::new();
+    // Channels requested by the peer, waiting for the decision of the admission hook
+    let mut pending_admissions: HashMap<LocalChannelId, StreamResolverTask> = HashMap::new();
     let mut needs_window_adjustment: HashSet<LocalChannelId> = HashSet::new();
-    let (internal_msg_tx, mut internal_msg_rx) = mpsc::channel::<InternalMessage>(INTERNAL_MPSC_CHANNEL_SIZE);
+    let (internal_msg_tx, mut internal_msg_rx) =
+        mpsc::channel::<InternalMessage>(core::cmp::max(cfg.internal_channel_size, 1));
+    let internal_msg_tx = InternalMessageSender {
+        inner: internal_msg_tx,
+        metrics: Arc::clone(&metrics),
+    };
 
     // Safety net against poor AsyncRead trait implementations.
     const MAX_CONSECUTIVE_PIPE_FAILURES: u8 = 5;
     let mut nb_consecutive_pipe_failures = 0;
 
+    let ttl_sleep = tokio::time::sleep(ttl.unwrap_or_default());
+    tokio::pin!(ttl_sleep);
+
+    // Armed once a shutdown is requested.
+    let grace_period_sleep = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(grace_period_sleep);
+    let mut draining = false;
+
+    // Idle channels are detected with a granularity of a quarter of the timeout (not polled at all when disabled).
+    let idle_sweep_period = cfg.idle_timeout.map_or(Duration::from_secs(1), |idle_timeout| {
+        (idle_timeout / 4).max(Duration::from_millis(1))
+    });
+    let mut idle_sweep = tokio::time::interval(idle_sweep_period);
+    idle_sweep.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    if cfg.advertise_capabilities {
+        msg_to_send_tx
+            .send(Message::capabilities(capabilities.local.features))
+            .await
+            .context("couldn’t send CAPABILITIES message through mpsc channel")?;
+    }
+
     loop {
         // NOTE: Current task is the "jmux scheduler" or "jmux orchestrator".
         // It handles the JMUX context and communicates with other tasks.
@@ -333,116 +1110,220 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
         // when they do not, it means that the JMUX proxy is already under very high load as the subtasks are not able to follow.
         // It's also expected to be resilient and `?` operator should be used only for unrecoverable failures.
 
+        if draining && jmux_ctx.channels.is_empty() {
+            info!("All channels are closed; JMUX session shut down gracefully");
+            sender_shutdown.notify_one();
+            break;
+        }
+
         tokio::select! {
             Some(request) = api_request_rx.recv() => {
                 match request {
+                    JmuxApiRequest::OpenChannel { destination_url, .. } if draining => {
+                        debug!("Ignoring API request while shutting down: {}", log_policy.url(&destination_url));
+                    }
                     JmuxApiRequest::OpenChannel { destination_url, api_response_tx } => {
                         match jmux_ctx.allocate_id() {
+                            Some(id) if destination_url.as_bytes().len() > cfg.max_destination_url_size => {
+                                warn!("Destination URL is too long for API request: {}", log_policy.url(&destination_url));
+                                jmux_ctx.unregister(id);
+                                let _ = api_response_tx.send(JmuxApiResponse::Failure { id, reason_code: ReasonCode::GENERAL_FAILURE });
+                            }
                             Some(id) => {
                                 trace!("Allocated local ID {}", id);
-                                debug!("{} request {}", id, destination_url);
-                                pending_channels.insert(id, (destination_url.clone(), api_response_tx));
+                                debug!("{} request {}", id, log_policy.url(&destination_url));
+                                let open = channel_open(id, destination_url.clone(), channel_limits, &capabilities);
+
+                                pending_channels.insert(id, PendingChannel { destination_url, api_response_tx, failed_attempts: 0 });
+
                                 msg_to_send_tx
-                                    .send(Message::open(id, MAXIMUM_PACKET_SIZE_IN_BYTES, destination_url))
+                                    .send(open)
                                     .await
                                     .context("couldn’t send CHANNEL OPEN message through mpsc channel")?;
                             }
-                            None => warn!("Couldn’t allocate ID for API request: {}", destination_url),
+                            None => warn!("Couldn’t allocate ID for API request: {}", log_policy.url(&destination_url)),
                         }
                     }
-                    JmuxApiRequest::Start { id, stream, leftover } => {
-                        let channel = jmux_ctx.get_channel(id).with_context(|| format!("couldn’t find channel with id {id}"))?;
+                    JmuxApiRequest::QueryChannels { api_response_tx } => {
+                        let stats = jmux_ctx.channels.values().map(JmuxChannelCtx::stats).collect();
+                        let _ = api_response_tx.send(stats);
+                    }
+                    JmuxApiRequest::Shutdown => {
+                        if draining {
+                            continue;
+                        }
 
-                        let (data_tx, data_rx) = mpsc::channel::<Bytes>(CHANNEL_DATA_MPSC_CHANNEL_SIZE);
+                        info!(grace_period = ?cfg.shutdown_grace_period, "Shutting down JMUX session; draining channels");
 
-                        if data_senders.insert(id, data_tx).is_some() {
-                            anyhow::bail!("detected two streams with the same ID {}", id);
+                        draining = true;
+                        grace_period_sleep.as_mut().reset(tokio::time::Instant::now() + cfg.shutdown_grace_period);
+
+                        for (id, PendingChannel { api_response_tx, .. }) in pending_channels.drain() {
+                            let _ = api_response_tx.send(JmuxApiResponse::Failure { id, reason_code: ReasonCode::GENERAL_FAILURE });
                         }
 
-                        // Send leftover bytes if any.
-                        if let Some(leftover) = leftover {
-                            if let Err(error) = msg_to_send_tx.send(Message::data(channel.distant_id, leftover)).await {
-                                error!(%error, "Couldn't send leftover bytes");
+                        let ids: Vec<LocalChannelId> = jmux_ctx.channels.keys().copied().collect();
+
+                        for id in ids {
+                            drain_channel(&mut jmux_ctx, &msg_to_send_tx, id).await?;
+                        }
+                    }
+                    JmuxApiRequest::Start { id, .. } if draining => {
+                        // The channel was already EOFed, and dropping the stream closes it.
+                        debug!(channel.id = %id, "Ignoring start request while shutting down");
+                    }
+                    JmuxApiRequest::Start { id, stream, leftover } => {
+                        let channel = jmux_ctx.get_channel_mut(id).with_context(|| format!("couldn’t find channel with id {id}"))?;
+
+                        if let Some(tcp_keepalive) = &cfg.tcp_keepalive {
+                            if let Err(error) = tcp_keepalive.apply(&stream) {
+                                channel.span.in_scope(|| {
+                                    warn!(%error, "Couldn’t set TCP keepalive");
+                                });
                             }
                         }
 
-                        let (reader, writer) = stream.into_split();
+                        let mut sequencer = DataSequencer::new(capabilities.sequence_data());
+
+                        let (data_msg_tx, data_msg_rx) = mpsc::channel(CHANNEL_DATA_QUEUE_SIZE);
+                        let data_msg_tx = DataMessageSender::new(data_msg_tx, Arc::clone(&memory_budget));
+                        // The sender task is already stopped when this fails.
+                        let _ = data_queue_tx.send((channel.distant_id, data_msg_rx));
+
+                        // Send leftover bytes if any, ahead of the data read from the stream.
+                        // The channel is not started at all when they can't be delivered, as the stream would have a gap.
+                        if let Some(leftover) = leftover {
+                            let leftover_len = u64::try_from(leftover.len()).expect("usize-to-u64");
+                            data_msg_tx
+                                .send(sequencer.data(channel.distant_id, leftover))
+                                .await
+                                .context("couldn’t send leftover bytes")?;
+                            channel.bytes_tx.fetch_add(leftover_len, Ordering::Relaxed);
+                            metrics_recorder.bytes_forwarded(DataDirection::Tx, leftover_len);
+                        }
+
+                        let data_buffer_size = cfg.channel_data_buffer_size.for_open_channels(data_senders.len());
+                        let (data_tx, data_rx) = mpsc::channel::<TargetData>(data_buffer_size);
+
+                        let data_sender = ChannelDataSender::new(id, data_tx, internal_msg_tx.clone(), Arc::clone(&memory_budget));
+
+                        if data_senders.insert(id, data_sender).is_some() {
+                            anyhow::bail!("detected two streams with the same ID {}", id);
+                        }
+
+                        let (reader, writer) = TargetStream::Tcp(stream).into_split();
 
                         DataWriterTask {
                             writer,
                             data_rx,
+                            rate_limiter: cfg.max_channel_rx_rate.map(TokenBucket::new),
                         }
                         .spawn(channel.span.clone())
                         .detach();
 
-                        DataReaderTask {
+                        let reader_task = DataReaderTask {
                             reader,
+                            bytes_tx: Arc::clone(&channel.bytes_tx),
                             local_id: channel.local_id,
                             distant_id: channel.distant_id,
                             window_size_updated: Arc::clone(&channel.window_size_updated),
                             window_size: Arc::clone(&channel.window_size),
                             maximum_packet_size: channel.maximum_packet_size,
-                            msg_to_send_tx: msg_to_send_tx.clone(),
+                            flow_control: channel.flow_control,
+                            sequencer,
+                            rate_limiter: cfg.max_channel_tx_rate.map(TokenBucket::new),
+                            memory_budget: Arc::clone(&memory_budget),
+                            metrics_recorder: Arc::clone(&metrics_recorder),
+                            data_msg_tx,
                             internal_msg_tx: internal_msg_tx.clone(),
                         }
-                        .spawn(channel.span.clone())
-                        .detach();
+                        .spawn(channel.span.clone());
+
+                        channel.reader_task = Some(reader_task.abort_handle());
+                        reader_task.detach();
                     }
                 }
             }
             Some(internal_msg) = internal_msg_rx.recv() => {
                 match internal_msg {
                     InternalMessage::Eof { id } => {
-                        let channel = jmux_ctx.get_channel_mut(id).with_context(|| format!("couldn’t find channel with id {id}"))?;
-                        let channel_span = channel.span.clone();
-                        let local_id = channel.local_id;
-                        let distant_id = channel.distant_id;
+                        local_eof(&mut jmux_ctx, &msg_to_send_tx, id).await?;
+                    }
+                    InternalMessage::AcceptIdleTimeout { id } => {
+                        let Some(channel) = jmux_ctx.get_channel_mut(id) else {
+                            continue;
+                        };
 
-                        match channel.distant_state {
-                            JmuxChannelState::Streaming => {
-                                channel.local_state = JmuxChannelState::Eof;
-                                msg_to_send_tx
-                                    .send(Message::eof(distant_id))
-                                    .await
-                                    .context("couldn’t send EOF message")?;
-                            },
-                            JmuxChannelState::Eof => {
-                                channel.local_state = JmuxChannelState::Closed;
-                                msg_to_send_tx
-                                    .send(Message::close(distant_id))
-                                    .await
-                                    .context("couldn’t send CLOSE message")?;
-                            },
-                            JmuxChannelState::Closed => {
-                                jmux_ctx.unregister(local_id);
-                                msg_to_send_tx
-                                    .send(Message::close(distant_id))
-                                    .await
-                                    .context("couldn’t send CLOSE message")?;
-                                channel_span.in_scope(|| {
-                                    debug!("Channel closed");
-                                });
-                            },
+                        if channel.idle_timer.take().is_none() {
+                            // The peer showed some activity in the meantime.
+                            continue;
                         }
+
+                        channel.span.in_scope(|| {
+                            warn!("Peer neither sent data nor closed the channel after it was accepted; closing abnormally");
+                        });
+
+                        close_channel_abnormally(&mut jmux_ctx, &mut data_senders, &msg_to_send_tx, id).await?;
+                    }
+                    InternalMessage::RetryOpen { id } => {
+                        let Some(pending) = pending_channels.get(&id) else {
+                            // The session is shutting down.
+                            continue;
+                        };
+
+                        trace!("{} request {} (attempt #{})", id, log_policy.url(&pending.destination_url), pending.failed_attempts + 1);
+
+                        msg_to_send_tx
+                            .send(channel_open(id, pending.destination_url.clone(), channel_limits, &capabilities))
+                            .await
+                            .context("couldn’t send CHANNEL OPEN message through mpsc channel")?;
+                    }
+                    InternalMessage::OpenAdmitted { id } => {
+                        let resolver_task = pending_admissions.remove(&id).with_context(|| format!("couldn’t find channel with id {id} pending admission"))?;
+                        resolver_task.spawn().detach();
+                    }
+                    InternalMessage::OpenDenied { id, reason } => {
+                        let resolver_task = pending_admissions.remove(&id).with_context(|| format!("couldn’t find channel with id {id} pending admission"))?;
+                        let peer_id = resolver_task.channel.distant_id;
+
+                        resolver_task.channel.span.in_scope(|| {
+                            debug!(%reason, "Channel opening denied by the admission hook");
+                        });
+
+                        // The channel was never registered, only its ID must be released.
+                        jmux_ctx.unregister(id);
+
+                        msg_to_send_tx
+                            .send(Message::open_failure(peer_id, reason, "channel opening denied"))
+                            .await
+                            .context("couldn’t send OPEN FAILURE message through mpsc channel")?;
                     }
                     InternalMessage::StreamResolved {
-                        channel, stream
+                        mut channel, stream
                     } => {
+                        // The time spent resolving and connecting is not accounted as idle time.
+                        channel.last_activity = tokio::time::Instant::now();
+
                         let local_id = channel.local_id;
                         let distant_id = channel.distant_id;
                         let initial_window_size = channel.initial_window_size;
                         let maximum_packet_size = channel.maximum_packet_size;
+                        let flow_control = channel.flow_control;
                         let window_size_updated = Arc::clone(&channel.window_size_updated);
                         let window_size = Arc::clone(&channel.window_size);
+                        let bytes_tx = Arc::clone(&channel.bytes_tx);
                         let channel_span = channel.span.clone();
 
-                        let (data_tx, data_rx) = mpsc::channel::<Bytes>(CHANNEL_DATA_MPSC_CHANNEL_SIZE);
+                        let data_buffer_size = cfg.channel_data_buffer_size.for_open_channels(data_senders.len());
+                        let (data_tx, data_rx) = mpsc::channel::<TargetData>(data_buffer_size);
+
+                        let data_sender = ChannelDataSender::new(local_id, data_tx, internal_msg_tx.clone(), Arc::clone(&memory_budget));
 
-                        if data_senders.insert(channel.local_id, data_tx).is_some() {
+                        if data_senders.insert(local_id, data_sender).is_some() {
                             anyhow::bail!("detected two streams with the same local ID {}", channel.local_id);
                         };
 
-                        jmux_ctx.register_channel(channel)?;
+                        jmux_ctx.register_channel(*channel)?;
 
                         msg_to_send_tx
                             .send(Message::open_success(distant_id, local_id, initial_window_size, maximum_packet_size))
@@ -458,26 +1339,82 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                         DataWriterTask {
                             writer,
                             data_rx,
+                            rate_limiter: cfg.max_channel_rx_rate.map(TokenBucket::new),
                         }
                         .spawn(channel_span.clone())
                         .detach();
 
-                        DataReaderTask {
+                        let (data_msg_tx, data_msg_rx) = mpsc::channel(CHANNEL_DATA_QUEUE_SIZE);
+                        let data_msg_tx = DataMessageSender::new(data_msg_tx, Arc::clone(&memory_budget));
+                        // The sender task is already stopped when this fails.
+                        let _ = data_queue_tx.send((distant_id, data_msg_rx));
+
+                        let reader_task = DataReaderTask {
                             reader,
+                            bytes_tx,
                             local_id,
                             distant_id,
                             window_size_updated,
                             window_size,
                             maximum_packet_size,
-                            msg_to_send_tx: msg_to_send_tx.clone(),
+                            flow_control,
+                            sequencer: DataSequencer::new(capabilities.sequence_data()),
+                            rate_limiter: cfg.max_channel_tx_rate.map(TokenBucket::new),
+                            memory_budget: Arc::clone(&memory_budget),
+                            metrics_recorder: Arc::clone(&metrics_recorder),
+                            data_msg_tx,
                             internal_msg_tx: internal_msg_tx.clone(),
                         }
-                        .spawn(channel_span)
-                        .detach();
+                        .spawn(channel_span);
+
+                        if let Some(channel) = jmux_ctx.get_channel_mut(local_id) {
+                            channel.reader_task = Some(reader_task.abort_handle());
+
+                            if let Some(accept_idle_timeout) = cfg.accept_idle_timeout {
+                                let internal_msg_tx = internal_msg_tx.clone();
+
+                                let idle_timer = ChildTask(tokio::spawn(async move {
+                                    tokio::time::sleep(accept_idle_timeout).await;
+                                    let _ = internal_msg_tx.send(InternalMessage::AcceptIdleTimeout { id: local_id }).await;
+                                }));
+
+                                channel.idle_timer = Some(idle_timer.abort_handle());
+                                idle_timer.detach();
+                            }
+                        }
+                        reader_task.detach();
+
+                        // The target was connected in the meantime, but nothing is forwarded to the peer anymore.
+                        if draining {
+                            drain_channel(&mut jmux_ctx, &msg_to_send_tx, local_id).await?;
+                        }
+                    }
+                    InternalMessage::DataQueueReady { id, permit } => {
+                        let Some(data_sender) = data_senders.get_mut(&id) else {
+                            // The channel was closed in the meantime.
+                            continue;
+                        };
+
+                        if data_sender.resume(permit) {
+                            if let Some(channel) = jmux_ctx.get_channel_mut(id) {
+                                channel.span.in_scope(|| {
+                                    trace!("Writer task caught up");
+                                });
+
+                                if channel.flow_control {
+                                    needs_window_adjustment.insert(id);
+                                }
+                            }
+                        }
                     }
                 }
             }
-            msg = jmux_stream.next() => {
+            Some(distant_id) = close_handed_over_rx.recv() => {
+                jmux_ctx.close_handed_over(distant_id);
+            }
+            // Reading from the pipe is paused while too much data is waiting for the targets.
+            () = memory_budget.wait_available(DataDirection::Rx), if memory_budget.is_exceeded(DataDirection::Rx) => {}
+            msg = jmux_stream.next(), if !memory_budget.is_exceeded(DataDirection::Rx) => {
                 let msg = match msg {
                     Some(msg) => msg,
                     None => {
@@ -514,14 +1451,31 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                     }
                 };
 
-                trace!(?msg, "Received channel message");
+                trace!(msg = ?log_policy.message(&msg), "Received channel message");
 
                 match msg {
+                    Message::Capabilities(msg) => {
+                        capabilities.peer_advertised(&msg);
+                        debug!(
+                            peer = format!("{:#x}", msg.features),
+                            negotiated = format!("{:#x}", capabilities.negotiated.features),
+                            "Capabilities negotiated"
+                        );
+                    }
                     Message::Open(msg) => {
                         let peer_id = DistantChannelId::from(msg.sender_channel_id);
 
-                        if let Err(error) = cfg.filtering.validate_destination(&msg.destination_url) {
-                            debug!(error = format!("{error:#}"), %msg.destination_url, %peer_id, "Invalid destination requested");
+                        if draining {
+                            debug!(destination_url = %log_policy.url(&msg.destination_url), %peer_id, "Channel opening requested while shutting down");
+                            msg_to_send_tx
+                                .send(Message::open_failure(peer_id, ReasonCode::GENERAL_FAILURE, "session is shutting down"))
+                                .await
+                                .context("couldn’t send OPEN FAILURE message through mpsc channel")?;
+                            continue;
+                        }
+
+                        if let Err(error) = filtering.validate_destination(&msg.destination_url) {
+                            debug!(error = format!("{error:#}"), destination_url = %log_policy.url(&msg.destination_url), %peer_id, "Invalid destination requested");
                             msg_to_send_tx
                                 .send(Message::open_failure(peer_id, ReasonCode::CONNECTION_NOT_ALLOWED_BY_RULESET, error.to_string()))
                                 .await
@@ -529,6 +1483,24 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                             continue;
                         }
 
+                        let Some(channel_admission) = channel_limiter.try_admit() else {
+                            debug!(destination_url = %log_policy.url(&msg.destination_url), %peer_id, "Too many channels");
+                            msg_to_send_tx
+                                .send(Message::open_failure(peer_id, ReasonCode::GENERAL_FAILURE, "too many channels"))
+                                .await
+                                .context("couldn’t send OPEN FAILURE message through mpsc channel")?;
+                            continue;
+                        };
+
+                        let Some(resolver_admission) = resolver_limiter.try_admit() else {
+                            debug!(destination_url = %log_policy.url(&msg.destination_url), %peer_id, "Too many channel openings in progress");
+                            msg_to_send_tx
+                                .send(Message::open_failure(peer_id, ReasonCode::GENERAL_FAILURE, "too many channel openings in progress"))
+                                .await
+                                .context("couldn’t send OPEN FAILURE message through mpsc channel")?;
+                            continue;
+                        };
+
                         let local_id = match jmux_ctx.allocate_id() {
                             Some(id) => id,
                             None => {
@@ -542,12 +1514,15 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                         };
 
                         trace!("Allocated ID {} for peer {}", local_id, peer_id);
-                        info!("({} {}) request {}", local_id, peer_id, msg.destination_url);
+                        info!("({} {}) request {}", local_id, peer_id, log_policy.url(&msg.destination_url));
+
+                        let channel_span = info_span!(parent: parent_span.clone(), "channel", %local_id, %peer_id, url = %log_policy.url(&msg.destination_url));
 
-                        let channel_span = info_span!(parent: parent_span.clone(), "channel", %local_id, %peer_id, url = %msg.destination_url);
+                        let flow_control = !(capabilities.unlimited_window() && msg.initial_window_size == UNLIMITED_WINDOW_SIZE);
+                        let initial_window_size = accepted_window_size(msg.initial_window_size, flow_control, &cfg);
 
                         let window_size_updated = Arc::new(Notify::new());
-                        let window_size = Arc::new(AtomicUsize::new(usize::try_from(msg.initial_window_size).expect("usize-to-u32")));
+                        let window_size = Arc::new(AtomicUsize::new(usize::try_from(initial_window_size).expect("usize-to-u32")));
 
                         let channel = JmuxChannelCtx {
                             distant_id: peer_id,
@@ -556,38 +1531,100 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                             local_id,
                             local_state: JmuxChannelState::Streaming,
 
-                            initial_window_size: msg.initial_window_size,
+                            initial_window_size,
                             window_size_updated: Arc::clone(&window_size_updated),
                             window_size: Arc::clone(&window_size),
-                            remote_window_size: msg.initial_window_size,
+                            remote_window_size: initial_window_size,
+
+                            maximum_packet_size: channel_limits.maximum_packet_size(msg.maximum_packet_size),
+                            dropped_oversized_packets: 0,
+                            next_distant_sequence_number: 0,
 
-                            maximum_packet_size: msg.maximum_packet_size,
+                            flow_control,
+
+                            destination_url: msg.destination_url.clone(),
+                            bytes_tx: Arc::new(AtomicU64::new(0)),
+                            bytes_rx: 0,
+                            last_activity: tokio::time::Instant::now(),
+                            last_activity_bytes: 0,
+
+                            reader_task: None,
+                            idle_timer: None,
+                            channel_permit: None,
+                            recorded: None,
 
                             span: channel_span,
                         };
 
-                        StreamResolverTask {
+                        let requested_url = msg.destination_url.clone();
+                        let destination_url = cfg.normalize_scheme(cfg.rewrite_destination(msg.destination_url));
+
+                        if destination_url.host() != requested_url.host() || destination_url.port() != requested_url.port() {
+                            channel.span.in_scope(|| {
+                                debug!(rewritten_url = %log_policy.url(&destination_url), "Destination rewritten");
+                            });
+                        }
+
+                        let resolver_task = StreamResolverTask {
                             channel,
-                            destination_url: msg.destination_url,
+                            destination_url,
+                            resolver: Arc::clone(&resolver),
+                            channel_admission,
+                            resolver_admission,
+                            connect_limiter: connect_limiter.clone(),
+                            happy_eyeballs_delay: cfg.happy_eyeballs_delay,
+                            max_addresses_per_resolution: cfg.max_addresses_per_resolution,
+                            tcp_keepalive: cfg.tcp_keepalive,
+                            log_policy,
                             internal_msg_tx: internal_msg_tx.clone(),
                             msg_to_send_tx: msg_to_send_tx.clone(),
-                        }
-                        .spawn()
-                        .detach();
+                        };
+
+                        let Some(open_admission) = cfg.open_admission.clone() else {
+                            resolver_task.spawn().detach();
+                            continue;
+                        };
+
+                        // The hook may take a while, so it's awaited in its own task to not block the scheduler.
+                        let internal_msg_tx = internal_msg_tx.clone();
+                        let span = resolver_task.channel.span.clone();
+
+                        pending_admissions.insert(local_id, resolver_task);
+
+                        let admission_task = ChildTask(tokio::spawn(
+                            async move {
+                                let internal_msg = match open_admission.check(&requested_url).await {
+                                    Ok(()) => InternalMessage::OpenAdmitted { id: local_id },
+                                    Err(reason) => InternalMessage::OpenDenied { id: local_id, reason },
+                                };
+
+                                let _ = internal_msg_tx.send(internal_msg).await;
+                            }
+                            .instrument(span),
+                        ));
+
+                        admission_task.detach();
                     }
                     Message::OpenSuccess(msg) => {
                         let local_id = LocalChannelId::from(msg.recipient_channel_id);
                         let peer_id = DistantChannelId::from(msg.sender_channel_id);
 
-                        let Some((destination_url, api_response_tx)) = pending_channels.remove(&local_id) else {
-                            warn!(channel.id = %local_id, "Couldn’t find pending channel");
+                        let Some(PendingChannel { destination_url, api_response_tx, .. }) = pending_channels.remove(&local_id) else {
+                            if jmux_ctx.get_channel_mut(local_id).is_some() {
+                                debug!(channel.id = %local_id, "Ignoring duplicated OPEN SUCCESS for an already opened channel");
+                            } else {
+                                warn!(channel.id = %local_id, "Couldn’t find pending channel");
+                            }
                             continue;
                         };
 
-                        let channel_span = info_span!(parent: parent_span.clone(), "channel", %local_id, %peer_id, url = %destination_url).entered();
+                        let channel_span = info_span!(parent: parent_span.clone(), "channel", %local_id, %peer_id, url = %log_policy.url(&destination_url)).entered();
 
                         trace!("Successfully opened channel");
 
+                        let flow_control = !(capabilities.unlimited_window() && msg.initial_window_size == UNLIMITED_WINDOW_SIZE);
+                        let initial_window_size = accepted_window_size(msg.initial_window_size, flow_control, &cfg);
+
                         if api_response_tx.send(JmuxApiResponse::Success { id: local_id }).is_err() {
                             warn!("Couldn’t send success API response through mpsc channel");
                             continue;
@@ -600,12 +1637,27 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                             local_id,
                             local_state: JmuxChannelState::Streaming,
 
-                            initial_window_size: msg.initial_window_size,
+                            initial_window_size,
                             window_size_updated: Arc::new(Notify::new()),
-                            window_size: Arc::new(AtomicUsize::new(usize::try_from(msg.initial_window_size).expect("u32-to-usize"))),
-                            remote_window_size: msg.initial_window_size,
+                            window_size: Arc::new(AtomicUsize::new(usize::try_from(initial_window_size).expect("u32-to-usize"))),
+                            remote_window_size: initial_window_size,
 
-                            maximum_packet_size: msg.maximum_packet_size,
+                            maximum_packet_size: channel_limits.maximum_packet_size(msg.maximum_packet_size),
+                            dropped_oversized_packets: 0,
+                            next_distant_sequence_number: 0,
+
+                            flow_control,
+
+                            destination_url,
+                            bytes_tx: Arc::new(AtomicU64::new(0)),
+                            bytes_rx: 0,
+                            last_activity: tokio::time::Instant::now(),
+                            last_activity_bytes: 0,
+
+                            reader_task: None,
+                            idle_timer: None,
+                            channel_permit: None,
+                            recorded: None,
 
                             span: channel_span.exit(),
                         })?;
@@ -623,31 +1675,94 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                     Message::Data(msg) => {
                         let id = LocalChannelId::from(msg.recipient_channel_id);
                         let Some(channel) = jmux_ctx.get_channel_mut(id) else {
-                            warn!(channel.id = %id, "Couldn’t find channel");
+                            if jmux_ctx.is_recently_closed(id) {
+                                debug!(channel.id = %id, payload_size = msg.transfer_data.len(), "Received data for a recently closed channel; dropped");
+                            } else {
+                                warn!(channel.id = %id, "Couldn’t find channel");
+                            }
                             continue;
                         };
 
+                        // The peer promised to not send more data in this direction.
+                        if matches!(channel.distant_state, JmuxChannelState::Eof | JmuxChannelState::Closed) {
+                            channel.span.in_scope(|| {
+                                warn!(payload_size = msg.transfer_data.len(), state = ?channel.distant_state, "Received data after distant peer EOFed; dropped");
+                            });
+                            continue;
+                        }
+
+                        channel.cancel_idle_timer();
+
+                        if let Some(sequence_number) = msg.sequence_number {
+                            let expected = channel.next_distant_sequence_number;
+
+                            if sequence_number != expected {
+                                // Data is either missing or duplicated: the stream can't be forwarded faithfully anymore.
+                                channel.span.in_scope(|| {
+                                    warn!(expected, received = sequence_number, "Out-of-sequence data received; closing abnormally");
+                                });
+
+                                close_channel_abnormally(&mut jmux_ctx, &mut data_senders, &msg_to_send_tx, id).await?;
+
+                                continue;
+                            }
+
+                            channel.next_distant_sequence_number = expected.wrapping_add(1);
+                        }
+
                         let payload_size = u32::try_from(msg.transfer_data.len()).expect("packet length is found by decoding a u16 in decoder");
                         channel.remote_window_size = channel.remote_window_size.saturating_sub(payload_size);
 
                         let packet_size = Header::SIZE + msg.size();
                         if usize::from(channel.maximum_packet_size) < packet_size {
+                            channel.dropped_oversized_packets += 1;
+                            metrics.oversized_data_dropped.fetch_add(1, Ordering::Relaxed);
+
                             channel.span.in_scope(|| {
-                                warn!(packet_size, "Packet's size is exceeding the maximum size for this channel and was dropped");
+                                warn!(packet_size, maximum_packet_size = channel.maximum_packet_size, dropped_count = channel.dropped_oversized_packets, "Packet's size is exceeding the maximum size for this channel and was dropped");
                             });
+
+                            // The peer consumed its window for this packet: it is credited back as if the data was forwarded,
+                            // otherwise repeated oversized packets would starve the channel.
+                            if channel.flow_control {
+                                needs_window_adjustment.insert(id);
+                            }
+
                             continue;
                         }
 
-                        let Some(data_tx) = data_senders.get_mut(&id) else {
-                            channel.span.in_scope(|| {
-                                warn!("Received data but associated data sender is missing");
-                            });
+                        let Some(data_sender) = data_senders.get_mut(&id) else {
+                            let channel_span = channel.span.clone();
+
+                            // Expected when the channel was closed locally while the data was in flight.
+                            if jmux_ctx.is_recently_closed(id) {
+                                channel_span.in_scope(|| {
+                                    debug!(payload_size, "Received data for a recently closed channel; dropped");
+                                });
+                            } else {
+                                channel_span.in_scope(|| {
+                                    warn!("Received data but associated data sender is missing");
+                                });
+                            }
                             continue;
                         };
 
-                        let _ = data_tx.send(msg.transfer_data).await;
+                        channel.bytes_rx += u64::from(payload_size);
+                        metrics_recorder.bytes_forwarded(DataDirection::Rx, u64::from(payload_size));
+
+                        // A slow target must not stall the other channels: instead of waiting for its writer task,
+                        // no more window is granted to the peer for this channel until the writer task catches up.
+                        let caught_up = data_sender.push(msg.transfer_data);
+
+                        if !caught_up {
+                            channel.span.in_scope(|| {
+                                trace!("Writer task is lagging behind; window adjustment delayed");
+                            });
+                        }
 
-                        needs_window_adjustment.insert(id);
+                        if channel.flow_control && caught_up {
+                            needs_window_adjustment.insert(id);
+                        }
                     }
                     Message::Eof(msg) => {
                         // Per the spec:
@@ -663,6 +1778,7 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                         };
 
                         channel.distant_state = JmuxChannelState::Eof;
+                        channel.cancel_idle_timer();
                         channel.span.in_scope(|| {
                             debug!("Distant peer EOFed");
                         });
@@ -673,11 +1789,13 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                         match channel.local_state {
                             JmuxChannelState::Streaming => {},
                             JmuxChannelState::Eof => {
+                                let distant_id = channel.distant_id;
                                 channel.local_state = JmuxChannelState::Closed;
                                 msg_to_send_tx
-                                    .send(Message::close(channel.distant_id))
+                                    .send(Message::close(distant_id))
                                     .await
                                     .context("couldn’t send CLOSE message")?;
+                                jmux_ctx.close_queued(id, distant_id);
                             },
                             JmuxChannelState::Closed => {},
                         }
@@ -685,14 +1803,39 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                     Message::OpenFailure(msg) => {
                         let id = LocalChannelId::from(msg.recipient_channel_id);
 
-                        let Some((destination_url, api_response_tx)) = pending_channels.remove(&id) else {
-                            warn!(channel.id = %id, "Couldn’t find pending channel");
+                        let Some(mut pending) = pending_channels.remove(&id) else {
+                            // The channel was either already resolved by a previous OPEN SUCCESS or OPEN FAILURE, or never requested.
+                            debug!(channel.id = %id, "Ignoring OPEN FAILURE for a channel which is not pending");
                             continue;
                         };
 
-                        warn!(local_id = %id, %destination_url, %msg.reason_code, "Channel opening failed: {}", msg.description);
+                        pending.failed_attempts += 1;
+
+                        if let Some(retry_policy) = cfg.open_retry_policy {
+                            if msg.reason_code.is_retryable() && pending.failed_attempts < retry_policy.max_attempts {
+                                let delay = retry_policy.delay(pending.failed_attempts);
+
+                                debug!(local_id = %id, destination_url = %log_policy.url(&pending.destination_url), %msg.reason_code, ?delay, "Channel opening failed, retrying: {}", log_policy.text(&msg.description));
+
+                                pending_channels.insert(id, pending);
+
+                                let internal_msg_tx = internal_msg_tx.clone();
+                                ChildTask(tokio::spawn(async move {
+                                    tokio::time::sleep(delay).await;
+                                    let _ = internal_msg_tx.send(InternalMessage::RetryOpen { id }).await;
+                                }))
+                                .detach();
+
+                                continue;
+                            }
+                        }
+
+                        warn!(local_id = %id, destination_url = %log_policy.url(&pending.destination_url), %msg.reason_code, "Channel opening failed: {}", log_policy.text(&msg.description));
 
-                        let _ = api_response_tx.send(JmuxApiResponse::Failure { id, reason_code: msg.reason_code });
+                        // The channel was never registered, but the ID can be reused.
+                        jmux_ctx.unregister(id);
+
+                        let _ = pending.api_response_tx.send(JmuxApiResponse::Failure { id, reason_code: msg.reason_code });
                     }
                     Message::Close(msg) => {
                         let local_id = LocalChannelId::from(msg.recipient_channel_id);
@@ -705,26 +1848,75 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                         let _enter = channel_span.enter();
 
                         channel.distant_state = JmuxChannelState::Closed;
+                        channel.cancel_idle_timer();
                         debug!("Distant peer closed");
 
                         // This will also shutdown the associated TCP stream.
                         data_senders.remove(&local_id);
 
-                        if channel.local_state == JmuxChannelState::Eof {
-                            channel.local_state = JmuxChannelState::Closed;
-                            msg_to_send_tx
-                                .send(Message::close(distant_id))
-                                .await
-                                .context("couldn’t send CLOSE message")?;
+                        match channel.local_state {
+                            JmuxChannelState::Streaming => {}
+                            JmuxChannelState::Eof => {
+                                channel.local_state = JmuxChannelState::Closed;
+                                msg_to_send_tx
+                                    .send(Message::close(distant_id))
+                                    .await
+                                    .context("couldn’t send CLOSE message")?;
+                                jmux_ctx.close_queued(local_id, distant_id);
+                                jmux_ctx.unregister(local_id);
+                                trace!("Channel closed");
+                            }
+                            JmuxChannelState::Closed => {
+                                jmux_ctx.unregister(local_id);
+                                trace!("Channel closed");
+                            }
                         }
+                    }
+                }
+            }
+            () = &mut ttl_sleep, if ttl.is_some() => {
+                info!("JMUX session TTL elapsed; closing all channels");
 
-                        if channel.local_state == JmuxChannelState::Closed {
-                            jmux_ctx.unregister(local_id);
-                            trace!("Channel closed");
-                        }
+                close_all_channels(&jmux_ctx, &mut data_senders, &msg_to_send_tx, "the session TTL elapsed").await?;
+
+                for (id, PendingChannel { api_response_tx, .. }) in pending_channels.drain() {
+                    let _ = api_response_tx.send(JmuxApiResponse::Failure { id, reason_code: ReasonCode::SESSION_EXPIRED });
+                }
+
+                sender_shutdown.notify_one();
+
+                break;
+            }
+            now = idle_sweep.tick(), if cfg.idle_timeout.is_some() => {
+                let idle_timeout = cfg.idle_timeout.expect("checked by the branch precondition");
+
+                let idle_channels: Vec<LocalChannelId> = jmux_ctx
+                    .channels
+                    .values_mut()
+                    .filter(|channel| channel.local_state != JmuxChannelState::Closed)
+                    .filter_map(|channel| (channel.idle_for(now) >= idle_timeout).then_some(channel.local_id))
+                    .collect();
+
+                for id in idle_channels {
+                    if let Some(channel) = jmux_ctx.get_channel_mut(id) {
+                        channel.span.in_scope(|| {
+                            warn!(?idle_timeout, "No data exchanged on the channel for too long; closing abnormally");
+                        });
                     }
+
+                    metrics.idle_channels_closed.fetch_add(1, Ordering::Relaxed);
+                    close_channel_abnormally(&mut jmux_ctx, &mut data_senders, &msg_to_send_tx, id).await?;
                 }
             }
+            () = &mut grace_period_sleep, if draining => {
+                warn!(remaining = jmux_ctx.channels.len(), "Shutdown grace period elapsed; closing the remaining channels");
+
+                close_all_channels(&jmux_ctx, &mut data_senders, &msg_to_send_tx, "the shutdown grace period elapsed").await?;
+
+                sender_shutdown.notify_one();
+
+                break;
+            }
             _ = core::future::ready(()), if !needs_window_adjustment.is_empty() => {
                 for channel_id in needs_window_adjustment.drain() {
                     let Some(channel) = jmux_ctx.get_channel_mut(channel_id) else {
@@ -748,19 +1940,81 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
 
     info!("Closing JMUX scheduler task...");
 
-    Ok(())
+    Ok(jmux_stream)
 }
 
 // ---------------------- //
 
+/// Numbers the DATA messages sent for a channel, when sequencing is enabled (see [`JmuxConfig::sequence_data`]).
+struct DataSequencer {
+    next_sequence_number: Option<u32>,
+}
+
+impl DataSequencer {
+    fn new(enabled: bool) -> Self {
+        Self {
+            next_sequence_number: enabled.then_some(0),
+        }
+    }
+
+    /// Number of bytes added to each DATA packet.
+    fn overhead(&self) -> u16 {
+        if self.next_sequence_number.is_some() {
+            u16::try_from(ChannelData::SEQUENCE_NUMBER_SIZE).expect("small constant")
+        } else {
+            0
+        }
+    }
+
+    fn data(&mut self, distant_id: DistantChannelId, transfer_data: Bytes) -> Message {
+        let data = ChannelData::new(distant_id, transfer_data);
+
+        match &mut self.next_sequence_number {
+            Some(next_sequence_number) => {
+                let sequence_number = *next_sequence_number;
+                *next_sequence_number = sequence_number.wrapping_add(1);
+                Message::Data(data.with_sequence_number(sequence_number))
+            }
+            None => Message::Data(data),
+        }
+    }
+}
+
+/// Sending side of the DATA queue of a channel.
+///
+/// The data is accounted by the memory budget once queued, until written to the JMUX pipe by the sender task. The data
+/// waiting for the peer to adjust the window is not queued yet.
+struct DataMessageSender {
+    tx: mpsc::Sender<QueuedData>,
+    memory_budget: Arc<MemoryBudget>,
+}
+
+impl DataMessageSender {
+    fn new(tx: mpsc::Sender<QueuedData>, memory_budget: Arc<MemoryBudget>) -> Self {
+        Self { tx, memory_budget }
+    }
+
+    async fn send(&self, msg: Message) -> Result<(), mpsc::error::SendError<QueuedData>> {
+        let reservation = self.memory_budget.reserve(DataDirection::Tx, transfer_data_len(&msg));
+        self.tx.send((msg, reservation)).await
+    }
+}
+
 struct DataReaderTask {
-    reader: OwnedReadHalf,
+    reader: TargetReader,
+    bytes_tx: Arc<AtomicU64>,
     local_id: LocalChannelId,
     distant_id: DistantChannelId,
     window_size_updated: Arc<Notify>,
     window_size: Arc<AtomicUsize>,
     maximum_packet_size: u16,
-    msg_to_send_tx: MessageSender,
+    flow_control: bool,
+    sequencer: DataSequencer,
+    rate_limiter: Option<TokenBucket>,
+    memory_budget: Arc<MemoryBudget>,
+    metrics_recorder: Arc<dyn MetricsRecorder>,
+    /// DATA queue of the channel, served by the sender task in turn with the other channels
+    data_msg_tx: DataMessageSender,
     internal_msg_tx: InternalMessageSender,
 }
 
@@ -782,23 +2036,36 @@ impl DataReaderTask {
 
         let Self {
             reader,
+            bytes_tx,
             local_id,
             distant_id,
             window_size_updated,
             window_size,
             maximum_packet_size,
-            msg_to_send_tx,
+            flow_control,
+            mut sequencer,
+            mut rate_limiter,
+            memory_budget,
+            metrics_recorder,
+            data_msg_tx,
             internal_msg_tx,
         } = self;
 
+        // Room is kept for the sequence number, if any.
+        let maximum_packet_size = maximum_packet_size.saturating_sub(sequencer.overhead());
+
         let codec = tokio_util::codec::BytesCodec::new();
         let mut bytes_stream = FramedRead::new(reader, codec);
-        let maximum_packet_size = usize::from(maximum_packet_size);
-
         trace!("Started forwarding");
 
-        while let Some(bytes) = bytes_stream.next().await {
-            let mut bytes = match bytes {
+        loop {
+            memory_budget.wait_available(DataDirection::Tx).await;
+
+            let Some(bytes) = bytes_stream.next().await else {
+                break;
+            };
+
+            let bytes = match bytes {
                 Ok(bytes) => bytes,
                 Err(error) if is_really_an_error(&error) => {
                     return Err(anyhow::Error::new(error).context("couldn’t read next bytes from stream"))
@@ -809,11 +2076,24 @@ impl DataReaderTask {
                 }
             };
 
-            let chunk_size = maximum_packet_size - Header::SIZE - ChannelData::FIXED_PART_SIZE;
+            let bytes_len = u64::try_from(bytes.len()).expect("usize-to-u64");
+            bytes_tx.fetch_add(bytes_len, Ordering::Relaxed);
+            metrics_recorder.bytes_forwarded(DataDirection::Tx, bytes_len);
+
+            if let Some(rate_limiter) = &mut rate_limiter {
+                rate_limiter.consume(bytes.len()).await;
+            }
+
+            for data in ChannelData::chunk(distant_id, bytes.freeze(), maximum_packet_size) {
+                if !flow_control {
+                    data_msg_tx
+                        .send(sequencer.data(distant_id, data.transfer_data))
+                        .await
+                        .context("couldn’t send DATA message")?;
+                    continue;
+                }
 
-            while !bytes.is_empty() {
-                let split_at = core::cmp::min(chunk_size, bytes.len());
-                let mut chunk = bytes.split_to(split_at);
+                let mut chunk = data.transfer_data;
 
                 loop {
                     let window_size_now = window_size.load(Ordering::SeqCst);
@@ -828,17 +2108,18 @@ impl DataReaderTask {
                         if window_size_now > 0 {
                             let to_send_now = chunk.split_to(window_size_now);
                             window_size.fetch_sub(to_send_now.len(), Ordering::SeqCst);
-                            msg_to_send_tx
-                                .send(Message::data(distant_id, to_send_now.freeze()))
+                            data_msg_tx
+                                .send(sequencer.data(distant_id, to_send_now))
                                 .await
                                 .context("couldn’t send DATA message")?;
                         }
 
+                        metrics_recorder.window_exhausted(local_id);
                         window_size_updated.notified().await;
                     } else {
                         window_size.fetch_sub(chunk.len(), Ordering::SeqCst);
-                        msg_to_send_tx
-                            .send(Message::data(distant_id, chunk.freeze()))
+                        data_msg_tx
+                            .send(sequencer.data(distant_id, chunk))
                             .await
                             .context("couldn’t send DATA message")?;
                         break;
@@ -860,9 +2141,132 @@ impl DataReaderTask {
 
 // ---------------------- //
 
+/// Sending side of the data queue of a channel, never blocking the scheduler.
+///
+/// When the writer task is lagging behind (the target is not reading fast enough), the data is kept aside in order,
+/// and a task waits for room in the queue on behalf of the scheduler.
+struct ChannelDataSender {
+    id: LocalChannelId,
+    data_tx: DataSender,
+    overflow: VecDeque<TargetData>,
+    waiting_for_room: bool,
+    internal_msg_tx: InternalMessageSender,
+    memory_budget: Arc<MemoryBudget>,
+}
+
+impl ChannelDataSender {
+    fn new(
+        id: LocalChannelId,
+        data_tx: DataSender,
+        internal_msg_tx: InternalMessageSender,
+        memory_budget: Arc<MemoryBudget>,
+    ) -> Self {
+        Self {
+            id,
+            data_tx,
+            overflow: VecDeque::new(),
+            waiting_for_room: false,
+            internal_msg_tx,
+            memory_budget,
+        }
+    }
+
+    /// Queues the data, and returns `false` when it was kept aside because the writer task is lagging behind.
+    fn push(&mut self, data: Bytes) -> bool {
+        // Released by the writer task once written to the target.
+        let reservation = self.memory_budget.reserve(DataDirection::Rx, data.len());
+        let data = (data, reservation);
+
+        if !self.overflow.is_empty() {
+            self.overflow.push_back(data);
+            return false;
+        }
+
+        match self.data_tx.try_send(data) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(data)) => {
+                self.overflow.push_back(data);
+                self.wait_for_room();
+                false
+            }
+            // The writer task is gone, the data is dropped.
+            Err(mpsc::error::TrySendError::Closed(_)) => true,
+        }
+    }
+
+    /// Moves the data kept aside to the queue, and returns `true` once the writer task caught up.
+    fn resume(&mut self, permit: mpsc::OwnedPermit<TargetData>) -> bool {
+        self.waiting_for_room = false;
+
+        let Some(data) = self.overflow.pop_front() else {
+            return true;
+        };
+
+        permit.send(data);
+
+        while let Some(data) = self.overflow.pop_front() {
+            match self.data_tx.try_send(data) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(data)) => {
+                    self.overflow.push_front(data);
+                    self.wait_for_room();
+                    return false;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            }
+        }
+
+        true
+    }
+
+    fn wait_for_room(&mut self) {
+        if self.waiting_for_room {
+            return;
+        }
+
+        self.waiting_for_room = true;
+
+        let id = self.id;
+        let data_tx = self.data_tx.clone();
+        let internal_msg_tx = self.internal_msg_tx.clone();
+
+        ChildTask(tokio::spawn(async move {
+            // Fails when the writer task is gone, and the data kept aside is dropped with the sender.
+            if let Ok(permit) = data_tx.reserve_owned().await {
+                let _ = internal_msg_tx
+                    .send(InternalMessage::DataQueueReady { id, permit })
+                    .await;
+            }
+        }))
+        .detach();
+    }
+}
+
+impl Drop for ChannelDataSender {
+    fn drop(&mut self) {
+        if self.overflow.is_empty() {
+            return;
+        }
+
+        // Like the data already queued, the data kept aside is written before the stream is shut down.
+        let overflow = std::mem::take(&mut self.overflow);
+        let data_tx = self.data_tx.clone();
+
+        ChildTask(tokio::spawn(async move {
+            for data in overflow {
+                if data_tx.send(data).await.is_err() {
+                    break;
+                }
+            }
+        }))
+        .detach();
+    }
+}
+
 struct DataWriterTask {
-    writer: OwnedWriteHalf,
+    writer: TargetWriter,
     data_rx: DataReceiver,
+    rate_limiter: Option<TokenBucket>,
 }
 
 impl DataWriterTask {
@@ -870,16 +2274,38 @@ impl DataWriterTask {
         let Self {
             mut writer,
             mut data_rx,
+            mut rate_limiter,
         } = self;
 
         let handle = tokio::spawn(
             async move {
-                while let Some(data) = data_rx.recv().await {
-                    if let Err(error) = writer.write_all(&data).await {
+                // The data sender is dropped by the scheduler when the channel is gracefully closed (EOF or CLOSE).
+                // Even then, `recv` keeps returning the data still buffered in the mpsc channel, so everything is
+                // delivered to the target before the write half is shut down.
+                // The reservation of the data is released once written, or when dropped along with the queue.
+                while let Some((data, _reservation)) = data_rx.recv().await {
+                    if let Some(rate_limiter) = &mut rate_limiter {
+                        rate_limiter.consume(data.len()).await;
+                    }
+
+                    // Flushing is required for the datagrams to be sent as soon as complete (no-op for TCP).
+                    let result = match writer.write_all(&data).await {
+                        Ok(()) => writer.flush().await,
+                        Err(error) => Err(error),
+                    };
+
+                    if let Err(error) = result {
                         warn!(%error, "Writer task failed");
-                        break;
+                        return;
                     }
                 }
+
+                // Graceful close: send a FIN to the target once all the data is written.
+                if let Err(error) = writer.shutdown().await {
+                    debug!(%error, "Couldn’t shutdown the write half of the stream");
+                }
+
+                trace!("Writer task finished");
             }
             .instrument(span),
         );
@@ -893,6 +2319,14 @@ impl DataWriterTask {
 struct StreamResolverTask {
     channel: JmuxChannelCtx,
     destination_url: DestinationUrl,
+    resolver: Arc<dyn Resolver>,
+    channel_admission: ChannelAdmission,
+    resolver_admission: ResolverAdmission,
+    connect_limiter: ConnectLimiter,
+    happy_eyeballs_delay: Duration,
+    max_addresses_per_resolution: usize,
+    tcp_keepalive: Option<TcpKeepalive>,
+    log_policy: LogPolicy,
     internal_msg_tx: InternalMessageSender,
     msg_to_send_tx: MessageSender,
 }
@@ -915,44 +2349,313 @@ impl StreamResolverTask {
 
     async fn run(self) -> anyhow::Result<()> {
         let Self {
-            channel,
+            mut channel,
             destination_url,
+            resolver,
+            channel_admission,
+            resolver_admission,
+            connect_limiter,
+            happy_eyeballs_delay,
+            max_addresses_per_resolution,
+            tcp_keepalive,
+            log_policy,
             internal_msg_tx,
             msg_to_send_tx,
         } = self;
 
+        let Some(channel_permit) = channel_admission.ready().await else {
+            msg_to_send_tx
+                .send(Message::open_failure(
+                    channel.distant_id,
+                    ReasonCode::GENERAL_FAILURE,
+                    "too many channels: timed out waiting for another channel to be closed",
+                ))
+                .await
+                .context("couldn’t send OPEN FAILURE message through mpsc channel")?;
+            anyhow::bail!("timed out waiting for a channel slot");
+        };
+
+        channel.channel_permit = Some(channel_permit);
+
+        let _resolver_permit = resolver_admission.ready().await;
+
         let scheme = destination_url.scheme();
         let host = destination_url.host();
         let port = destination_url.port();
 
-        match scheme {
-            "tcp" => match TcpStream::connect((host, port)).await {
-                Ok(stream) => {
-                    internal_msg_tx
-                        .send(InternalMessage::StreamResolved { channel, stream })
-                        .await
-                        .context("could't send back resolved stream through internal mpsc channel")?;
+        let Some(connect_permit) = connect_limiter.acquire(host, port).await else {
+            msg_to_send_tx
+                .send(Message::open_failure(
+                    channel.distant_id,
+                    ReasonCode::GENERAL_FAILURE,
+                    "too many concurrent connection attempts to the destination",
+                ))
+                .await
+                .context("couldn’t send OPEN FAILURE message through mpsc channel")?;
+            anyhow::bail!(
+                "timed out waiting for a connect slot to {}:{}",
+                log_policy.host(host),
+                port
+            );
+        };
+
+        let result = match scheme {
+            "tcp" => connect_tcp(
+                resolver.as_ref(),
+                host,
+                port,
+                happy_eyeballs_delay,
+                max_addresses_per_resolution,
+            )
+            .await
+            .map(TargetStream::Tcp),
+            "udp" => connect_udp(resolver.as_ref(), host, port).await.map(TargetStream::Udp),
+            _ => anyhow::bail!("unsupported scheme: {}", scheme),
+        };
+
+        drop(connect_permit);
+
+        match result {
+            Ok(stream) => {
+                // The local address chosen by the OS helps correlating with upstream firewall logs.
+                // Only the port is logged under redaction, as the local IP may disclose the destination network.
+                match stream.local_addr() {
+                    Ok(local_addr) if log_policy.redact_destination => {
+                        debug!(local_port = local_addr.port(), "Connected to target")
+                    }
+                    Ok(local_addr) => debug!(%local_addr, "Connected to target"),
+                    Err(error) => debug!(%error, "Connected to target, but couldn’t query the local address"),
                 }
-                Err(error) => {
-                    debug!(?error, "TcpStream::connect failed");
-                    msg_to_send_tx
-                        .send(Message::open_failure(
-                            channel.distant_id,
-                            ReasonCode::from(error.kind()),
-                            error.to_string(),
-                        ))
-                        .await
-                        .context("couldn’t send OPEN FAILURE message through mpsc channel")?;
-                    anyhow::bail!("couldn’t open TCP stream to {}:{}: {}", host, port, error);
+
+                if let (Some(tcp_keepalive), TargetStream::Tcp(stream)) = (tcp_keepalive, &stream) {
+                    if let Err(error) = tcp_keepalive.apply(stream) {
+                        warn!(%error, "Couldn’t set TCP keepalive");
+                    }
                 }
-            },
-            _ => anyhow::bail!("unsupported scheme: {}", scheme),
+
+                internal_msg_tx
+                    .send(InternalMessage::StreamResolved {
+                        channel: Box::new(channel),
+                        stream,
+                    })
+                    .await
+                    .context("could't send back resolved stream through internal mpsc channel")?;
+            }
+            Err(error) => {
+                debug!(?error, "Connection to target failed");
+                msg_to_send_tx
+                    .send(Message::open_failure(
+                        channel.distant_id,
+                        ReasonCode::from_error_chain(&error),
+                        error.to_string(),
+                    ))
+                    .await
+                    .context("couldn’t send OPEN FAILURE message through mpsc channel")?;
+                anyhow::bail!(
+                    "couldn’t open {} stream to {}:{}: {}",
+                    scheme.to_uppercase(),
+                    log_policy.host(host),
+                    port,
+                    error
+                );
+            }
         }
 
         Ok(())
     }
 }
 
+fn channel_open(
+    id: LocalChannelId,
+    destination_url: DestinationUrl,
+    channel_limits: ChannelLimits,
+    capabilities: &SessionCapabilities,
+) -> Message {
+    let mut open = ChannelOpen::new(id, channel_limits.maximum_packet_size, destination_url);
+    open.initial_window_size = channel_limits.initial_window_size;
+
+    if capabilities.unlimited_window() {
+        open.initial_window_size = UNLIMITED_WINDOW_SIZE;
+    }
+
+    Message::Open(open)
+}
+
+/// Tells the peer that no more data will be sent on the channel, and closes it if the peer already did the same.
+async fn local_eof(jmux_ctx: &mut JmuxCtx, msg_to_send_tx: &MessageSender, id: LocalChannelId) -> anyhow::Result<()> {
+    let Some(channel) = jmux_ctx.get_channel_mut(id) else {
+        // The channel was closed in the meantime.
+        return Ok(());
+    };
+
+    if channel.local_state != JmuxChannelState::Streaming {
+        // Already EOFed (e.g.: the reader task hit the end of the stream while the channel was being drained).
+        return Ok(());
+    }
+
+    let channel_span = channel.span.clone();
+    let local_id = channel.local_id;
+    let distant_id = channel.distant_id;
+
+    match channel.distant_state {
+        JmuxChannelState::Streaming => {
+            channel.local_state = JmuxChannelState::Eof;
+            msg_to_send_tx
+                .send(Message::eof(distant_id))
+                .await
+                .context("couldn’t send EOF message")?;
+        }
+        JmuxChannelState::Eof => {
+            channel.local_state = JmuxChannelState::Closed;
+            msg_to_send_tx
+                .send(Message::close(distant_id))
+                .await
+                .context("couldn’t send CLOSE message")?;
+            jmux_ctx.close_queued(local_id, distant_id);
+        }
+        JmuxChannelState::Closed => {
+            msg_to_send_tx
+                .send(Message::close(distant_id))
+                .await
+                .context("couldn’t send CLOSE message")?;
+            jmux_ctx.close_queued(local_id, distant_id);
+            jmux_ctx.unregister(local_id);
+            channel_span.in_scope(|| {
+                debug!("Channel closed");
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Stops reading from the target and EOFs the channel, so it is closed once the peer is done as well.
+///
+/// Unlike [`close_channel_abnormally`], the data sent by the peer in the meantime is still written to the target.
+async fn drain_channel(
+    jmux_ctx: &mut JmuxCtx,
+    msg_to_send_tx: &MessageSender,
+    id: LocalChannelId,
+) -> anyhow::Result<()> {
+    let Some(channel) = jmux_ctx.get_channel_mut(id) else {
+        return Ok(());
+    };
+
+    if let Some(reader_task) = channel.reader_task.take() {
+        reader_task.abort();
+    }
+
+    channel.span.in_scope(|| {
+        debug!("Draining channel");
+    });
+
+    local_eof(jmux_ctx, msg_to_send_tx, id).await
+}
+
+/// Closes all the channels at once, when the session is stopped without waiting for the peer.
+async fn close_all_channels(
+    jmux_ctx: &JmuxCtx,
+    data_senders: &mut HashMap<LocalChannelId, ChannelDataSender>,
+    msg_to_send_tx: &MessageSender,
+    reason: &str,
+) -> anyhow::Result<()> {
+    for channel in jmux_ctx.channels.values() {
+        if let Some(reader_task) = &channel.reader_task {
+            reader_task.abort();
+        }
+
+        if channel.local_state != JmuxChannelState::Closed {
+            msg_to_send_tx
+                .send(Message::close(channel.distant_id))
+                .await
+                .context("couldn’t send CLOSE message")?;
+        }
+
+        channel.span.in_scope(|| {
+            debug!("Channel closed because {reason}");
+        });
+    }
+
+    // Dropping the data senders gracefully shuts down the streams once the buffered data is written.
+    data_senders.clear();
+
+    Ok(())
+}
+
+/// Closes a channel right away, without waiting for the buffered data to be forwarded.
+async fn close_channel_abnormally(
+    jmux_ctx: &mut JmuxCtx,
+    data_senders: &mut HashMap<LocalChannelId, ChannelDataSender>,
+    msg_to_send_tx: &MessageSender,
+    id: LocalChannelId,
+) -> anyhow::Result<()> {
+    let Some(channel) = jmux_ctx.get_channel_mut(id) else {
+        return Ok(());
+    };
+
+    if let Some(reader_task) = channel.reader_task.take() {
+        reader_task.abort();
+    }
+
+    // This will also shutdown the associated TCP stream.
+    data_senders.remove(&id);
+
+    let distant_id = channel.distant_id;
+    let distant_closed = channel.distant_state == JmuxChannelState::Closed;
+
+    if channel.local_state != JmuxChannelState::Closed {
+        channel.local_state = JmuxChannelState::Closed;
+        msg_to_send_tx
+            .send(Message::close(distant_id))
+            .await
+            .context("couldn’t send CLOSE message")?;
+        jmux_ctx.close_queued(id, distant_id);
+    }
+
+    if distant_closed {
+        jmux_ctx.unregister(id);
+    }
+
+    Ok(())
+}
+
+/// Returns the window size accepted for a channel, given the one advertised by the peer.
+///
+/// The advertised window is clamped, so a peer can't effectively opt out of flow control with a huge window.
+/// The unlimited window is kept when flow control is disabled for the channel, as it must be advertised back.
+fn accepted_window_size(advertised: u32, flow_control: bool, cfg: &JmuxConfig) -> u32 {
+    if flow_control {
+        let accepted = advertised.min(cfg.max_initial_window_size);
+
+        if accepted < advertised {
+            debug!(
+                advertised,
+                accepted, "Clamped the initial window size advertised by the peer"
+            );
+        }
+
+        accepted
+    } else {
+        advertised
+    }
+}
+
+async fn connect_tcp(
+    resolver: &dyn Resolver,
+    host: &str,
+    port: u16,
+    happy_eyeballs_delay: Duration,
+    max_addresses_per_resolution: usize,
+) -> io::Result<TcpStream> {
+    let addrs = resolver.resolve(host, port).await?;
+    happy_eyeballs::connect(&addrs, happy_eyeballs_delay, max_addresses_per_resolution).await
+}
+
+async fn connect_udp(resolver: &dyn Resolver, host: &str, port: u16) -> io::Result<UdpSocket> {
+    let addrs = resolver.resolve(host, port).await?;
+    datagram::connect(&addrs).await
+}
+
 /// Aborts the running task when dropped.
 /// Also see https://github.com/tokio-rs/tokio/issues/1830 for some background.
 #[must_use]
@@ -967,6 +2670,10 @@ impl<T> ChildTask<T> {
         self.0.abort()
     }
 
+    fn abort_handle(&self) -> AbortHandle {
+        self.0.abort_handle()
+    }
+
     fn detach(self) {
         core::mem::forget(self);
     }
@@ -997,3 +2704,193 @@ fn is_really_an_error(original_error: &(dyn std::error::Error + 'static)) -> boo
 
     true
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Writer keeping all the written bytes, and counting the number of writes.
+    #[derive(Clone, Default)]
+    struct CountingWriter {
+        written: Arc<parking_lot::Mutex<Vec<u8>>>,
+        nb_writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.nb_writes.fetch_add(1, Ordering::SeqCst);
+            self.written.lock().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_coalesced() {
+        const NB_MESSAGES: u32 = 100;
+
+        let message = |i: u32| {
+            Message::data(
+                DistantChannelId::from(i),
+                Bytes::from(vec![0; usize::from(DEFAULT_MAXIMUM_PACKET_SIZE_IN_BYTES)]),
+            )
+        };
+
+        let (msg_to_send_tx, msg_to_send_rx) = mpsc::channel(JMUX_MESSAGE_MPSC_CHANNEL_SIZE);
+
+        for i in 0..NB_MESSAGES {
+            msg_to_send_tx.send(message(i)).await.unwrap();
+        }
+        drop(msg_to_send_tx);
+
+        let writer = CountingWriter::default();
+
+        JmuxSenderTask {
+            jmux_writer: writer.clone(),
+            msg_to_send_rx,
+            data_queue_rx: mpsc::unbounded_channel().1,
+            shutdown: Arc::new(Notify::new()),
+            log_policy: LogPolicy::default(),
+            compressed_fields: Arc::default(),
+            max_destination_url_size: ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE,
+            close_handed_over_tx: mpsc::unbounded_channel().0,
+        }
+        .run()
+        .await
+        .unwrap();
+
+        // Without coalescing, the buffered writer would write every 4 messages.
+        let nb_writes = writer.nb_writes.load(Ordering::SeqCst);
+        assert!(nb_writes <= 10, "{nb_writes} writes");
+
+        // All the messages are sent in order.
+        let written = Bytes::from(writer.written.lock().clone());
+        let decoded = Message::decode_all(written).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(decoded, (0..NB_MESSAGES).map(message).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn message_failing_to_encode_is_skipped() {
+        let id = DistantChannelId::from(1);
+        let data = || Message::data(id, Bytes::from_static(b"hello"));
+
+        // The description alone is larger than the maximum packet size.
+        let unencodable = Message::open_failure(
+            DistantChannelId::from(2),
+            ReasonCode::GENERAL_FAILURE,
+            "x".repeat(usize::from(u16::MAX)),
+        );
+
+        let (msg_to_send_tx, msg_to_send_rx) = mpsc::channel(JMUX_MESSAGE_MPSC_CHANNEL_SIZE);
+        msg_to_send_tx.send(data()).await.unwrap();
+        msg_to_send_tx.send(unencodable).await.unwrap();
+        msg_to_send_tx.send(Message::eof(id)).await.unwrap();
+        drop(msg_to_send_tx);
+
+        let writer = CountingWriter::default();
+
+        JmuxSenderTask {
+            jmux_writer: writer.clone(),
+            msg_to_send_rx,
+            data_queue_rx: mpsc::unbounded_channel().1,
+            shutdown: Arc::new(Notify::new()),
+            log_policy: LogPolicy::default(),
+            compressed_fields: Arc::default(),
+            max_destination_url_size: ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE,
+            close_handed_over_tx: mpsc::unbounded_channel().0,
+        }
+        .run()
+        .await
+        .unwrap();
+
+        let written = Bytes::from(writer.written.lock().clone());
+        let decoded = Message::decode_all(written).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(decoded, [data(), Message::eof(id)]);
+    }
+
+    #[test]
+    fn id_is_reused_only_once_the_close_is_handed_over() {
+        let mut jmux_ctx = JmuxCtx::new(
+            JmuxConfig::DEFAULT_LATE_DATA_GRACE_PERIOD,
+            Arc::new(NoopMetricsRecorder),
+        );
+
+        let local_id = jmux_ctx.allocate_id().unwrap();
+        let distant_id = DistantChannelId::from(42);
+
+        jmux_ctx
+            .register_channel(JmuxChannelCtx {
+                distant_id,
+                distant_state: JmuxChannelState::Closed,
+                local_id,
+                local_state: JmuxChannelState::Closed,
+                initial_window_size: 1024,
+                window_size_updated: Arc::new(Notify::new()),
+                window_size: Arc::new(AtomicUsize::new(1024)),
+                remote_window_size: 1024,
+                maximum_packet_size: DEFAULT_MAXIMUM_PACKET_SIZE_IN_BYTES,
+                dropped_oversized_packets: 0,
+                next_distant_sequence_number: 0,
+                flow_control: true,
+                destination_url: DestinationUrl::parse_str("tcp://localhost:80").unwrap(),
+                bytes_tx: Arc::new(AtomicU64::new(0)),
+                bytes_rx: 0,
+                last_activity: tokio::time::Instant::now(),
+                last_activity_bytes: 0,
+                reader_task: None,
+                idle_timer: None,
+                channel_permit: None,
+                recorded: None,
+                span: Span::none(),
+            })
+            .unwrap();
+
+        jmux_ctx.close_queued(local_id, distant_id);
+        jmux_ctx.unregister(local_id);
+
+        // The CLOSE message is still in the queue of the sender task.
+        let other_id = jmux_ctx.allocate_id().unwrap();
+        assert_ne!(other_id, local_id);
+
+        jmux_ctx.close_handed_over(distant_id);
+        assert_eq!(jmux_ctx.allocate_id(), Some(local_id));
+    }
+
+    #[tokio::test]
+    async fn waiting_on_a_full_internal_channel_is_counted() {
+        let metrics = Arc::new(JmuxMetrics::default());
+        let (tx, mut rx) = mpsc::channel(1);
+        let tx = InternalMessageSender {
+            inner: tx,
+            metrics: Arc::clone(&metrics),
+        };
+
+        let id = LocalChannelId::from(1);
+
+        tx.send(InternalMessage::Eof { id }).await.unwrap();
+        assert_eq!(metrics.internal_channel_full(), 0);
+
+        let blocked_send = tokio::spawn({
+            let tx = tx.clone();
+            async move { tx.send(InternalMessage::Eof { id }).await.unwrap() }
+        });
+
+        tokio::task::yield_now().await;
+        assert_eq!(metrics.internal_channel_full(), 1);
+
+        rx.recv().await.unwrap();
+        blocked_send.await.unwrap();
+        rx.recv().await.unwrap();
+    }
+}
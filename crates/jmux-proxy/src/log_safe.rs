@@ -0,0 +1,162 @@
+//! Helpers to format user-controlled values in logs.
+//!
+//! Control characters are always escaped, so a value can't forge extra log lines,
+//! and long values are truncated so they can't bloat the logs.
+
+use crate::JmuxConfig;
+use jmux_proto::{DestinationUrl, Message};
+use std::fmt::{self, Write as _};
+use std::hash::{Hash as _, Hasher as _};
+
+/// How user-controlled values are formatted in the logs.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LogPolicy {
+    pub(crate) redact_destination: bool,
+    /// Maximum number of characters written for a single value
+    pub(crate) max_value_len: usize,
+}
+
+impl Default for LogPolicy {
+    fn default() -> Self {
+        Self {
+            redact_destination: false,
+            max_value_len: JmuxConfig::DEFAULT_MAX_LOGGED_VALUE_LEN,
+        }
+    }
+}
+
+impl LogPolicy {
+    pub(crate) fn new(cfg: &JmuxConfig) -> Self {
+        Self {
+            redact_destination: cfg.redact_destination_in_logs,
+            max_value_len: cfg.max_logged_value_len,
+        }
+    }
+
+    pub(crate) fn text(self, text: &str) -> LoggedText<'_> {
+        LoggedText {
+            text,
+            max_len: self.max_value_len,
+        }
+    }
+
+    pub(crate) fn host(self, host: &str) -> LoggedHost<'_> {
+        LoggedHost { host, policy: self }
+    }
+
+    pub(crate) fn url(self, url: &DestinationUrl) -> LoggedDestinationUrl<'_> {
+        LoggedDestinationUrl { url, policy: self }
+    }
+
+    pub(crate) fn message(self, msg: &Message) -> LoggedMessage<'_> {
+        LoggedMessage { msg, policy: self }
+    }
+}
+
+/// Displays a text with its control characters escaped, truncated to the maximum length.
+#[derive(Clone, Copy)]
+pub(crate) struct LoggedText<'a> {
+    text: &'a str,
+    max_len: usize,
+}
+
+impl fmt::Display for LoggedText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut chars = self.text.chars();
+
+        for c in chars.by_ref().take(self.max_len) {
+            if c.is_control() {
+                write!(f, "{}", c.escape_default())?;
+            } else {
+                f.write_char(c)?;
+            }
+        }
+
+        let nb_truncated = chars.count();
+
+        if nb_truncated > 0 {
+            write!(f, "…({nb_truncated} more characters)")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Displays a host, replaced by a stable hash when redaction is enabled.
+///
+/// The same host is always replaced by the same hash, so log lines can still be correlated.
+#[derive(Clone, Copy)]
+pub(crate) struct LoggedHost<'a> {
+    host: &'a str,
+    policy: LogPolicy,
+}
+
+impl fmt::Display for LoggedHost<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.policy.redact_destination {
+            // Keys are fixed, so the hash is stable across sessions.
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            self.host.to_ascii_lowercase().hash(&mut hasher);
+            write!(f, "redacted-{:016x}", hasher.finish())
+        } else {
+            self.policy.text(self.host).fmt(f)
+        }
+    }
+}
+
+/// Displays a destination URL, with the host redacted when redaction is enabled.
+#[derive(Clone, Copy)]
+pub(crate) struct LoggedDestinationUrl<'a> {
+    url: &'a DestinationUrl,
+    policy: LogPolicy,
+}
+
+impl fmt::Display for LoggedDestinationUrl<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.policy.redact_destination {
+            write!(
+                f,
+                "{}://{}:{}",
+                self.policy.text(self.url.scheme()),
+                self.policy.host(self.url.host()),
+                self.url.port()
+            )
+        } else {
+            self.policy.text(self.url.as_str()).fmt(f)
+        }
+    }
+}
+
+/// Debug-formats a JMUX message, with the destination URL and the description formatted according to the policy.
+#[derive(Clone, Copy)]
+pub(crate) struct LoggedMessage<'a> {
+    msg: &'a Message,
+    policy: LogPolicy,
+}
+
+impl fmt::Debug for LoggedMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.msg {
+            Message::Open(msg) => f
+                .debug_tuple("Open")
+                .field(&format_args!(
+                    "ChannelOpen {{ sender_channel_id: {}, initial_window_size: {}, maximum_packet_size: {}, destination_url: {} }}",
+                    msg.sender_channel_id,
+                    msg.initial_window_size,
+                    msg.maximum_packet_size,
+                    self.policy.url(&msg.destination_url)
+                ))
+                .finish(),
+            Message::OpenFailure(msg) => f
+                .debug_tuple("OpenFailure")
+                .field(&format_args!(
+                    "ChannelOpenFailure {{ recipient_channel_id: {}, reason_code: {:?}, description: {} }}",
+                    msg.recipient_channel_id,
+                    msg.reason_code,
+                    self.policy.text(&msg.description)
+                ))
+                .finish(),
+            msg => fmt::Debug::fmt(msg, f),
+        }
+    }
+}
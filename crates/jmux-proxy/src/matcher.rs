@@ -0,0 +1,339 @@
+use crate::config::{ensure_connectable_port, FilteringRule};
+use jmux_proto::DestinationUrl;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Pre-processed form of a [`FilteringRule`], optimized for repeated evaluation.
+///
+/// Building this is relatively costly, but it is typically done only once per proxy.
+/// Leaves of an "OR" rule are indexed so that checking a destination against thousands of
+/// allowed hosts is roughly proportional to the length of the host rather than to the number of rules:
+/// - exact hosts, ports, schemes and host:port pairs are stored in hash sets,
+/// - wildcard hosts are stored in a trie keyed by domain labels (from right to left).
+///
+/// Decisions are always identical to the ones taken by [`FilteringRule::validate_destination`].
+///
+/// The compiled form is immutable and shared: cloning it is cheap.
+///
+/// ```
+/// use jmux_proxy::FilteringRule;
+///
+/// let rule = FilteringRule::host("devolutions.net")
+///     .or(FilteringRule::wildcard_host("*.devolutions.net"))
+///     .or(FilteringRule::host_and_port("127.0.0.1", 8080));
+/// let compiled = rule.compile();
+///
+/// assert!(compiled.validate_destination_str("tcp://DEVOLUTIONS.NET:80").is_ok());
+/// assert!(compiled.validate_destination_str("tcp://dvls.devolutions.net:443").is_ok());
+/// assert!(compiled.validate_destination_str("tcp://127.0.0.1:8080").is_ok());
+/// assert!(compiled.validate_destination_str("tcp://127.0.0.1:22").is_err());
+/// ```
+#[derive(Debug, Clone)]
+pub struct CompiledFilteringRule {
+    root: Arc<Node>,
+}
+
+impl CompiledFilteringRule {
+    pub fn new(rule: &FilteringRule) -> Self {
+        Self {
+            root: Arc::new(Node::compile(rule)),
+        }
+    }
+
+    pub fn is_allowed(&self, scheme: &str, host: &str, port: u16) -> bool {
+        let target = Target {
+            scheme: scheme.to_ascii_lowercase(),
+            host: host.to_ascii_lowercase(),
+            port,
+        };
+        self.root.matches(&target)
+    }
+
+    pub fn validate_destination(&self, destination_url: &DestinationUrl) -> anyhow::Result<()> {
+        ensure_connectable_port(destination_url.port())?;
+
+        if self.is_allowed(destination_url.scheme(), destination_url.host(), destination_url.port()) {
+            Ok(())
+        } else {
+            anyhow::bail!("target doesn't obey the filtering rule");
+        }
+    }
+
+    pub fn validate_destination_str(&self, destination_url: impl AsRef<str>) -> anyhow::Result<()> {
+        use anyhow::Context as _;
+
+        let (scheme, target) = destination_url
+            .as_ref()
+            .split_once("://")
+            .context("invalid destination URL format")?;
+        let (host, port) = target.rsplit_once(':').context("invalid target format")?;
+        let port = port.parse().context("invalid port value")?;
+        ensure_connectable_port(port)?;
+
+        if self.is_allowed(scheme, host, port) {
+            Ok(())
+        } else {
+            anyhow::bail!("target doesn't obey the filtering rule");
+        }
+    }
+}
+
+impl From<&FilteringRule> for CompiledFilteringRule {
+    fn from(rule: &FilteringRule) -> Self {
+        Self::new(rule)
+    }
+}
+
+struct Target {
+    scheme: String,
+    host: String,
+    port: u16,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Deny,
+    Allow,
+    Not(Box<Node>),
+    All(Vec<Node>),
+    Any(Box<AnyNode>),
+    Host(String),
+    Port(u16),
+    Scheme(String),
+    HostAndPort { host: String, port: u16 },
+    WildcardHost(WildcardTrie),
+}
+
+/// Indexed form of an "OR" rule.
+#[derive(Debug, Clone, Default)]
+struct AnyNode {
+    hosts: HashSet<String>,
+    ports: HashSet<u16>,
+    schemes: HashSet<String>,
+    host_and_ports: HashMap<String, HashSet<u16>>,
+    wildcard_hosts: WildcardTrie,
+    /// Sub-rules which can't be indexed, evaluated linearly.
+    others: Vec<Node>,
+}
+
+impl Node {
+    fn compile(rule: &FilteringRule) -> Self {
+        match rule {
+            FilteringRule::Deny => Node::Deny,
+            FilteringRule::Allow => Node::Allow,
+            FilteringRule::Not(rule) => Node::Not(Box::new(Node::compile(rule))),
+            FilteringRule::All(rules) => Node::All(rules.iter().map(Node::compile).collect()),
+            FilteringRule::Any(rules) => {
+                let mut any = AnyNode::default();
+
+                for rule in rules {
+                    match rule {
+                        FilteringRule::Host(host) => {
+                            any.hosts.insert(host.to_ascii_lowercase());
+                        }
+                        FilteringRule::Port(port) => {
+                            any.ports.insert(*port);
+                        }
+                        FilteringRule::Scheme(scheme) => {
+                            any.schemes.insert(scheme.to_ascii_lowercase());
+                        }
+                        FilteringRule::HostAndPort { host, port } => {
+                            any.host_and_ports
+                                .entry(host.to_ascii_lowercase())
+                                .or_default()
+                                .insert(*port);
+                        }
+                        FilteringRule::WildcardHost(pattern) => any.wildcard_hosts.insert(pattern),
+                        other => any.others.push(Node::compile(other)),
+                    }
+                }
+
+                Node::Any(Box::new(any))
+            }
+            FilteringRule::Host(host) => Node::Host(host.to_ascii_lowercase()),
+            FilteringRule::Port(port) => Node::Port(*port),
+            FilteringRule::Scheme(scheme) => Node::Scheme(scheme.to_ascii_lowercase()),
+            FilteringRule::HostAndPort { host, port } => Node::HostAndPort {
+                host: host.to_ascii_lowercase(),
+                port: *port,
+            },
+            FilteringRule::WildcardHost(pattern) => {
+                let mut trie = WildcardTrie::default();
+                trie.insert(pattern);
+                Node::WildcardHost(trie)
+            }
+        }
+    }
+
+    fn matches(&self, target: &Target) -> bool {
+        match self {
+            Node::Deny => false,
+            Node::Allow => true,
+            Node::Not(node) => !node.matches(target),
+            Node::All(nodes) => nodes.iter().all(|node| node.matches(target)),
+            Node::Any(any) => {
+                any.hosts.contains(&target.host)
+                    || any.ports.contains(&target.port)
+                    || any.schemes.contains(&target.scheme)
+                    || any
+                        .host_and_ports
+                        .get(&target.host)
+                        .is_some_and(|ports| ports.contains(&target.port))
+                    || any.wildcard_hosts.matches(&target.host)
+                    || any.others.iter().any(|node| node.matches(target))
+            }
+            Node::Host(host) => target.host == *host,
+            Node::Port(port) => target.port == *port,
+            Node::Scheme(scheme) => target.scheme == *scheme,
+            Node::HostAndPort { host, port } => target.host == *host && target.port == *port,
+            Node::WildcardHost(trie) => trie.matches(&target.host),
+        }
+    }
+}
+
+/// Trie of wildcard host patterns, keyed by domain labels from right to left.
+///
+/// A `*` label matches exactly one label of any value.
+#[derive(Debug, Clone, Default)]
+struct WildcardTrie {
+    children: HashMap<String, WildcardTrie>,
+    star: Option<Box<WildcardTrie>>,
+    terminal: bool,
+}
+
+impl WildcardTrie {
+    fn insert(&mut self, pattern: &str) {
+        let mut node = self;
+
+        for label in pattern.rsplit('.') {
+            node = if label == "*" {
+                node.star.get_or_insert_with(Box::default)
+            } else {
+                node.children.entry(label.to_ascii_lowercase()).or_default()
+            };
+        }
+
+        node.terminal = true;
+    }
+
+    /// `host` is expected to be lowercase already.
+    fn matches(&self, host: &str) -> bool {
+        let labels: Vec<&str> = host.rsplit('.').collect();
+        self.matches_labels(&labels)
+    }
+
+    fn matches_labels(&self, labels: &[&str]) -> bool {
+        let Some((label, rest)) = labels.split_first() else {
+            return self.terminal;
+        };
+
+        if let Some(child) = self.children.get(*label) {
+            if child.matches_labels(rest) {
+                return true;
+            }
+        }
+
+        match &self.star {
+            Some(star) => star.matches_labels(rest),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JmuxConfig;
+
+    fn sample_rules() -> Vec<FilteringRule> {
+        let mut large = FilteringRule::Deny;
+        for idx in 0..500 {
+            large = large.or(FilteringRule::host(format!("host-{idx}.example.com")));
+            large = large.or(FilteringRule::wildcard_host(format!("*.zone-{idx}.example.net")));
+        }
+        large = large.or(FilteringRule::host_and_port("127.0.0.1", 8080));
+
+        vec![
+            FilteringRule::Allow,
+            FilteringRule::Deny,
+            FilteringRule::Any(Vec::new()),
+            FilteringRule::All(Vec::new()),
+            FilteringRule::port(80)
+                .and(
+                    FilteringRule::host("doc.rust-lang.org")
+                        .or(FilteringRule::wildcard_host("devolutions.*"))
+                        .or(FilteringRule::wildcard_host("*.devolutions.net")),
+                )
+                .or(FilteringRule::port(22).and(FilteringRule::wildcard_host("vps.*.*")))
+                .or(FilteringRule::port(1080).invert().and(FilteringRule::host("sekai.net")))
+                .or(FilteringRule::host_and_port("127.0.0.1", 8080).and(FilteringRule::scheme("wss"))),
+            FilteringRule::wildcard_host("*.*.devolutions.net").or(FilteringRule::wildcard_host("a.*.devolutions.net")),
+            FilteringRule::scheme("TCP").or(FilteringRule::port(443)),
+            large,
+        ]
+    }
+
+    fn sample_destinations() -> Vec<&'static str> {
+        vec![
+            "tcp://doc.rust-lang.org:80",
+            "ws://DeVoLUTiONS.net:80",
+            "wss://dvls.devolutions.net:80",
+            "tcp://dvls.devolutions.ninja:80",
+            "tcp://vps.my-web-site.com:22",
+            "tcp://super.vps.ninja:22",
+            "tcp://sekai.net:1080",
+            "tCp://sEkAi.nEt:22",
+            "wss://127.0.0.1:8080",
+            "tcp://127.0.0.1:8080",
+            "tcp://a.b.devolutions.net:1",
+            "tcp://a.devolutions.net:1",
+            "udp://anything:443",
+            "tcp://host-42.example.com:1",
+            "tcp://HOST-499.EXAMPLE.COM:1",
+            "tcp://host-500.example.com:1",
+            "tcp://x.zone-7.example.net:1",
+            "tcp://zone-7.example.net:1",
+            "tcp://x.y.zone-7.example.net:1",
+            "tcp://:1",
+        ]
+    }
+
+    #[test]
+    fn compiled_rule_takes_identical_decisions() {
+        for rule in sample_rules() {
+            let compiled = rule.compile();
+
+            for destination in sample_destinations() {
+                assert_eq!(
+                    rule.validate_destination_str(destination).is_ok(),
+                    compiled.validate_destination_str(destination).is_ok(),
+                    "{destination} with {rule:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cloned_config_takes_identical_decisions() {
+        for rule in sample_rules() {
+            let config = JmuxConfig {
+                filtering: rule,
+                ..JmuxConfig::default()
+            };
+            let cloned_config = config.clone();
+
+            let compiled = config.filtering.compile();
+            let cloned_compiled = compiled.clone();
+            assert!(Arc::ptr_eq(&compiled.root, &cloned_compiled.root));
+
+            for destination in sample_destinations() {
+                let expected = config.filtering.validate_destination_str(destination).is_ok();
+                assert_eq!(
+                    cloned_config.filtering.validate_destination_str(destination).is_ok(),
+                    expected
+                );
+                assert_eq!(cloned_compiled.validate_destination_str(destination).is_ok(), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::metrics::{DataDirection, JmuxMetrics};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Accounts for the channel data held in memory by a proxy, and tells when backpressure should be applied.
+///
+/// Data is accounted from the moment it is queued (towards a target or towards the peer) until it is written,
+/// through the [`Reservation`] travelling along with it. Data waiting for the peer to adjust the window is not
+/// queued yet, and is not accounted.
+pub(crate) struct MemoryBudget {
+    outstanding_bytes: AtomicU64,
+    /// Part of the outstanding bytes received from the peer and waiting to be written to the targets
+    outstanding_rx_bytes: AtomicU64,
+    soft_cap: Option<u64>,
+    released: Notify,
+    metrics: Arc<JmuxMetrics>,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(soft_cap: Option<u64>, metrics: Arc<JmuxMetrics>) -> Self {
+        Self {
+            outstanding_bytes: AtomicU64::new(0),
+            outstanding_rx_bytes: AtomicU64::new(0),
+            soft_cap,
+            released: Notify::new(),
+            metrics,
+        }
+    }
+
+    /// Accounts for `len` bytes being queued, until the returned reservation is dropped.
+    ///
+    /// The data is always accepted: the cap is only enforced by the callers waiting for room before reading more.
+    pub(crate) fn reserve(self: &Arc<Self>, direction: DataDirection, len: usize) -> Reservation {
+        let len_u64 = u64::try_from(len).expect("usize-to-u64");
+
+        let before = self.outstanding_bytes.fetch_add(len_u64, Ordering::SeqCst);
+        if direction == DataDirection::Rx {
+            self.outstanding_rx_bytes.fetch_add(len_u64, Ordering::SeqCst);
+        }
+        self.metrics.outstanding_bytes.fetch_add(len_u64, Ordering::Relaxed);
+
+        if let Some(soft_cap) = self.soft_cap {
+            if before <= soft_cap && soft_cap < before + len_u64 {
+                self.metrics
+                    .outstanding_bytes_cap_reached
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    soft_cap,
+                    outstanding_bytes = before + len_u64,
+                    "Outstanding bytes cap reached; applying backpressure"
+                );
+            }
+        }
+
+        Reservation {
+            budget: Arc::clone(self),
+            direction,
+            len,
+        }
+    }
+
+    /// Accounts for `len` bytes being written out.
+    fn release(&self, direction: DataDirection, len: usize) {
+        if len == 0 {
+            return;
+        }
+
+        let len = u64::try_from(len).expect("usize-to-u64");
+
+        let saturating_sub = |outstanding: u64| Some(outstanding.saturating_sub(len));
+
+        let _ = self
+            .outstanding_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, saturating_sub);
+        if direction == DataDirection::Rx {
+            let _ = self
+                .outstanding_rx_bytes
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, saturating_sub);
+        }
+        let _ = self
+            .metrics
+            .outstanding_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, saturating_sub);
+
+        // The outstanding RX bytes are part of the outstanding bytes, so this covers both kinds of waiters.
+        if !self.is_exceeded(DataDirection::Rx) {
+            self.released.notify_waiters();
+        }
+    }
+
+    /// Tells whether reading more data going in the given direction should be paused.
+    ///
+    /// Reading from the targets (TX) is paused when too much data is outstanding overall. Reading from the JMUX pipe
+    /// (RX) is only paused when too much data is waiting for the targets: the data waiting for the peer must not pause
+    /// it, as the messages releasing it (e.g.: WINDOW ADJUST) are themselves received through the pipe.
+    pub(crate) fn is_exceeded(&self, direction: DataDirection) -> bool {
+        let outstanding = match direction {
+            DataDirection::Tx => &self.outstanding_bytes,
+            DataDirection::Rx => &self.outstanding_rx_bytes,
+        };
+
+        self.soft_cap
+            .is_some_and(|soft_cap| soft_cap < outstanding.load(Ordering::SeqCst))
+    }
+
+    /// Waits until reading more data going in the given direction is allowed again.
+    pub(crate) async fn wait_available(&self, direction: DataDirection) {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+
+            // Registered before checking, so a release happening in-between is not missed.
+            released.as_mut().enable();
+
+            if !self.is_exceeded(direction) {
+                return;
+            }
+
+            released.await;
+        }
+    }
+}
+
+/// Bytes accounted by the [`MemoryBudget`], released when dropped.
+///
+/// Kept along with the data it accounts for, so the bytes are released however the data is discarded
+/// (e.g.: a task aborted while holding it, or a queue dropped before being emptied).
+pub(crate) struct Reservation {
+    budget: Arc<MemoryBudget>,
+    direction: DataDirection,
+    len: usize,
+}
+
+impl core::fmt::Debug for Reservation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Reservation")
+            .field("direction", &self.direction)
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.direction, self.len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_are_released_when_the_reservation_is_dropped() {
+        let metrics = Arc::new(JmuxMetrics::new());
+        let budget = Arc::new(MemoryBudget::new(Some(16), Arc::clone(&metrics)));
+
+        let tx = budget.reserve(DataDirection::Tx, 32);
+        assert_eq!(metrics.outstanding_bytes(), 32);
+        assert!(budget.is_exceeded(DataDirection::Tx));
+        // Data waiting for the peer doesn't pause reading from the pipe.
+        assert!(!budget.is_exceeded(DataDirection::Rx));
+
+        let rx = budget.reserve(DataDirection::Rx, 32);
+        assert!(budget.is_exceeded(DataDirection::Rx));
+
+        drop(rx);
+        assert!(!budget.is_exceeded(DataDirection::Rx));
+        assert!(budget.is_exceeded(DataDirection::Tx));
+
+        drop(tx);
+        assert!(!budget.is_exceeded(DataDirection::Tx));
+        assert_eq!(metrics.outstanding_bytes(), 0);
+        assert_eq!(metrics.outstanding_bytes_cap_reached(), 1);
+    }
+}
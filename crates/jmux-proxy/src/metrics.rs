@@ -0,0 +1,118 @@
+use jmux_proto::LocalChannelId;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Counters updated by a running JMUX proxy, for monitoring purposes.
+///
+/// Share it with the proxy using [`JmuxProxy::with_metrics`](crate::JmuxProxy::with_metrics),
+/// and read the counters at any time.
+#[derive(Debug, Default)]
+pub struct JmuxMetrics {
+    pub(crate) internal_channel_full: AtomicU64,
+    pub(crate) oversized_data_dropped: AtomicU64,
+    pub(crate) outstanding_bytes: AtomicU64,
+    pub(crate) outstanding_bytes_cap_reached: AtomicU64,
+    pub(crate) idle_channels_closed: AtomicU64,
+}
+
+impl JmuxMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of times a task had to wait because the internal channel of the scheduler was full.
+    ///
+    /// A steadily increasing value means the internal signaling is a bottleneck, and
+    /// [`JmuxConfig::internal_channel_size`](crate::JmuxConfig::internal_channel_size) should be increased.
+    pub fn internal_channel_full(&self) -> u64 {
+        self.internal_channel_full.load(Ordering::Relaxed)
+    }
+
+    /// Number of DATA packets dropped because they were exceeding the maximum packet size of their channel.
+    ///
+    /// A non-zero value usually points to a misbehaving peer.
+    pub fn oversized_data_dropped(&self) -> u64 {
+        self.oversized_data_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes of channel data currently held in memory, waiting to be written to a target or to the peer.
+    pub fn outstanding_bytes(&self) -> u64 {
+        self.outstanding_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the outstanding bytes went over [`JmuxConfig::max_outstanding_bytes`](crate::JmuxConfig::max_outstanding_bytes).
+    ///
+    /// Each time, reading is paused until enough data is written out.
+    pub fn outstanding_bytes_cap_reached(&self) -> u64 {
+        self.outstanding_bytes_cap_reached.load(Ordering::Relaxed)
+    }
+
+    /// Number of channels closed abnormally because no data was exchanged for [`JmuxConfig::idle_timeout`](crate::JmuxConfig::idle_timeout).
+    pub fn idle_channels_closed(&self) -> u64 {
+        self.idle_channels_closed.load(Ordering::Relaxed)
+    }
+}
+
+/// Direction of the data forwarded through a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataDirection {
+    /// Read from the target, and sent to the peer
+    Tx,
+    /// Received from the peer, and written to the target
+    Rx,
+}
+
+/// Receiver of the protocol events of a running JMUX proxy, typically feeding a metrics system (e.g.: Prometheus).
+///
+/// Share it with the proxy using [`JmuxProxy::with_metrics_recorder`](crate::JmuxProxy::with_metrics_recorder).
+/// All the methods do nothing by default. They are called by the proxy tasks as the events occur, so they must
+/// return quickly (e.g.: by updating atomic counters).
+pub trait MetricsRecorder: Send + Sync {
+    /// A channel was opened, either at the request of the peer or through the API.
+    fn channel_opened(&self, _id: LocalChannelId) {}
+
+    /// A channel previously reported as opened was closed.
+    fn channel_closed(&self, _id: LocalChannelId) {}
+
+    /// Data was forwarded through a channel, in the given direction.
+    fn bytes_forwarded(&self, _direction: DataDirection, _n: u64) {}
+
+    /// A channel had data to send to the peer, but had to wait for the peer to adjust the window.
+    ///
+    /// Frequent occurrences mean the window is too small for the bandwidth-delay product of the link
+    /// (see [`JmuxProxy::with_initial_window_size`](crate::JmuxProxy::with_initial_window_size)).
+    fn window_exhausted(&self, _id: LocalChannelId) {}
+}
+
+/// Recorder used when none is provided
+pub(crate) struct NoopMetricsRecorder;
+
+impl MetricsRecorder for NoopMetricsRecorder {}
+
+/// Reports a channel as opened when created, and as closed when dropped.
+pub(crate) struct RecordedChannel {
+    recorder: Arc<dyn MetricsRecorder>,
+    id: LocalChannelId,
+}
+
+impl RecordedChannel {
+    pub(crate) fn new(recorder: Arc<dyn MetricsRecorder>, id: LocalChannelId) -> Self {
+        recorder.channel_opened(id);
+        Self { recorder, id }
+    }
+}
+
+impl Drop for RecordedChannel {
+    fn drop(&mut self) {
+        self.recorder.channel_closed(self.id);
+    }
+}
+
+impl fmt::Debug for RecordedChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordedChannel")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
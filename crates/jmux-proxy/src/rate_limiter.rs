@@ -0,0 +1,95 @@
+//! Bandwidth limitation of the channels (see [`JmuxConfig::max_channel_tx_rate`](crate::JmuxConfig::max_channel_tx_rate))
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Bursts are limited to this fraction of a second of traffic.
+const BURSTS_PER_SECOND: u64 = 10;
+
+/// Token bucket limiting the throughput of a channel in one direction, one token per byte.
+///
+/// Owned by the task forwarding the data, so only this task waits when the limit is reached.
+pub(crate) struct TokenBucket {
+    /// Tokens added per second
+    rate: u64,
+    /// Maximum number of tokens accumulated while idle
+    capacity: u64,
+    tokens: u64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u64) -> Self {
+        let capacity = (rate / BURSTS_PER_SECOND).max(1);
+
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes `len` tokens, waiting for the missing ones if any.
+    ///
+    /// Data larger than the capacity is let through at once, after waiting for as long as its size requires.
+    pub(crate) async fn consume(&mut self, len: usize) {
+        let now = Instant::now();
+        self.refill(now);
+
+        let len = u64::try_from(len).expect("usize-to-u64");
+
+        if len <= self.tokens {
+            self.tokens -= len;
+            return;
+        }
+
+        let missing = len - self.tokens;
+        self.tokens = 0;
+
+        let wait_nanos = u128::from(missing) * NANOS_PER_SEC / u128::from(self.rate);
+        let deadline = now + Duration::from_nanos(u64::try_from(wait_nanos).unwrap_or(u64::MAX));
+
+        tokio::time::sleep_until(deadline).await;
+
+        // The tokens generated while waiting were consumed.
+        self.last_refill = deadline;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_nanos();
+        let new_tokens = elapsed * u128::from(self.rate) / NANOS_PER_SEC;
+
+        if new_tokens == 0 {
+            return;
+        }
+
+        let tokens = u128::from(self.tokens) + new_tokens;
+        self.tokens = u64::try_from(tokens).unwrap_or(u64::MAX).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bursts_are_limited_to_the_capacity() {
+        const RATE: u64 = 1024 * 1024;
+        const BURST: usize = 100 * 1024;
+
+        let mut bucket = TokenBucket::new(RATE);
+
+        let start = Instant::now();
+        bucket.consume(BURST).await;
+        assert!(start.elapsed() < Duration::from_millis(50), "{:?}", start.elapsed());
+
+        // The bucket is empty, so the next burst has to wait for its tokens.
+        let start = Instant::now();
+        bucket.consume(BURST).await;
+        assert!(start.elapsed() >= Duration::from_millis(90), "{:?}", start.elapsed());
+    }
+}
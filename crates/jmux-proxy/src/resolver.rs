@@ -0,0 +1,213 @@
+use futures_util::future::BoxFuture;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Resolves a host and a port into socket addresses before connecting to a target.
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        (**self).resolve(host, port)
+    }
+}
+
+/// Resolver backed by the system resolver (`tokio::net::lookup_host`)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host, port)).await?;
+            Ok(addrs.collect())
+        })
+    }
+}
+
+/// Resolver keeping the addresses returned by another resolver in a bounded LRU cache
+///
+/// DNS TTLs are not exposed by `lookup_host`, so a fixed TTL is used for all the entries.
+/// Failed resolutions are never cached.
+pub struct CachingResolver<R = SystemResolver> {
+    inner: R,
+    ttl: Duration,
+    max_entries: usize,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<(String, u16), CacheEntry>,
+    /// Monotonic counter used to track the recency of the entries
+    clock: u64,
+}
+
+struct CacheEntry {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+    last_used: u64,
+}
+
+impl CachingResolver<SystemResolver> {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self::with_resolver(SystemResolver, ttl, max_entries)
+    }
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn with_resolver(inner: R, ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            inner,
+            ttl,
+            max_entries,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    fn lookup_cache(&self, host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+        let mut cache = self.cache.lock();
+        let key = (host.to_ascii_lowercase(), port);
+        let now = Instant::now();
+
+        cache.clock += 1;
+        let clock = cache.clock;
+
+        let entry = cache.entries.get_mut(&key)?;
+
+        if now.duration_since(entry.resolved_at) < self.ttl {
+            entry.last_used = clock;
+            Some(entry.addrs.clone())
+        } else {
+            cache.entries.remove(&key);
+            None
+        }
+    }
+
+    fn insert_cache(&self, host: &str, port: u16, addrs: Vec<SocketAddr>) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let mut cache = self.cache.lock();
+        let key = (host.to_ascii_lowercase(), port);
+        let now = Instant::now();
+
+        cache.clock += 1;
+        let clock = cache.clock;
+
+        if !cache.entries.contains_key(&key) && cache.entries.len() >= self.max_entries {
+            // Expired entries are evicted first, then the least recently used one.
+            cache
+                .entries
+                .retain(|_, entry| now.duration_since(entry.resolved_at) < self.ttl);
+
+            if cache.entries.len() >= self.max_entries {
+                let lru_key = cache
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone());
+
+                if let Some(lru_key) = lru_key {
+                    cache.entries.remove(&lru_key);
+                }
+            }
+        }
+
+        cache.entries.insert(
+            key,
+            CacheEntry {
+                addrs,
+                resolved_at: now,
+                last_used: clock,
+            },
+        );
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            if let Some(addrs) = self.lookup_cache(host, port) {
+                trace!(host, port, "Resolved from cache");
+                return Ok(addrs);
+            }
+
+            let addrs = self.inner.resolve(host, port).await?;
+
+            self.insert_cache(host, port, addrs.clone());
+
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingResolver {
+        count: AtomicUsize,
+    }
+
+    impl Resolver for CountingResolver {
+        fn resolve<'a>(&'a self, _: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn second_resolve_within_ttl_is_cached() {
+        let inner = Arc::new(CountingResolver::default());
+        let resolver = CachingResolver::with_resolver(Arc::clone(&inner), Duration::from_secs(60), 16);
+
+        let first = resolver.resolve("devolutions.net", 443).await.unwrap();
+        let second = resolver.resolve("DEVOLUTIONS.NET", 443).await.unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(inner.count.load(Ordering::SeqCst), 1);
+
+        resolver.resolve("devolutions.net", 80).await.unwrap();
+        assert_eq!(inner.count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_resolved_again() {
+        let inner = Arc::new(CountingResolver::default());
+        let resolver = CachingResolver::with_resolver(Arc::clone(&inner), Duration::ZERO, 16);
+
+        resolver.resolve("devolutions.net", 443).await.unwrap();
+        resolver.resolve("devolutions.net", 443).await.unwrap();
+
+        assert_eq!(inner.count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_entry_is_evicted() {
+        let inner = Arc::new(CountingResolver::default());
+        let resolver = CachingResolver::with_resolver(Arc::clone(&inner), Duration::from_secs(60), 2);
+
+        resolver.resolve("a", 1).await.unwrap();
+        resolver.resolve("b", 1).await.unwrap();
+        resolver.resolve("a", 1).await.unwrap(); // "b" is now the least recently used entry
+        resolver.resolve("c", 1).await.unwrap();
+        assert_eq!(inner.count.load(Ordering::SeqCst), 3);
+
+        resolver.resolve("a", 1).await.unwrap();
+        assert_eq!(inner.count.load(Ordering::SeqCst), 3);
+
+        resolver.resolve("b", 1).await.unwrap();
+        assert_eq!(inner.count.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,133 @@
+//! Helpers to write tests involving JMUX proxies.
+//!
+//! ```
+//! use jmux_proxy::test_util::connected_pair;
+//! use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+//! use tokio::net::TcpListener;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+//! let target_url = format!("tcp://{}", target.local_addr().unwrap());
+//!
+//! let (client, _server) = connected_pair();
+//!
+//! // Open.
+//! let mut stream = client.open_stream(&target_url).await.unwrap();
+//! let (mut target_stream, _) = target.accept().await.unwrap();
+//!
+//! // Data.
+//! stream.write_all(b"hello").await.unwrap();
+//! let mut buf = [0; 5];
+//! target_stream.read_exact(&mut buf).await.unwrap();
+//! assert_eq!(&buf, b"hello");
+//!
+//! // Close.
+//! drop(stream);
+//! let mut rest = Vec::new();
+//! target_stream.read_to_end(&mut rest).await.unwrap();
+//! assert!(rest.is_empty());
+//! # }
+//! ```
+
+use crate::{DestinationUrl, JmuxApiRequest, JmuxApiResponse, JmuxConfig, JmuxProxy};
+use anyhow::Context as _;
+use jmux_proto::LocalChannelId;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// One side of a pair of connected JMUX proxies
+///
+/// The proxy is aborted when the endpoint is dropped.
+pub struct JmuxEndpoint {
+    api_request_tx: mpsc::Sender<JmuxApiRequest>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Spawns two permissive JMUX proxies whose pipes are connected to each other.
+pub fn connected_pair() -> (JmuxEndpoint, JmuxEndpoint) {
+    connected_pair_with(JmuxConfig::permissive(), JmuxConfig::permissive())
+}
+
+/// Same as [`connected_pair`], but with a specific configuration for each side.
+pub fn connected_pair_with(left: JmuxConfig, right: JmuxConfig) -> (JmuxEndpoint, JmuxEndpoint) {
+    let (left_pipe, right_pipe) = tokio::io::duplex(64 * 1024);
+    (
+        JmuxEndpoint::spawn(left_pipe, left),
+        JmuxEndpoint::spawn(right_pipe, right),
+    )
+}
+
+impl JmuxEndpoint {
+    fn spawn(pipe: tokio::io::DuplexStream, cfg: JmuxConfig) -> Self {
+        let (api_request_tx, api_request_rx) = mpsc::channel(8);
+        let (reader, writer) = tokio::io::split(pipe);
+
+        let proxy = JmuxProxy::new(Box::new(reader), Box::new(writer))
+            .with_config(cfg)
+            .with_requester_api(api_request_rx);
+
+        Self {
+            api_request_tx,
+            task: tokio::spawn(proxy.run()),
+        }
+    }
+
+    /// Sender for the requester API of this side.
+    pub fn api_request_tx(&self) -> &mpsc::Sender<JmuxApiRequest> {
+        &self.api_request_tx
+    }
+
+    /// Requests a new channel to `destination_url`, and returns the raw API response.
+    pub async fn request_channel(&self, destination_url: &str) -> anyhow::Result<JmuxApiResponse> {
+        let destination_url = DestinationUrl::parse_str(destination_url)?;
+        let (api_response_tx, api_response_rx) = oneshot::channel();
+
+        self.api_request_tx
+            .send(JmuxApiRequest::OpenChannel {
+                destination_url,
+                api_response_tx,
+            })
+            .await
+            .context("JMUX proxy is stopped")?;
+
+        api_response_rx.await.context("JMUX proxy is stopped")
+    }
+
+    /// Opens a new channel to `destination_url`.
+    pub async fn open_channel(&self, destination_url: &str) -> anyhow::Result<LocalChannelId> {
+        match self.request_channel(destination_url).await? {
+            JmuxApiResponse::Success { id } => Ok(id),
+            JmuxApiResponse::Failure { id, reason_code } => {
+                anyhow::bail!("channel {id} to {destination_url} failed to open: {reason_code}")
+            }
+        }
+    }
+
+    /// Opens a new channel to `destination_url`, and returns the local end of the stream forwarded through it.
+    pub async fn open_stream(&self, destination_url: &str) -> anyhow::Result<TcpStream> {
+        let id = self.open_channel(destination_url).await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let local_stream = TcpStream::connect(listener.local_addr()?).await?;
+        let (proxied_stream, _) = listener.accept().await?;
+
+        self.api_request_tx
+            .send(JmuxApiRequest::Start {
+                id,
+                stream: proxied_stream,
+                leftover: None,
+            })
+            .await
+            .context("JMUX proxy is stopped")?;
+
+        Ok(local_stream)
+    }
+}
+
+impl Drop for JmuxEndpoint {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
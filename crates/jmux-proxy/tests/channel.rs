@@ -0,0 +1,2669 @@
+#![allow(unused_crate_dependencies)]
+#![allow(clippy::unwrap_used)]
+
+use jmux_proto::{
+    Bytes, BytesMut, Capabilities, ChannelData, ChannelOpen, DistantChannelId, Header, LocalChannelId, Message,
+    ReasonCode,
+};
+use jmux_proxy::{
+    ChannelDataBufferSize, ChannelLimitPolicy, ChannelStats, ConfigError, ConnectConcurrencyLimit, DataDirection,
+    DestinationUrl, FilteringRule, HostPattern, JmuxApiRequest, JmuxApiResponse, JmuxConfig, JmuxMetrics, JmuxProxy,
+    MetricsRecorder, OpenRetryPolicy, Resolver, ResolverConcurrencyLimit,
+};
+use std::collections::HashMap;
+use std::future::Future as _;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+struct ProxyPair {
+    /// API of the client side
+    api_request_tx: mpsc::Sender<JmuxApiRequest>,
+    client: JoinHandle<anyhow::Result<()>>,
+    server: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Spawns two JMUX proxies connected to each other.
+fn spawn_proxy_pair() -> ProxyPair {
+    spawn_proxy_pair_with(|server| server.with_config(JmuxConfig::permissive()))
+}
+
+/// Same as `spawn_proxy_pair`, but the server side is customized using `configure_server`.
+fn spawn_proxy_pair_with(configure_server: impl FnOnce(JmuxProxy) -> JmuxProxy) -> ProxyPair {
+    spawn_configured_proxy_pair(|client| client.with_config(JmuxConfig::client()), configure_server)
+}
+
+/// Same as `spawn_proxy_pair_with`, but the client side is customized as well using `configure_client`.
+fn spawn_configured_proxy_pair(
+    configure_client: impl FnOnce(JmuxProxy) -> JmuxProxy,
+    configure_server: impl FnOnce(JmuxProxy) -> JmuxProxy,
+) -> ProxyPair {
+    let (client_side, server_side) = tokio::io::duplex(64 * 1024);
+    let (api_request_tx, api_request_rx) = mpsc::channel(8);
+
+    let (client_reader, client_writer) = tokio::io::split(client_side);
+    let client = configure_client(JmuxProxy::new(Box::new(client_reader), Box::new(client_writer)))
+        .with_requester_api(api_request_rx);
+    let client = tokio::spawn(client.run());
+
+    let (server_reader, server_writer) = tokio::io::split(server_side);
+    let server = configure_server(JmuxProxy::new(Box::new(server_reader), Box::new(server_writer)));
+    let server = tokio::spawn(server.run());
+
+    ProxyPair {
+        api_request_tx,
+        client,
+        server,
+    }
+}
+
+async fn request_channel(api_request_tx: &mpsc::Sender<JmuxApiRequest>, destination_url: &str) -> JmuxApiResponse {
+    let (api_response_tx, api_response_rx) = oneshot::channel();
+
+    api_request_tx
+        .send(JmuxApiRequest::OpenChannel {
+            destination_url: DestinationUrl::parse_str(destination_url).unwrap(),
+            api_response_tx,
+        })
+        .await
+        .unwrap();
+
+    api_response_rx.await.unwrap()
+}
+
+/// Opens a channel to `destination_url` and returns the local end of the stream forwarded through it.
+async fn open_channel(api_request_tx: &mpsc::Sender<JmuxApiRequest>, destination_url: &str) -> TcpStream {
+    let JmuxApiResponse::Success { id } = request_channel(api_request_tx, destination_url).await else {
+        panic!("failed to open the channel");
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (proxied_stream, _) = listener.accept().await.unwrap();
+
+    api_request_tx
+        .send(JmuxApiRequest::Start {
+            id,
+            stream: proxied_stream,
+            leftover: None,
+        })
+        .await
+        .unwrap();
+
+    local_stream
+}
+
+#[tokio::test]
+async fn buffered_data_is_delivered_before_eof() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair();
+
+    let mut local_stream = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    let payload: Vec<u8> = (0..512 * 1024u32).map(|i| u8::try_from(i % 251).unwrap()).collect();
+
+    local_stream.write_all(&payload).await.unwrap();
+    local_stream.shutdown().await.unwrap();
+
+    let mut received = Vec::new();
+    tokio::time::timeout(TIMEOUT, target_stream.read_to_end(&mut received))
+        .await
+        .expect("EOF not received in time")
+        .unwrap();
+
+    assert_eq!(received.len(), payload.len());
+    assert!(received == payload);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn saturated_internal_signaling_does_not_deadlock() {
+    const NB_CHANNELS: usize = 64;
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    // Echo server.
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = target.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let metrics = Arc::new(JmuxMetrics::new());
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with({
+        let metrics = Arc::clone(&metrics);
+        move |server| {
+            server
+                .with_config(JmuxConfig {
+                    internal_channel_size: 1,
+                    ..JmuxConfig::permissive()
+                })
+                .with_metrics(metrics)
+        }
+    });
+
+    let destination_url = format!("tcp://{target_addr}");
+
+    let channels = (0..NB_CHANNELS).map(|_| {
+        let api_request_tx = api_request_tx.clone();
+        let destination_url = destination_url.clone();
+
+        tokio::spawn(async move {
+            let mut stream = open_channel(&api_request_tx, &destination_url).await;
+            stream.write_all(b"ping").await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).await.unwrap();
+            assert_eq!(echoed, b"ping");
+        })
+    });
+
+    tokio::time::timeout(TIMEOUT, futures_util::future::try_join_all(channels))
+        .await
+        .expect("deadlock")
+        .unwrap();
+
+    // The counter is readable at any time.
+    let _ = metrics.internal_channel_full();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn slow_target_does_not_stall_other_channels() {
+    // Largely more than what the socket buffers and the data queue of the channel can hold.
+    const SLOW_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
+
+    let slow_target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let slow_target_addr = slow_target.local_addr().unwrap();
+    let fast_target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let fast_target_addr = fast_target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server.with_config(JmuxConfig {
+            channel_data_buffer_size: ChannelDataBufferSize::Fixed(1),
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    // The slow target never reads.
+    let mut slow_stream = open_channel(&api_request_tx, &format!("tcp://{slow_target_addr}")).await;
+    let (_slow_target_stream, _) = slow_target.accept().await.unwrap();
+
+    let slow_writer = tokio::spawn(async move {
+        let _ = slow_stream.write_all(&vec![0; SLOW_PAYLOAD_SIZE]).await;
+    });
+
+    // Leave some time for the queues of the slow channel to fill up.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    tokio::time::timeout(TIMEOUT, async {
+        let mut fast_stream = open_channel(&api_request_tx, &format!("tcp://{fast_target_addr}")).await;
+        let (mut fast_target_stream, _) = fast_target.accept().await.unwrap();
+
+        fast_stream.write_all(b"hello").await.unwrap();
+
+        let mut received = [0; 5];
+        fast_target_stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+    })
+    .await
+    .expect("fast channel stalled by the slow one");
+
+    slow_writer.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn rapidly_reopened_channels_do_not_cross_talk() {
+    const NB_WORKERS: u32 = 8;
+    const NB_CHANNELS_PER_WORKER: u32 = 32;
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    // Echo server.
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = target.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair();
+    let destination_url = format!("tcp://{target_addr}");
+
+    // IDs of the closed channels are reused as soon as possible, so each channel must only see its own payload.
+    let workers = (0..NB_WORKERS).map(|worker| {
+        let api_request_tx = api_request_tx.clone();
+        let destination_url = destination_url.clone();
+
+        tokio::spawn(async move {
+            for i in 0..NB_CHANNELS_PER_WORKER {
+                let payload = format!("worker {worker}, channel {i}");
+
+                let mut stream = open_channel(&api_request_tx, &destination_url).await;
+                stream.write_all(payload.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+
+                let mut echoed = Vec::new();
+                stream.read_to_end(&mut echoed).await.unwrap();
+                assert_eq!(String::from_utf8(echoed).unwrap(), payload);
+            }
+        })
+    });
+
+    tokio::time::timeout(TIMEOUT, futures_util::future::try_join_all(workers))
+        .await
+        .expect("channels stalled")
+        .unwrap();
+}
+
+/// Resolver tracking the maximum number of resolutions in flight at the same time.
+#[derive(Default)]
+struct SlowResolver {
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+impl Resolver for SlowResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> futures_util::future::BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            let addrs = tokio::net::lookup_host((host, port)).await?;
+            Ok(addrs.collect())
+        })
+    }
+}
+
+#[tokio::test]
+async fn concurrent_connects_to_a_destination_are_bounded() {
+    const NB_OPENS: usize = 10;
+    const LIMIT: usize = 2;
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let resolver = Arc::new(SlowResolver::default());
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with({
+        let resolver = Arc::clone(&resolver);
+        move |server| {
+            server
+                .with_config(JmuxConfig {
+                    connect_concurrency_limit: Some(ConnectConcurrencyLimit {
+                        per_destination: LIMIT,
+                        queue_timeout: TIMEOUT,
+                    }),
+                    ..JmuxConfig::permissive()
+                })
+                .with_resolver(resolver)
+        }
+    });
+
+    let destination_url = format!("tcp://{target_addr}");
+
+    let responses = futures_util::future::join_all(
+        (0..NB_OPENS).map(|_| tokio::time::timeout(TIMEOUT, request_channel(&api_request_tx, &destination_url))),
+    )
+    .await;
+
+    for response in responses {
+        assert!(matches!(response.unwrap(), JmuxApiResponse::Success { .. }));
+    }
+
+    let max_in_flight = resolver.max_in_flight.load(Ordering::SeqCst);
+    assert!(max_in_flight <= LIMIT, "{max_in_flight} connects in flight");
+}
+
+#[tokio::test]
+async fn flood_of_opens_is_bounded() {
+    const NB_OPENS: usize = 10;
+    const MAX_IN_FLIGHT: usize = 2;
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let resolver = Arc::new(SlowResolver::default());
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with({
+        let resolver = Arc::clone(&resolver);
+        move |server| {
+            server
+                .with_config(JmuxConfig {
+                    resolver_concurrency_limit: Some(ResolverConcurrencyLimit {
+                        max_in_flight: MAX_IN_FLIGHT,
+                        max_queued: 2,
+                    }),
+                    ..JmuxConfig::permissive()
+                })
+                .with_resolver(resolver)
+        }
+    });
+
+    let destination_url = format!("tcp://{target_addr}");
+
+    let responses = futures_util::future::join_all(
+        (0..NB_OPENS).map(|_| tokio::time::timeout(TIMEOUT, request_channel(&api_request_tx, &destination_url))),
+    )
+    .await;
+
+    let (successes, failures): (Vec<_>, Vec<_>) = responses
+        .into_iter()
+        .map(|response| response.unwrap())
+        .partition(|response| matches!(response, JmuxApiResponse::Success { .. }));
+
+    // The excess openings are rejected right away.
+    assert!(!successes.is_empty());
+    assert!(!failures.is_empty());
+    assert!(failures.iter().all(|response| matches!(
+        response,
+        JmuxApiResponse::Failure {
+            reason_code: ReasonCode::GENERAL_FAILURE,
+            ..
+        }
+    )));
+
+    let max_in_flight = resolver.max_in_flight.load(Ordering::SeqCst);
+    assert!(max_in_flight <= MAX_IN_FLIGHT, "{max_in_flight} resolutions in flight");
+}
+
+#[tokio::test]
+async fn open_fails_when_connect_queue_timeout_is_elapsed() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server
+            .with_config(JmuxConfig {
+                connect_concurrency_limit: Some(ConnectConcurrencyLimit {
+                    per_destination: 1,
+                    queue_timeout: Duration::from_millis(10),
+                }),
+                ..JmuxConfig::permissive()
+            })
+            .with_resolver(Arc::new(SlowResolver::default()))
+    });
+
+    let destination_url = format!("tcp://{target_addr}");
+
+    let (first, second) = tokio::join!(
+        request_channel(&api_request_tx, &destination_url),
+        request_channel(&api_request_tx, &destination_url),
+    );
+
+    let failures = [first, second]
+        .into_iter()
+        .filter(|response| matches!(response, JmuxApiResponse::Failure { .. }))
+        .count();
+    assert_eq!(failures, 1);
+}
+
+#[tokio::test]
+async fn channels_above_the_limit_are_rejected() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server.with_config(JmuxConfig {
+            max_concurrent_channels: Some(1),
+            channel_limit_policy: ChannelLimitPolicy::Reject,
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let destination_url = format!("tcp://{target_addr}");
+
+    let _local_stream = open_channel(&api_request_tx, &destination_url).await;
+
+    let response = request_channel(&api_request_tx, &destination_url).await;
+    assert!(matches!(
+        response,
+        JmuxApiResponse::Failure {
+            reason_code: ReasonCode::GENERAL_FAILURE,
+            ..
+        }
+    ));
+}
+
+#[tokio::test]
+async fn queued_open_succeeds_once_another_channel_is_closed() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server.with_config(JmuxConfig {
+            max_concurrent_channels: Some(1),
+            channel_limit_policy: ChannelLimitPolicy::Queue {
+                timeout: TIMEOUT,
+                max_queued: 1,
+            },
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let destination_url = format!("tcp://{target_addr}");
+
+    let local_stream = open_channel(&api_request_tx, &destination_url).await;
+    let (target_stream, _) = target.accept().await.unwrap();
+
+    let (response, ()) = tokio::join!(request_channel(&api_request_tx, &destination_url), async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(local_stream);
+        drop(target_stream);
+    });
+
+    assert!(matches!(response, JmuxApiResponse::Success { .. }));
+}
+
+#[tokio::test]
+async fn queued_open_fails_when_queue_timeout_is_elapsed() {
+    const QUEUE_TIMEOUT: Duration = Duration::from_millis(50);
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server.with_config(JmuxConfig {
+            max_concurrent_channels: Some(1),
+            channel_limit_policy: ChannelLimitPolicy::Queue {
+                timeout: QUEUE_TIMEOUT,
+                max_queued: 1,
+            },
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let destination_url = format!("tcp://{target_addr}");
+
+    let _local_stream = open_channel(&api_request_tx, &destination_url).await;
+
+    let start = tokio::time::Instant::now();
+    let response = request_channel(&api_request_tx, &destination_url).await;
+
+    assert!(matches!(
+        response,
+        JmuxApiResponse::Failure {
+            reason_code: ReasonCode::GENERAL_FAILURE,
+            ..
+        }
+    ));
+    assert!(start.elapsed() >= QUEUE_TIMEOUT);
+}
+
+#[tokio::test]
+async fn open_denied_by_admission_hook_is_rejected() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = target.local_addr().unwrap().port();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server.with_config(
+            JmuxConfig::permissive().with_open_admission(Arc::new(|destination_url| {
+                let denied = destination_url.host() == "localhost";
+
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+
+                    if denied {
+                        Err(ReasonCode::AUTHORIZATION_REVOKED)
+                    } else {
+                        Ok(())
+                    }
+                })
+            })),
+        )
+    });
+
+    let denied = request_channel(&api_request_tx, &format!("tcp://localhost:{port}")).await;
+    assert!(matches!(
+        denied,
+        JmuxApiResponse::Failure {
+            reason_code: ReasonCode::AUTHORIZATION_REVOKED,
+            ..
+        }
+    ));
+
+    let admitted = request_channel(&api_request_tx, &format!("tcp://127.0.0.1:{port}")).await;
+    assert!(matches!(admitted, JmuxApiResponse::Success { .. }));
+}
+
+#[tokio::test]
+async fn open_to_port_zero_is_rejected() {
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair();
+
+    let response = tokio::time::timeout(TIMEOUT, request_channel(&api_request_tx, "tcp://127.0.0.1:0"))
+        .await
+        .unwrap();
+    assert!(matches!(
+        response,
+        JmuxApiResponse::Failure {
+            reason_code: ReasonCode::CONNECTION_NOT_ALLOWED_BY_RULESET,
+            ..
+        }
+    ));
+
+    // Even the most permissive rule doesn't allow it.
+    for rule in [FilteringRule::Allow, FilteringRule::port(0)] {
+        let error = rule.validate_destination_str("tcp://127.0.0.1:0").unwrap_err();
+        assert_eq!(error.to_string(), "port 0 is not a valid target");
+
+        let error = rule
+            .compile()
+            .validate_destination_str("tcp://127.0.0.1:0")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "port 0 is not a valid target");
+    }
+}
+
+#[tokio::test]
+async fn aliased_scheme_is_routed_to_its_target_scheme() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server.with_config(JmuxConfig {
+            scheme_aliases: HashMap::from([("ssh".to_owned(), "tcp".to_owned())]),
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let mut local_stream = open_channel(&api_request_tx, &format!("SSH://{target_addr}")).await;
+    let (mut target_stream, _) = tokio::time::timeout(TIMEOUT, target.accept()).await.unwrap().unwrap();
+
+    local_stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    target_stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test]
+async fn open_channels_can_be_queried() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair();
+
+    let mut local_stream = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    local_stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    target_stream.read_exact(&mut buf).await.unwrap();
+
+    target_stream.write_all(b"pong!").await.unwrap();
+    let mut buf = [0; 5];
+    local_stream.read_exact(&mut buf).await.unwrap();
+
+    let (api_response_tx, api_response_rx) = oneshot::channel();
+    api_request_tx
+        .send(JmuxApiRequest::QueryChannels { api_response_tx })
+        .await
+        .unwrap();
+    let stats = api_response_rx.await.unwrap();
+
+    let [ChannelStats {
+        target_host,
+        target_port,
+        bytes_tx,
+        bytes_rx,
+        window_size,
+        ..
+    }] = stats.as_slice()
+    else {
+        panic!("expected exactly one channel: {stats:?}");
+    };
+    assert_eq!(target_host, "127.0.0.1");
+    assert_eq!(*target_port, target_addr.port());
+    assert_eq!(*bytes_tx, 4);
+    assert_eq!(*bytes_rx, 5);
+    assert!(*window_size > 0);
+}
+
+#[tokio::test]
+async fn datagram_boundaries_are_preserved_over_udp_channels() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair();
+
+    let mut local_stream = open_channel(&api_request_tx, &format!("udp://{target_addr}")).await;
+
+    // Two datagrams written at once, followed by a third one split across two writes.
+    local_stream
+        .write_all(b"\x00\x05hello\x00\x06world!\x00")
+        .await
+        .unwrap();
+    local_stream.write_all(b"\x03bye").await.unwrap();
+
+    let mut buf = [0; 64];
+    let mut peer_addr = None;
+
+    for expected in [&b"hello"[..], b"world!", b"bye"] {
+        let (len, from) = tokio::time::timeout(TIMEOUT, target.recv_from(&mut buf))
+            .await
+            .expect("datagram not received in time")
+            .unwrap();
+        assert_eq!(&buf[..len], expected);
+        peer_addr = Some(from);
+    }
+
+    let peer_addr = peer_addr.unwrap();
+    target.send_to(b"pong", peer_addr).await.unwrap();
+    target.send_to(b"", peer_addr).await.unwrap();
+    target.send_to(b"pong!", peer_addr).await.unwrap();
+
+    let mut received = [0; 15];
+    tokio::time::timeout(TIMEOUT, local_stream.read_exact(&mut received))
+        .await
+        .expect("datagrams not received in time")
+        .unwrap();
+    assert_eq!(&received, b"\x00\x04pong\x00\x00\x00\x05pong!");
+}
+
+#[tokio::test]
+async fn rewritten_destination_is_connected_under_the_requested_name() {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(logs.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let internal_url = DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server.with_config(JmuxConfig {
+            destination_rewrites: vec![(HostPattern::Suffix("example.com".to_owned()), internal_url)],
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let mut local_stream = open_channel(&api_request_tx, "tcp://public.example.com:443").await;
+    let (mut target_stream, _) = tokio::time::timeout(TIMEOUT, target.accept()).await.unwrap().unwrap();
+
+    local_stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    target_stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    let logs = logs.contents();
+    let rewrite_line = logs
+        .lines()
+        .find(|line| line.contains("Destination rewritten"))
+        .expect("rewrite not logged");
+    assert!(rewrite_line.contains("url=tcp://public.example.com:443"), "{logs}");
+    assert!(
+        rewrite_line.contains(&format!("rewritten_url=tcp://{target_addr}")),
+        "{logs}"
+    );
+}
+
+#[test]
+fn host_patterns() {
+    let exact = HostPattern::Exact("public.example.com".to_owned());
+    assert!(exact.matches("PUBLIC.example.com"));
+    assert!(!exact.matches("www.public.example.com"));
+
+    let suffix = HostPattern::Suffix("example.com".to_owned());
+    assert!(suffix.matches("example.com"));
+    assert!(suffix.matches("public.EXAMPLE.com"));
+    assert!(!suffix.matches("publicexample.com"));
+    assert!(!suffix.matches("example.org"));
+}
+
+#[tokio::test]
+async fn channels_are_closed_when_ttl_elapses() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair {
+        api_request_tx, server, ..
+    } = spawn_proxy_pair_with(|server| {
+        server
+            .with_config(JmuxConfig::permissive())
+            .with_ttl(Duration::from_millis(200))
+    });
+
+    let mut local_stream = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    local_stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0; 5];
+    target_stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Both ends of the channel are closed.
+    let mut rest = Vec::new();
+    tokio::time::timeout(TIMEOUT, local_stream.read_to_end(&mut rest))
+        .await
+        .expect("local stream not closed in time")
+        .unwrap();
+    tokio::time::timeout(TIMEOUT, target_stream.read_to_end(&mut rest))
+        .await
+        .expect("target stream not closed in time")
+        .unwrap();
+    assert!(rest.is_empty());
+
+    // The server side stops on its own.
+    tokio::time::timeout(TIMEOUT, server)
+        .await
+        .expect("server not stopped in time")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn transport_is_reclaimed_after_graceful_shutdown() {
+    let (proxy_side, mut peer) = tokio::io::duplex(64 * 1024);
+
+    let (proxy_reader, proxy_writer) = tokio::io::split(proxy_side);
+    let proxy = JmuxProxy::new(Box::new(proxy_reader), Box::new(proxy_writer))
+        .with_config(JmuxConfig::permissive())
+        .with_ttl(Duration::from_millis(200));
+    let proxy = tokio::spawn(proxy.run_reclaim());
+
+    // A partial frame, not yet processed when the session is stopped.
+    let partial = Message::eof(DistantChannelId::from(1)).encode_to_vec().unwrap();
+    peer.write_all(&partial[..2]).await.unwrap();
+
+    let (mut reader, mut writer) = tokio::time::timeout(TIMEOUT, proxy)
+        .await
+        .expect("proxy not stopped in time")
+        .unwrap()
+        .unwrap();
+
+    // The transport is now usable as a raw stream, in both directions.
+    peer.write_all(b"raw").await.unwrap();
+    let mut buf = [0; 5];
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf[..2], partial[..2]);
+    assert_eq!(&buf[2..], b"raw");
+
+    writer.write_all(b"pong").await.unwrap();
+    writer.flush().await.unwrap();
+    let mut buf = [0; 4];
+    peer.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[tokio::test]
+async fn accepted_channel_without_activity_is_reaped() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server.with_config(JmuxConfig {
+            accept_idle_timeout: Some(Duration::from_millis(200)),
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    // The channel is opened, but never started: the peer doesn't send anything.
+    let response = request_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    assert!(matches!(response, JmuxApiResponse::Success { .. }));
+
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    let mut rest = Vec::new();
+    tokio::time::timeout(TIMEOUT, target_stream.read_to_end(&mut rest))
+        .await
+        .expect("target stream not closed in time")
+        .unwrap();
+    assert!(rest.is_empty());
+}
+
+/// Log output captured in memory.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for CapturedLogs {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[tokio::test]
+async fn destination_host_is_redacted_in_logs() {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(logs.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+
+    let (client_side, server_side) = tokio::io::duplex(64 * 1024);
+    let (api_request_tx, api_request_rx) = mpsc::channel(8);
+
+    let (client_reader, client_writer) = tokio::io::split(client_side);
+    let client = JmuxProxy::new(Box::new(client_reader), Box::new(client_writer))
+        .with_config(JmuxConfig {
+            redact_destination_in_logs: true,
+            ..JmuxConfig::client()
+        })
+        .with_requester_api(api_request_rx);
+    tokio::spawn(client.run());
+
+    let (server_reader, server_writer) = tokio::io::split(server_side);
+    let server = JmuxProxy::new(Box::new(server_reader), Box::new(server_writer)).with_config(JmuxConfig {
+        redact_destination_in_logs: true,
+        ..JmuxConfig::permissive()
+    });
+    tokio::spawn(server.run());
+
+    let response = request_channel(&api_request_tx, &format!("tcp://127.0.0.1:{target_port}")).await;
+    assert!(matches!(response, JmuxApiResponse::Success { .. }));
+
+    // Connection refused.
+    let response = request_channel(&api_request_tx, "tcp://127.0.0.1:1").await;
+    assert!(matches!(response, JmuxApiResponse::Failure { .. }));
+
+    let logs = logs.contents();
+    assert!(logs.contains("tcp://redacted-"), "{logs}");
+    assert!(!logs.contains("127.0.0.1"), "{logs}");
+}
+
+#[tokio::test]
+async fn label_is_attached_to_the_proxy_logs() {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(logs.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+
+    let (client_side, server_side) = tokio::io::duplex(64 * 1024);
+    let (api_request_tx, api_request_rx) = mpsc::channel(8);
+
+    let (client_reader, client_writer) = tokio::io::split(client_side);
+    let client = JmuxProxy::new(Box::new(client_reader), Box::new(client_writer))
+        .with_config(JmuxConfig::client())
+        .with_requester_api(api_request_rx)
+        .with_label("session-client");
+    tokio::spawn(client.run());
+
+    let (server_reader, server_writer) = tokio::io::split(server_side);
+    let server = JmuxProxy::new(Box::new(server_reader), Box::new(server_writer))
+        .with_config(JmuxConfig::permissive())
+        .with_label("session-server");
+    tokio::spawn(server.run());
+
+    let response = request_channel(&api_request_tx, &format!("tcp://127.0.0.1:{target_port}")).await;
+    assert!(matches!(response, JmuxApiResponse::Success { .. }));
+
+    let logs = logs.contents();
+    let scheduler_logs = logs
+        .lines()
+        .filter(|line| line.contains("scheduler"))
+        .collect::<Vec<_>>();
+
+    assert!(
+        scheduler_logs.iter().any(|line| line.contains("label=session-client")),
+        "{logs}"
+    );
+    assert!(
+        scheduler_logs.iter().any(|line| line.contains("label=session-server")),
+        "{logs}"
+    );
+    assert!(
+        scheduler_logs.iter().all(|line| line.contains("label=session-")),
+        "{logs}"
+    );
+}
+
+#[tokio::test]
+async fn data_after_eof_is_dropped() {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_ansi(false)
+        .with_writer(logs.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (_api_request_tx, mut peer) =
+        spawn_client_with_raw_peer_with(|proxy| proxy.with_config(JmuxConfig::permissive()));
+
+    let destination_url = DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap();
+    write_message(&mut peer, Message::open(LocalChannelId::from(1), 4096, destination_url)).await;
+
+    let Message::OpenSuccess(open_success) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    let (mut target_stream, _) = tokio::time::timeout(TIMEOUT, target.accept()).await.unwrap().unwrap();
+
+    let id = DistantChannelId::from(open_success.sender_channel_id);
+    write_message(&mut peer, Message::data(id, Bytes::from_static(b"hello"))).await;
+    write_message(&mut peer, Message::eof(id)).await;
+    write_message(&mut peer, Message::data(id, Bytes::from_static(b"late"))).await;
+
+    let mut received = Vec::new();
+    tokio::time::timeout(TIMEOUT, target_stream.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, b"hello");
+
+    tokio::time::timeout(TIMEOUT, async {
+        while !logs
+            .contents()
+            .contains("Received data after distant peer EOFed; dropped")
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn late_data_after_local_close_is_dropped_without_warning() {
+    const LATE_DATA_LOG: &str = "Received data for a recently closed channel; dropped";
+
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(logs.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (_api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|proxy| {
+        proxy.with_config(JmuxConfig {
+            accept_idle_timeout: Some(Duration::from_millis(10)),
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let destination_url = DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap();
+    write_message(&mut peer, Message::open(LocalChannelId::from(1), 4096, destination_url)).await;
+
+    let Message::OpenSuccess(open_success) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    let (_target_stream, _) = tokio::time::timeout(TIMEOUT, target.accept()).await.unwrap().unwrap();
+
+    // The channel is closed locally, as the peer stays idle for too long.
+    let Message::Close(_) = tokio::time::timeout(TIMEOUT, read_message(&mut peer)).await.unwrap() else {
+        panic!("expected CHANNEL CLOSE");
+    };
+
+    // Sent by the peer before it learned about the closure.
+    let id = DistantChannelId::from(open_success.sender_channel_id);
+    write_message(&mut peer, Message::data(id, Bytes::from_static(b"late"))).await;
+
+    tokio::time::timeout(TIMEOUT, async {
+        while !logs.contents().contains(LATE_DATA_LOG) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let logs = logs.contents();
+    let late_data_log = logs.lines().find(|line| line.contains(LATE_DATA_LOG)).unwrap();
+    assert!(late_data_log.contains("DEBUG"), "{logs}");
+    assert!(!logs.contains("data sender is missing"), "{logs}");
+    assert!(!logs.contains("Couldn’t find channel"), "{logs}");
+}
+
+#[tokio::test]
+async fn user_controlled_values_are_escaped_and_truncated_in_logs() {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_ansi(false)
+        .with_writer(logs.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|client| {
+        client.with_config(JmuxConfig {
+            max_logged_value_len: 32,
+            ..JmuxConfig::client()
+        })
+    });
+
+    let (api_response_tx, api_response_rx) = oneshot::channel();
+    api_request_tx
+        .send(JmuxApiRequest::OpenChannel {
+            destination_url: DestinationUrl::parse_str_lenient("tcp://evil\nFORGED:80").unwrap(),
+            api_response_tx,
+        })
+        .await
+        .unwrap();
+
+    // The CHANNEL OPEN message is read raw, because the lenient destination URL is rejected by the decoder.
+    let mut frame = vec![0; Header::SIZE];
+    peer.read_exact(&mut frame).await.unwrap();
+    let size = usize::from(u16::from_be_bytes([frame[1], frame[2]]));
+    frame.resize(size, 0);
+    peer.read_exact(&mut frame[Header::SIZE..]).await.unwrap();
+    let sender_id = u32::from_be_bytes(frame[Header::SIZE..Header::SIZE + 4].try_into().unwrap());
+
+    let description = format!("refused\nFORGED{}", "x".repeat(100));
+    write_message(
+        &mut peer,
+        Message::open_failure(
+            DistantChannelId::from(sender_id),
+            ReasonCode::GENERAL_FAILURE,
+            description,
+        ),
+    )
+    .await;
+
+    let response = tokio::time::timeout(TIMEOUT, api_response_rx).await.unwrap().unwrap();
+    assert!(matches!(response, JmuxApiResponse::Failure { .. }));
+
+    let logs = logs.contents();
+    assert!(logs.contains("tcp://evil\\nFORGED:80"), "{logs}");
+    assert!(logs.contains("refused\\nFORGED"), "{logs}");
+    assert!(logs.contains("…(82 more characters)"), "{logs}");
+    assert!(!logs.contains("\nFORGED"), "{logs}");
+}
+
+#[tokio::test]
+async fn local_address_of_the_target_connection_is_logged() {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(logs.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (_api_request_tx, mut peer) =
+        spawn_client_with_raw_peer_with(|proxy| proxy.with_config(JmuxConfig::permissive()));
+
+    let destination_url = DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap();
+    write_message(&mut peer, Message::open(LocalChannelId::from(1), 4096, destination_url)).await;
+
+    let Message::OpenSuccess(_) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    let (_target_stream, proxy_addr) = tokio::time::timeout(TIMEOUT, target.accept()).await.unwrap().unwrap();
+
+    let logs = logs.contents();
+    assert!(logs.contains(&format!("local_addr={proxy_addr}")), "{logs}");
+}
+
+#[tokio::test]
+async fn excessive_window_advertised_in_open_is_clamped() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (_api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|proxy| {
+        proxy.with_config(JmuxConfig {
+            max_initial_window_size: 64 * 1024,
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let destination_url = DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap();
+    let mut open = ChannelOpen::new(LocalChannelId::from(1), 4096, destination_url);
+    open.initial_window_size = u32::MAX - 1;
+    write_message(&mut peer, Message::Open(open)).await;
+
+    let Message::OpenSuccess(open_success) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    assert_eq!(open_success.initial_window_size, 64 * 1024);
+}
+
+#[tokio::test]
+async fn excessive_window_advertised_in_open_success_is_clamped() {
+    const MAX_WINDOW_SIZE: u32 = 4096;
+
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|client| {
+        client.with_config(JmuxConfig {
+            max_initial_window_size: MAX_WINDOW_SIZE,
+            ..JmuxConfig::client()
+        })
+    });
+
+    let (api_response_tx, api_response_rx) = oneshot::channel();
+    api_request_tx
+        .send(JmuxApiRequest::OpenChannel {
+            destination_url: DestinationUrl::parse_str("tcp://127.0.0.1:80").unwrap(),
+            api_response_tx,
+        })
+        .await
+        .unwrap();
+
+    let Message::Open(open) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN");
+    };
+    let client_id = DistantChannelId::from(open.sender_channel_id);
+    write_message(
+        &mut peer,
+        Message::open_success(client_id, LocalChannelId::from(7), u32::MAX - 1, 1024),
+    )
+    .await;
+
+    let JmuxApiResponse::Success { id } = tokio::time::timeout(TIMEOUT, api_response_rx).await.unwrap().unwrap() else {
+        panic!("failed to open the channel");
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut local_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (proxied_stream, _) = listener.accept().await.unwrap();
+    api_request_tx
+        .send(JmuxApiRequest::Start {
+            id,
+            stream: proxied_stream,
+            leftover: None,
+        })
+        .await
+        .unwrap();
+
+    local_stream.write_all(&[0; 64 * 1024]).await.unwrap();
+
+    // Only the clamped window is sent until the peer adjusts it.
+    let mut received = 0;
+    while let Ok(message) = tokio::time::timeout(Duration::from_millis(200), read_message(&mut peer)).await {
+        let Message::Data(data) = message else {
+            panic!("expected CHANNEL DATA");
+        };
+        received += data.transfer_data.len();
+    }
+    assert_eq!(received, usize::try_from(MAX_WINDOW_SIZE).unwrap());
+
+    // Sending resumes once the peer adjusts the window.
+    write_message(&mut peer, Message::window_adjust(client_id, 1024)).await;
+    let Message::Data(_) = tokio::time::timeout(TIMEOUT, read_message(&mut peer)).await.unwrap() else {
+        panic!("expected CHANNEL DATA");
+    };
+}
+
+#[tokio::test]
+async fn window_consumed_by_oversized_data_is_credited_back() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let metrics = Arc::new(JmuxMetrics::new());
+
+    let (_api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|proxy| {
+        proxy
+            .with_config(JmuxConfig::permissive())
+            .with_metrics(Arc::clone(&metrics))
+    });
+
+    let destination_url = DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap();
+    write_message(&mut peer, Message::open(LocalChannelId::from(1), 64, destination_url)).await;
+
+    let Message::OpenSuccess(open_success) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    let id = DistantChannelId::from(open_success.sender_channel_id);
+
+    for _ in 0..5 {
+        write_message(&mut peer, Message::data(id, Bytes::from(vec![0; 1000]))).await;
+    }
+
+    let Message::WindowAdjust(window_adjust) = tokio::time::timeout(TIMEOUT, read_message(&mut peer)).await.unwrap()
+    else {
+        panic!("expected CHANNEL WINDOW ADJUST");
+    };
+    assert_eq!(window_adjust.window_adjustment, 5000);
+    assert_eq!(metrics.oversized_data_dropped(), 5);
+}
+
+#[tokio::test]
+async fn duplicated_data_is_detected_using_sequence_numbers() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (_api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|proxy| {
+        proxy.with_config(JmuxConfig {
+            sequence_data: true,
+            advertise_capabilities: true,
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let Message::Capabilities(_) = read_message(&mut peer).await else {
+        panic!("expected CAPABILITIES");
+    };
+    write_message(&mut peer, Message::capabilities(Capabilities::SEQUENCE_NUMBERS)).await;
+
+    let destination_url = DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap();
+    write_message(&mut peer, Message::open(LocalChannelId::from(1), 4096, destination_url)).await;
+
+    let Message::OpenSuccess(open_success) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    let (mut target_stream, _) = tokio::time::timeout(TIMEOUT, target.accept()).await.unwrap().unwrap();
+    let id = DistantChannelId::from(open_success.sender_channel_id);
+
+    // The DATA messages sent by the proxy are numbered.
+    target_stream.write_all(b"pong").await.unwrap();
+    let Message::Data(data) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL DATA");
+    };
+    assert_eq!(data.sequence_number, Some(0));
+    assert_eq!(&data.transfer_data[..], b"pong");
+
+    let sequenced_data = |sequence_number: u32, payload: &'static [u8]| {
+        Message::Data(ChannelData::new(id, Bytes::from_static(payload)).with_sequence_number(sequence_number))
+    };
+    write_message(&mut peer, sequenced_data(0, b"hello")).await;
+    write_message(&mut peer, sequenced_data(1, b" world")).await;
+    write_message(&mut peer, sequenced_data(1, b" world")).await;
+
+    // The duplicate is not forwarded, and the channel is closed.
+    let Message::Close(_) = tokio::time::timeout(TIMEOUT, read_message(&mut peer)).await.unwrap() else {
+        panic!("expected CHANNEL CLOSE");
+    };
+
+    let mut received = Vec::new();
+    tokio::time::timeout(TIMEOUT, target_stream.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, b"hello world");
+}
+
+/// Spawns a client JMUX proxy whose peer is driven manually by the test.
+fn spawn_client_with_raw_peer() -> (mpsc::Sender<JmuxApiRequest>, DuplexStream) {
+    spawn_client_with_raw_peer_with(|client| client)
+}
+
+/// Same as `spawn_client_with_raw_peer`, but the client is customized using `configure_client`.
+fn spawn_client_with_raw_peer_with(
+    configure_client: impl FnOnce(JmuxProxy) -> JmuxProxy,
+) -> (mpsc::Sender<JmuxApiRequest>, DuplexStream) {
+    let (client_side, peer_side) = tokio::io::duplex(64 * 1024);
+    let (api_request_tx, api_request_rx) = mpsc::channel(8);
+
+    let (client_reader, client_writer) = tokio::io::split(client_side);
+    let client = JmuxProxy::new(Box::new(client_reader), Box::new(client_writer))
+        .with_config(JmuxConfig::client())
+        .with_requester_api(api_request_rx);
+    let client = configure_client(client);
+    tokio::spawn(client.run());
+
+    (api_request_tx, peer_side)
+}
+
+async fn read_frame(peer: &mut DuplexStream) -> Bytes {
+    let mut frame = vec![0; Header::SIZE];
+    peer.read_exact(&mut frame).await.unwrap();
+
+    let size = usize::from(u16::from_be_bytes([frame[1], frame[2]]));
+    frame.resize(size, 0);
+    peer.read_exact(&mut frame[Header::SIZE..]).await.unwrap();
+
+    frame.into()
+}
+
+async fn read_message(peer: &mut DuplexStream) -> Message {
+    Message::decode(read_frame(peer).await).unwrap()
+}
+
+async fn write_message(peer: &mut DuplexStream, message: Message) {
+    peer.write_all(&message.encode_to_vec().unwrap()).await.unwrap();
+}
+
+#[tokio::test]
+async fn duplicated_open_success_is_ignored() {
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer();
+
+    let response = tokio::spawn({
+        let api_request_tx = api_request_tx.clone();
+        async move { request_channel(&api_request_tx, "tcp://127.0.0.1:80").await }
+    });
+
+    let Message::Open(open) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN");
+    };
+
+    for _ in 0..2 {
+        write_message(
+            &mut peer,
+            Message::open_success(
+                DistantChannelId::from(open.sender_channel_id),
+                LocalChannelId::from(7),
+                1024,
+                1024,
+            ),
+        )
+        .await;
+    }
+
+    let response = tokio::time::timeout(TIMEOUT, response).await.unwrap().unwrap();
+    assert!(matches!(response, JmuxApiResponse::Success { .. }));
+
+    // The proxy is still alive and able to open new channels.
+    let response = tokio::spawn(async move { request_channel(&api_request_tx, "tcp://127.0.0.1:80").await });
+    assert!(matches!(read_message(&mut peer).await, Message::Open(_)));
+    drop(response);
+}
+
+#[derive(Default)]
+struct CountingRecorder {
+    opened: AtomicUsize,
+    closed: AtomicUsize,
+    bytes_tx: AtomicU64,
+    bytes_rx: AtomicU64,
+    window_exhausted: AtomicUsize,
+}
+
+impl MetricsRecorder for CountingRecorder {
+    fn channel_opened(&self, _: LocalChannelId) {
+        self.opened.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn channel_closed(&self, _: LocalChannelId) {
+        self.closed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn bytes_forwarded(&self, direction: DataDirection, n: u64) {
+        match direction {
+            DataDirection::Tx => self.bytes_tx.fetch_add(n, Ordering::SeqCst),
+            DataDirection::Rx => self.bytes_rx.fetch_add(n, Ordering::SeqCst),
+        };
+    }
+
+    fn window_exhausted(&self, _: LocalChannelId) {
+        self.window_exhausted.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[tokio::test]
+async fn protocol_events_are_reported_to_the_metrics_recorder() {
+    const PAYLOAD_SIZE: usize = 256 * 1024;
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let recorder = Arc::new(CountingRecorder::default());
+
+    // The window advertised by the client is much smaller than the payload sent by the target.
+    let ProxyPair { api_request_tx, .. } = spawn_configured_proxy_pair(
+        |client| {
+            client
+                .with_config(JmuxConfig::client())
+                .with_initial_window_size(16 * 1024)
+        },
+        {
+            let recorder = Arc::clone(&recorder);
+            move |server| {
+                server
+                    .with_config(JmuxConfig::permissive())
+                    .with_metrics_recorder(recorder)
+            }
+        },
+    );
+
+    let mut local_stream = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (mut target_stream, _) = tokio::time::timeout(TIMEOUT, target.accept()).await.unwrap().unwrap();
+
+    local_stream.write_all(b"ping").await.unwrap();
+    local_stream.shutdown().await.unwrap();
+
+    let mut received = Vec::new();
+    target_stream.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"ping");
+
+    target_stream.write_all(&[0xAB; PAYLOAD_SIZE]).await.unwrap();
+    target_stream.shutdown().await.unwrap();
+
+    let mut received = Vec::new();
+    tokio::time::timeout(TIMEOUT, local_stream.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.len(), PAYLOAD_SIZE);
+
+    tokio::time::timeout(TIMEOUT, async {
+        while recorder.closed.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("channel not closed in time");
+
+    assert_eq!(recorder.opened.load(Ordering::SeqCst), 1);
+    assert_eq!(recorder.closed.load(Ordering::SeqCst), 1);
+    assert_eq!(recorder.bytes_rx.load(Ordering::SeqCst), 4);
+    assert_eq!(
+        recorder.bytes_tx.load(Ordering::SeqCst),
+        u64::try_from(PAYLOAD_SIZE).unwrap()
+    );
+    assert!(recorder.window_exhausted.load(Ordering::SeqCst) > 0);
+}
+
+#[tokio::test]
+async fn configured_packet_and_window_sizes_are_used() {
+    const MAXIMUM_PACKET_SIZE: u16 = 1024;
+    const INITIAL_WINDOW_SIZE: u32 = 256 * 1024;
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|proxy| {
+        proxy
+            .with_config(JmuxConfig::permissive())
+            .with_maximum_packet_size(MAXIMUM_PACKET_SIZE)
+            .with_initial_window_size(INITIAL_WINDOW_SIZE)
+    });
+
+    // The sizes are advertised when requesting a channel.
+    let response = tokio::spawn(async move { request_channel(&api_request_tx, "tcp://127.0.0.1:80").await });
+    let Message::Open(open) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN");
+    };
+    assert_eq!(open.maximum_packet_size, MAXIMUM_PACKET_SIZE);
+    assert_eq!(open.initial_window_size, INITIAL_WINDOW_SIZE);
+    drop(response);
+
+    // A larger packet size advertised by the peer is lowered to the configured one.
+    write_message(
+        &mut peer,
+        Message::open(
+            LocalChannelId::from(1),
+            32 * 1024,
+            DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap(),
+        ),
+    )
+    .await;
+
+    let Message::OpenSuccess(open_success) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    assert_eq!(open_success.maximum_packet_size, MAXIMUM_PACKET_SIZE);
+
+    let (mut target_stream, _) = target.accept().await.unwrap();
+    target_stream.write_all(&[0xAB; 8 * 1024]).await.unwrap();
+
+    let mut received = 0;
+    while received < 8 * 1024 {
+        let message = tokio::time::timeout(TIMEOUT, read_message(&mut peer)).await.unwrap();
+        assert!(message.size() <= usize::from(MAXIMUM_PACKET_SIZE));
+
+        let Message::Data(data) = message else {
+            panic!("expected CHANNEL DATA");
+        };
+        received += data.transfer_data.len();
+    }
+}
+
+#[tokio::test]
+async fn too_small_maximum_packet_size_is_rejected_at_start() {
+    let (proxy_side, _peer) = tokio::io::duplex(1024);
+    let (proxy_reader, proxy_writer) = tokio::io::split(proxy_side);
+
+    let error = JmuxProxy::new(Box::new(proxy_reader), Box::new(proxy_writer))
+        .with_maximum_packet_size(8)
+        .run()
+        .await
+        .unwrap_err();
+
+    assert!(format!("{error:#}").contains("maximum packet size"));
+}
+
+/// JMUX writer failing all the writes once broken, and notifying when dropped.
+struct BreakableWriter {
+    inner: tokio::io::WriteHalf<DuplexStream>,
+    broken: Arc<AtomicBool>,
+    _dropped: oneshot::Sender<()>,
+}
+
+impl AsyncWrite for BreakableWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.broken.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn leftover_delivery_failure_aborts_the_channel_start() {
+    let (client_side, mut peer) = tokio::io::duplex(64 * 1024);
+    let (api_request_tx, api_request_rx) = mpsc::channel(8);
+    let broken = Arc::new(AtomicBool::new(false));
+    let (dropped_tx, dropped_rx) = oneshot::channel::<()>();
+
+    let (client_reader, client_writer) = tokio::io::split(client_side);
+    let client_writer = BreakableWriter {
+        inner: client_writer,
+        broken: Arc::clone(&broken),
+        _dropped: dropped_tx,
+    };
+    let client = JmuxProxy::new(Box::new(client_reader), Box::new(client_writer))
+        .with_config(JmuxConfig::client())
+        .with_requester_api(api_request_rx);
+    let client = tokio::spawn(client.run());
+
+    let response = tokio::spawn({
+        let api_request_tx = api_request_tx.clone();
+        async move { request_channel(&api_request_tx, "tcp://127.0.0.1:80").await }
+    });
+
+    let Message::Open(open) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN");
+    };
+    write_message(
+        &mut peer,
+        Message::open_success(
+            DistantChannelId::from(open.sender_channel_id),
+            LocalChannelId::from(7),
+            1024,
+            1024,
+        ),
+    )
+    .await;
+
+    let response = tokio::time::timeout(TIMEOUT, response).await.unwrap().unwrap();
+    let JmuxApiResponse::Success { id } = response else {
+        panic!("failed to open the channel");
+    };
+
+    // Make the sender task fail on its next write, caused by the CHANNEL OPEN FAILURE sent for this denied opening.
+    broken.store(true, Ordering::SeqCst);
+    let destination_url = DestinationUrl::parse_str("tcp://127.0.0.1:80").unwrap();
+    write_message(&mut peer, Message::open(LocalChannelId::from(8), 1024, destination_url)).await;
+    tokio::time::timeout(TIMEOUT, dropped_rx).await.unwrap().unwrap_err();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut local_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (proxied_stream, _) = listener.accept().await.unwrap();
+
+    api_request_tx
+        .send(JmuxApiRequest::Start {
+            id,
+            stream: proxied_stream,
+            leftover: Some(Bytes::from_static(b"leftover")),
+        })
+        .await
+        .unwrap();
+
+    let error = tokio::time::timeout(TIMEOUT, client)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap_err();
+    assert!(
+        format!("{error:#}").contains("couldn’t send leftover bytes"),
+        "{error:#}"
+    );
+
+    // The stream was not handed over to the channel tasks.
+    let mut buf = [0; 8];
+    let n = tokio::time::timeout(TIMEOUT, local_stream.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(n, 0);
+}
+
+#[tokio::test]
+async fn outstanding_bytes_cap_applies_backpressure() {
+    const SOFT_CAP: u64 = 32 * 1024;
+    const PAYLOAD_SIZE: usize = 1024 * 1024;
+
+    let metrics = Arc::new(JmuxMetrics::new());
+
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer_with({
+        let metrics = Arc::clone(&metrics);
+        move |client| {
+            client
+                .with_config(JmuxConfig {
+                    max_outstanding_bytes: Some(SOFT_CAP),
+                    ..JmuxConfig::client()
+                })
+                .with_metrics(metrics)
+        }
+    });
+
+    let local_stream = tokio::spawn({
+        let api_request_tx = api_request_tx.clone();
+        async move { open_channel(&api_request_tx, "tcp://127.0.0.1:80").await }
+    });
+
+    let Message::Open(open) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN");
+    };
+    write_message(
+        &mut peer,
+        Message::open_success(
+            DistantChannelId::from(open.sender_channel_id),
+            LocalChannelId::from(7),
+            ChannelOpen::DEFAULT_INITIAL_WINDOW_SIZE,
+            4096,
+        ),
+    )
+    .await;
+
+    let mut local_stream = tokio::time::timeout(TIMEOUT, local_stream).await.unwrap().unwrap();
+
+    // The peer is not reading the pipe for now, so the data piles up in the client.
+    let writer = tokio::spawn(async move {
+        local_stream.write_all(&vec![0xAB; PAYLOAD_SIZE]).await.unwrap();
+        local_stream
+    });
+
+    tokio::time::timeout(TIMEOUT, async {
+        while metrics.outstanding_bytes_cap_reached() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("backpressure not engaged");
+
+    // Reading from the stream is paused: the outstanding bytes stay close to the cap instead of filling the queues
+    // (the data not read yet is kept by the socket buffers).
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let outstanding_bytes = metrics.outstanding_bytes();
+    assert!(
+        outstanding_bytes <= SOFT_CAP + 64 * 1024,
+        "{outstanding_bytes} bytes outstanding"
+    );
+
+    // Everything is forwarded once the peer reads again.
+    let mut received = 0;
+    tokio::time::timeout(TIMEOUT, async {
+        while received < PAYLOAD_SIZE {
+            if let Message::Data(data) = read_message(&mut peer).await {
+                received += data.transfer_data.len();
+            }
+        }
+    })
+    .await
+    .expect("data not forwarded after backpressure");
+
+    let _local_stream = tokio::time::timeout(TIMEOUT, writer).await.unwrap().unwrap();
+    assert_eq!(received, PAYLOAD_SIZE);
+}
+
+/// Opens `nb_channels` channels from a client driven by a raw peer granting `initial_window_size` bytes to each, and
+/// starts writing `payload_size` bytes to each of them.
+async fn open_loaded_channels(
+    api_request_tx: &mpsc::Sender<JmuxApiRequest>,
+    peer: &mut DuplexStream,
+    nb_channels: u32,
+    initial_window_size: u32,
+    payload_size: usize,
+) -> HashMap<LocalChannelId, DistantChannelId> {
+    let mut channels = HashMap::new();
+    let mut local_streams = Vec::new();
+
+    for i in 0..nb_channels {
+        let local_stream = tokio::spawn({
+            let api_request_tx = api_request_tx.clone();
+            async move { open_channel(&api_request_tx, "tcp://127.0.0.1:80").await }
+        });
+
+        let Message::Open(open) = read_message(peer).await else {
+            panic!("expected CHANNEL OPEN");
+        };
+        let peer_id = LocalChannelId::from(100 + i);
+        let client_id = DistantChannelId::from(open.sender_channel_id);
+        write_message(
+            peer,
+            Message::open_success(client_id, peer_id, initial_window_size, 4096),
+        )
+        .await;
+        channels.insert(peer_id, client_id);
+
+        local_streams.push(tokio::time::timeout(TIMEOUT, local_stream).await.unwrap().unwrap());
+    }
+
+    // Written once all the channels are open, so the peer only receives data from now on.
+    for mut local_stream in local_streams {
+        tokio::spawn(async move {
+            // Fails when the channel is closed in the meantime.
+            let _ = local_stream.write_all(&vec![0xAB; payload_size]).await;
+            local_stream
+        });
+    }
+
+    channels
+}
+
+#[tokio::test]
+async fn data_waiting_for_the_window_does_not_pause_pipe_reads() {
+    const SOFT_CAP: u64 = 16 * 1024;
+    const NB_CHANNELS: u32 = 6;
+    const INITIAL_WINDOW_SIZE: u32 = 1024;
+    const PAYLOAD_SIZE: usize = 64 * 1024;
+
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|client| {
+        client.with_config(JmuxConfig {
+            max_outstanding_bytes: Some(SOFT_CAP),
+            ..JmuxConfig::client()
+        })
+    });
+
+    let channels = open_loaded_channels(
+        &api_request_tx,
+        &mut peer,
+        NB_CHANNELS,
+        INITIAL_WINDOW_SIZE,
+        PAYLOAD_SIZE,
+    )
+    .await;
+
+    // Much more data than the cap is read from the targets while the channels wait for the window, yet the
+    // WINDOW ADJUST messages are still processed.
+    let mut received: HashMap<LocalChannelId, usize> = HashMap::new();
+    tokio::time::timeout(TIMEOUT, async {
+        while received.values().sum::<usize>() < PAYLOAD_SIZE * channels.len() {
+            let Message::Data(data) = read_message(&mut peer).await else {
+                continue;
+            };
+
+            let peer_id = LocalChannelId::from(data.recipient_channel_id);
+            *received.entry(peer_id).or_default() += data.transfer_data.len();
+
+            let window_adjustment = u32::try_from(data.transfer_data.len()).unwrap();
+            write_message(&mut peer, Message::window_adjust(channels[&peer_id], window_adjustment)).await;
+        }
+    })
+    .await
+    .expect("data not forwarded");
+
+    assert!(received.values().all(|&n| n == PAYLOAD_SIZE));
+}
+
+#[tokio::test]
+async fn outstanding_bytes_are_released_when_channels_are_closed_abnormally() {
+    const SOFT_CAP: u64 = 16 * 1024;
+    const NB_CHANNELS: u32 = 4;
+    const INITIAL_WINDOW_SIZE: u32 = 1024;
+    const PAYLOAD_SIZE: usize = 256 * 1024;
+
+    let metrics = Arc::new(JmuxMetrics::new());
+
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer_with({
+        let metrics = Arc::clone(&metrics);
+        move |client| {
+            client
+                .with_config(JmuxConfig {
+                    max_outstanding_bytes: Some(SOFT_CAP),
+                    idle_timeout: Some(Duration::from_millis(300)),
+                    ..JmuxConfig::client()
+                })
+                .with_metrics(metrics)
+        }
+    });
+
+    let channels = open_loaded_channels(
+        &api_request_tx,
+        &mut peer,
+        NB_CHANNELS,
+        INITIAL_WINDOW_SIZE,
+        PAYLOAD_SIZE,
+    )
+    .await;
+
+    // The window is never adjusted, so the channels are stalled until closed for being idle, while their reader
+    // tasks are holding data.
+    tokio::time::timeout(TIMEOUT, async {
+        while metrics.idle_channels_closed() < u64::from(NB_CHANNELS) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("channels not closed");
+
+    let mut nb_closed = 0;
+    tokio::time::timeout(TIMEOUT, async {
+        while nb_closed < channels.len() {
+            if let Message::Close(_) = read_message(&mut peer).await {
+                nb_closed += 1;
+            }
+        }
+    })
+    .await
+    .expect("CHANNEL CLOSE not received");
+
+    // Nothing is left accounted once the data is either written or discarded.
+    tokio::time::timeout(TIMEOUT, async {
+        while metrics.outstanding_bytes() != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{} bytes still outstanding", metrics.outstanding_bytes()));
+}
+
+#[tokio::test]
+async fn duplicated_open_failure_is_ignored() {
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer();
+
+    let response = tokio::spawn({
+        let api_request_tx = api_request_tx.clone();
+        async move { request_channel(&api_request_tx, "tcp://127.0.0.1:80").await }
+    });
+
+    let Message::Open(open) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN");
+    };
+
+    for _ in 0..2 {
+        write_message(
+            &mut peer,
+            Message::open_failure(
+                DistantChannelId::from(open.sender_channel_id),
+                ReasonCode::CONNECTION_REFUSED,
+                "refused",
+            ),
+        )
+        .await;
+    }
+
+    let response = tokio::time::timeout(TIMEOUT, response).await.unwrap().unwrap();
+    assert!(matches!(
+        response,
+        JmuxApiResponse::Failure {
+            reason_code: ReasonCode::CONNECTION_REFUSED,
+            ..
+        }
+    ));
+
+    // The proxy is still alive, and the ID of the failed channel is reused.
+    let response = tokio::spawn(async move { request_channel(&api_request_tx, "tcp://127.0.0.1:80").await });
+    let Message::Open(second_open) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN");
+    };
+    assert_eq!(second_open.sender_channel_id, open.sender_channel_id);
+    drop(response);
+}
+
+#[tokio::test]
+async fn pending_channels_fail_with_session_expired_when_ttl_elapses() {
+    let (api_request_tx, mut peer) =
+        spawn_client_with_raw_peer_with(|client| client.with_ttl(Duration::from_millis(200)));
+
+    let response = tokio::spawn(async move { request_channel(&api_request_tx, "tcp://127.0.0.1:80").await });
+
+    // The peer never answers.
+    assert!(matches!(read_message(&mut peer).await, Message::Open(_)));
+
+    let response = tokio::time::timeout(TIMEOUT, response).await.unwrap().unwrap();
+    assert!(matches!(
+        response,
+        JmuxApiResponse::Failure {
+            reason_code: ReasonCode::SESSION_EXPIRED,
+            ..
+        }
+    ));
+}
+
+/// Opens a channel from a raw peer advertising `peer_features` (if any), and returns whether the data sent back
+/// by a proxy configured to number the CHANNEL DATA messages is actually numbered.
+async fn data_is_sequenced_for_peer_capabilities(peer_features: Option<u32>) -> bool {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (_api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|proxy| {
+        proxy.with_config(JmuxConfig {
+            sequence_data: true,
+            advertise_capabilities: true,
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let Message::Capabilities(advertised) = read_message(&mut peer).await else {
+        panic!("expected CAPABILITIES");
+    };
+    assert!(advertised.supports(Capabilities::SEQUENCE_NUMBERS));
+
+    if let Some(peer_features) = peer_features {
+        write_message(&mut peer, Message::capabilities(peer_features)).await;
+    }
+
+    write_message(
+        &mut peer,
+        Message::open(
+            LocalChannelId::from(1),
+            4096,
+            DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap(),
+        ),
+    )
+    .await;
+
+    let Message::OpenSuccess(_) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    target_stream.write_all(b"hello").await.unwrap();
+
+    let Message::Data(data) = tokio::time::timeout(TIMEOUT, read_message(&mut peer)).await.unwrap() else {
+        panic!("expected CHANNEL DATA");
+    };
+    assert_eq!(data.transfer_data, Bytes::from_static(b"hello"));
+
+    data.sequence_number.is_some()
+}
+
+#[tokio::test]
+async fn optional_features_are_only_used_when_supported_by_both_peers() {
+    assert!(data_is_sequenced_for_peer_capabilities(Some(Capabilities::SEQUENCE_NUMBERS)).await);
+
+    // Unknown features are ignored.
+    assert!(data_is_sequenced_for_peer_capabilities(Some(Capabilities::SEQUENCE_NUMBERS | 0x8000_0000)).await);
+
+    assert!(
+        !data_is_sequenced_for_peer_capabilities(Some(
+            Capabilities::COMPRESSED_FIELDS | Capabilities::UNLIMITED_WINDOW
+        ))
+        .await
+    );
+
+    // Peers not advertising their capabilities are limited to the baseline features.
+    assert!(!data_is_sequenced_for_peer_capabilities(None).await);
+}
+
+/// Requests a channel to a long destination from a client whose raw peer advertises `peer_features` (if any),
+/// and returns whether the destination URL of the CHANNEL OPEN message sent is compressed.
+async fn open_is_compressed_for_peer_capabilities(peer_features: Option<u32>) -> bool {
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|client| {
+        client.with_config(JmuxConfig {
+            advertise_capabilities: true,
+            ..JmuxConfig::client()
+        })
+    });
+
+    let Message::Capabilities(advertised) = read_message(&mut peer).await else {
+        panic!("expected CAPABILITIES");
+    };
+    assert!(advertised.supports(Capabilities::COMPRESSED_FIELDS));
+
+    if let Some(peer_features) = peer_features {
+        write_message(&mut peer, Message::capabilities(peer_features)).await;
+    }
+
+    // The messages from the peer are processed in order: once the denied opening is answered, the capabilities
+    // of the peer are known.
+    let destination_url = DestinationUrl::parse_str("tcp://127.0.0.1:80").unwrap();
+    write_message(&mut peer, Message::open(LocalChannelId::from(1), 4096, destination_url)).await;
+    let Message::OpenFailure(_) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN FAILURE");
+    };
+
+    let destination_url = format!("tcp://{}.example.com:443", "sub.".repeat(50));
+    tokio::spawn({
+        let destination_url = destination_url.clone();
+        async move { request_channel(&api_request_tx, &destination_url).await }
+    });
+
+    let frame = read_frame(&mut peer).await;
+    let header = Header::decode(frame.slice(..Header::SIZE)).unwrap();
+
+    let Message::Open(open) = Message::decode(frame).unwrap() else {
+        panic!("expected CHANNEL OPEN");
+    };
+    assert_eq!(open.destination_url.as_str(), destination_url);
+
+    header.flags & Header::FLAG_COMPRESSED != 0
+}
+
+#[tokio::test]
+async fn destination_url_size_limit_is_configurable() {
+    const MAX_DESTINATION_URL_SIZE: usize = 8 * 1024;
+
+    let destination_url = format!(
+        "tcp://{}:443",
+        "a".repeat(ChannelOpen::DEFAULT_MAX_DESTINATION_URL_SIZE)
+    );
+
+    // Rejected with the default limit, without being sent to the peer.
+    let (api_request_tx, _peer) = spawn_client_with_raw_peer();
+    let response = tokio::time::timeout(TIMEOUT, request_channel(&api_request_tx, &destination_url))
+        .await
+        .unwrap();
+    assert!(matches!(response, JmuxApiResponse::Failure { .. }));
+
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|client| {
+        client.with_config(JmuxConfig {
+            max_destination_url_size: MAX_DESTINATION_URL_SIZE,
+            ..JmuxConfig::client()
+        })
+    });
+    tokio::spawn({
+        let destination_url = destination_url.clone();
+        async move { request_channel(&api_request_tx, &destination_url).await }
+    });
+
+    let frame = read_frame(&mut peer).await;
+    let Message::Open(open) = Message::decode_with_max_url_size(frame, MAX_DESTINATION_URL_SIZE).unwrap() else {
+        panic!("expected CHANNEL OPEN");
+    };
+    assert_eq!(open.destination_url.as_str(), destination_url);
+
+    // The same limit applies to the CHANNEL OPEN messages received.
+    let destination_url = DestinationUrl::parse_str(&destination_url).unwrap();
+    let mut buf = BytesMut::new();
+    Message::open(LocalChannelId::from(1), 4096, destination_url)
+        .encode_with_max_url_size(&mut buf, MAX_DESTINATION_URL_SIZE)
+        .unwrap();
+    peer.write_all(&buf).await.unwrap();
+
+    // Denied by the filtering rule, rather than failing to decode.
+    let Message::OpenFailure(open_failure) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN FAILURE");
+    };
+    assert_eq!(open_failure.recipient_channel_id, 1);
+}
+
+#[tokio::test]
+async fn destination_is_compressed_when_supported_by_the_peer() {
+    assert!(open_is_compressed_for_peer_capabilities(Some(Capabilities::COMPRESSED_FIELDS)).await);
+    assert!(!open_is_compressed_for_peer_capabilities(Some(Capabilities::SEQUENCE_NUMBERS)).await);
+    assert!(!open_is_compressed_for_peer_capabilities(None).await);
+}
+
+#[tokio::test]
+async fn optional_features_are_not_used_without_advertising_them() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (_api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|proxy| {
+        proxy.with_config(JmuxConfig {
+            sequence_data: true,
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    // Even when the peer advertises the feature, the proxy didn't, so the peer doesn't expect numbered data.
+    write_message(&mut peer, Message::capabilities(Capabilities::SEQUENCE_NUMBERS)).await;
+
+    let destination_url = DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap();
+    write_message(&mut peer, Message::open(LocalChannelId::from(1), 4096, destination_url)).await;
+
+    let Message::OpenSuccess(_) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    target_stream.write_all(b"hello").await.unwrap();
+
+    let Message::Data(data) = tokio::time::timeout(TIMEOUT, read_message(&mut peer)).await.unwrap() else {
+        panic!("expected CHANNEL DATA");
+    };
+    assert_eq!(data.sequence_number, None);
+}
+
+/// Opens a channel with an unlimited window from a raw peer, sends some data through it,
+/// and returns whether the proxy produced any WINDOW ADJUST message.
+async fn window_adjust_sent_for_unlimited_window(disable_flow_control: bool) -> bool {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (_api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|proxy| {
+        proxy.with_config(JmuxConfig {
+            disable_flow_control,
+            advertise_capabilities: true,
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let Message::Capabilities(_) = read_message(&mut peer).await else {
+        panic!("expected CAPABILITIES");
+    };
+    write_message(&mut peer, Message::capabilities(Capabilities::UNLIMITED_WINDOW)).await;
+
+    let mut open = ChannelOpen::new(
+        LocalChannelId::from(1),
+        4096,
+        DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap(),
+    );
+    open.initial_window_size = u32::MAX;
+    write_message(&mut peer, Message::Open(open)).await;
+
+    let Message::OpenSuccess(open_success) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN SUCCESS");
+    };
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    const PACKET_COUNT: usize = 32;
+    const PACKET_SIZE: usize = 4000;
+
+    for _ in 0..PACKET_COUNT {
+        write_message(
+            &mut peer,
+            Message::data(
+                DistantChannelId::from(open_success.sender_channel_id),
+                vec![0xAB; PACKET_SIZE].into(),
+            ),
+        )
+        .await;
+    }
+
+    let mut received = vec![0; PACKET_COUNT * PACKET_SIZE];
+    target_stream.read_exact(&mut received).await.unwrap();
+
+    // Closing the target stream makes the proxy send an EOF once everything else is sent.
+    drop(target_stream);
+
+    let mut window_adjust_sent = false;
+
+    loop {
+        match tokio::time::timeout(TIMEOUT, read_message(&mut peer)).await.unwrap() {
+            Message::WindowAdjust(_) => window_adjust_sent = true,
+            Message::Eof(_) => break,
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    window_adjust_sent
+}
+
+#[tokio::test]
+async fn no_window_adjust_is_sent_when_flow_control_is_disabled() {
+    assert!(!window_adjust_sent_for_unlimited_window(true).await);
+
+    // Flow control is still applied when only the peer is asking for it.
+    assert!(window_adjust_sent_for_unlimited_window(false).await);
+}
+
+#[tokio::test]
+async fn open_is_retried_on_retryable_failures() {
+    let (api_request_tx, mut peer) = spawn_client_with_raw_peer_with(|client| {
+        client.with_config(JmuxConfig {
+            open_retry_policy: Some(OpenRetryPolicy {
+                max_attempts: 3,
+                backoff: Duration::from_millis(10),
+            }),
+            ..JmuxConfig::client()
+        })
+    });
+
+    let response = tokio::spawn({
+        let api_request_tx = api_request_tx.clone();
+        async move { request_channel(&api_request_tx, "tcp://127.0.0.1:80").await }
+    });
+
+    // The target is refusing the connection twice, then accepts it.
+    for _ in 0..2 {
+        let Message::Open(open) = read_message(&mut peer).await else {
+            panic!("expected CHANNEL OPEN");
+        };
+
+        write_message(
+            &mut peer,
+            Message::open_failure(
+                DistantChannelId::from(open.sender_channel_id),
+                ReasonCode::CONNECTION_REFUSED,
+                "refused",
+            ),
+        )
+        .await;
+    }
+
+    let Message::Open(open) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN");
+    };
+
+    write_message(
+        &mut peer,
+        Message::open_success(
+            DistantChannelId::from(open.sender_channel_id),
+            LocalChannelId::from(1),
+            open.initial_window_size,
+            open.maximum_packet_size,
+        ),
+    )
+    .await;
+
+    let response = tokio::time::timeout(TIMEOUT, response).await.unwrap().unwrap();
+    assert!(matches!(response, JmuxApiResponse::Success { .. }));
+
+    // Non-retryable failures are reported immediately.
+    let response = tokio::spawn(async move { request_channel(&api_request_tx, "tcp://127.0.0.1:80").await });
+
+    let Message::Open(open) = read_message(&mut peer).await else {
+        panic!("expected CHANNEL OPEN");
+    };
+
+    write_message(
+        &mut peer,
+        Message::open_failure(
+            DistantChannelId::from(open.sender_channel_id),
+            ReasonCode::CONNECTION_NOT_ALLOWED_BY_RULESET,
+            "denied",
+        ),
+    )
+    .await;
+
+    let response = tokio::time::timeout(TIMEOUT, response).await.unwrap().unwrap();
+    assert!(matches!(
+        response,
+        JmuxApiResponse::Failure {
+            reason_code: ReasonCode::CONNECTION_NOT_ALLOWED_BY_RULESET,
+            ..
+        }
+    ));
+}
+
+#[tokio::test]
+async fn invalid_config_is_rejected_at_start() {
+    let (proxy_side, _peer) = tokio::io::duplex(1024);
+    let (proxy_reader, proxy_writer) = tokio::io::split(proxy_side);
+
+    let error = JmuxProxy::new(Box::new(proxy_reader), Box::new(proxy_writer))
+        .with_config(JmuxConfig {
+            filtering: FilteringRule::port(80).and(FilteringRule::port(443)),
+            ..JmuxConfig::default()
+        })
+        .run()
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<ConfigError>(),
+        Some(ConfigError::UnsatisfiableFilteringRule { .. })
+    ));
+}
+
+#[tokio::test]
+async fn pipelined_bytes_are_forwarded_when_bridging_an_http_tunnel() {
+    const CLIENT_HELLO: &[u8] = &[0x16, 0x03, 0x01, 0x00, 0x05, 0x01, 0x00, 0x00, 0x01, 0x03];
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (incoming, _) = listener.accept().await.unwrap();
+
+    // The client sends its ClientHello right away, without waiting for the CONNECT response.
+    let mut request = format!("CONNECT {target_addr} HTTP/1.1\r\nHost: {target_addr}\r\n\r\n").into_bytes();
+    request.extend_from_slice(CLIENT_HELLO);
+    client.write_all(&request).await.unwrap();
+
+    let proxy_http::HttpProxyAcceptor::TunnelRequest(tunnel_request) =
+        proxy_http::HttpProxyAcceptor::accept(incoming).await.unwrap()
+    else {
+        panic!("expected a tunnel request");
+    };
+
+    let JmuxApiResponse::Success { id } = request_channel(&api_request_tx, &format!("tcp://{target_addr}")).await
+    else {
+        panic!("failed to open the channel");
+    };
+
+    let (stream, leftover) = tunnel_request.success().await.unwrap().into_parts();
+
+    api_request_tx
+        .send(JmuxApiRequest::Start {
+            id,
+            stream,
+            leftover: (!leftover.is_empty()).then_some(leftover),
+        })
+        .await
+        .unwrap();
+
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    let mut received = vec![0; CLIENT_HELLO.len()];
+    tokio::time::timeout(TIMEOUT, target_stream.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, CLIENT_HELLO);
+}
+
+#[tokio::test]
+async fn shutdown_drains_open_channels() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair {
+        api_request_tx, client, ..
+    } = spawn_proxy_pair();
+
+    let mut local_stream = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    local_stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0; 5];
+    target_stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    api_request_tx.send(JmuxApiRequest::Shutdown).await.unwrap();
+
+    // The target is told that nothing more is coming.
+    let mut rest = Vec::new();
+    tokio::time::timeout(TIMEOUT, target_stream.read_to_end(&mut rest))
+        .await
+        .expect("target stream not EOFed in time")
+        .unwrap();
+    assert!(rest.is_empty());
+
+    // New channels are refused while shutting down.
+    let (api_response_tx, api_response_rx) = oneshot::channel();
+    api_request_tx
+        .send(JmuxApiRequest::OpenChannel {
+            destination_url: DestinationUrl::parse_str(&format!("tcp://{target_addr}")).unwrap(),
+            api_response_tx,
+        })
+        .await
+        .unwrap();
+    assert!(api_response_rx.await.is_err());
+
+    // The data still sent by the target is forwarded until it closes the stream.
+    target_stream.write_all(b"bye").await.unwrap();
+    drop(target_stream);
+
+    tokio::time::timeout(TIMEOUT, local_stream.read_to_end(&mut rest))
+        .await
+        .expect("local stream not closed in time")
+        .unwrap();
+    assert_eq!(rest, b"bye");
+
+    tokio::time::timeout(TIMEOUT, client)
+        .await
+        .expect("client not stopped in time")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn remaining_channels_are_closed_when_shutdown_grace_period_elapses() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair {
+        api_request_tx, client, ..
+    } = spawn_configured_proxy_pair(
+        |client| {
+            client.with_config(JmuxConfig {
+                shutdown_grace_period: Duration::from_millis(200),
+                ..JmuxConfig::client()
+            })
+        },
+        |server| server.with_config(JmuxConfig::permissive()),
+    );
+
+    let mut local_stream = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (_target_stream, _) = target.accept().await.unwrap();
+
+    api_request_tx.send(JmuxApiRequest::Shutdown).await.unwrap();
+
+    // The target never closes its side, so the client stops once the grace period is elapsed.
+    tokio::time::timeout(TIMEOUT, client)
+        .await
+        .expect("client not stopped in time")
+        .unwrap()
+        .unwrap();
+
+    let mut rest = Vec::new();
+    tokio::time::timeout(TIMEOUT, local_stream.read_to_end(&mut rest))
+        .await
+        .expect("local stream not closed in time")
+        .unwrap();
+    assert!(rest.is_empty());
+}
+
+#[tokio::test]
+async fn idle_channels_are_closed() {
+    const IDLE_TIMEOUT: Duration = Duration::from_millis(300);
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let metrics = Arc::new(JmuxMetrics::new());
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server
+            .with_config(JmuxConfig {
+                idle_timeout: Some(IDLE_TIMEOUT),
+                ..JmuxConfig::permissive()
+            })
+            .with_metrics(Arc::clone(&metrics))
+    });
+
+    let mut local_stream = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (mut target_stream, _) = target.accept().await.unwrap();
+
+    // Data exchanged in either direction keeps the channel open longer than the idle timeout.
+    for _ in 0..4 {
+        tokio::time::sleep(IDLE_TIMEOUT / 3).await;
+
+        local_stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        target_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        tokio::time::sleep(IDLE_TIMEOUT / 3).await;
+
+        target_stream.write_all(b"pong").await.unwrap();
+        local_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    assert_eq!(metrics.idle_channels_closed(), 0);
+
+    // Both ends of the channel are closed once nothing is exchanged anymore.
+    let mut rest = Vec::new();
+    tokio::time::timeout(TIMEOUT, local_stream.read_to_end(&mut rest))
+        .await
+        .expect("local stream not closed in time")
+        .unwrap();
+    tokio::time::timeout(TIMEOUT, target_stream.read_to_end(&mut rest))
+        .await
+        .expect("target stream not closed in time")
+        .unwrap();
+    assert!(rest.is_empty());
+
+    assert_eq!(metrics.idle_channels_closed(), 1);
+}
+
+/// JMUX writer accepting at most `chunk_size` bytes per `period`, so the pipe is the bottleneck.
+struct ThrottledWriter {
+    inner: tokio::io::WriteHalf<DuplexStream>,
+    chunk_size: usize,
+    period: Duration,
+    next_write: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl AsyncWrite for ThrottledWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.next_write.as_mut().poll(cx));
+
+        let len = buf.len().min(self.chunk_size);
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]))?;
+
+        let deadline = tokio::time::Instant::now() + self.period;
+        self.next_write.as_mut().reset(deadline);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn bulk_transfer_does_not_starve_interactive_channel() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let (client_side, server_side) = tokio::io::duplex(64 * 1024);
+    let (api_request_tx, api_request_rx) = mpsc::channel(8);
+
+    let (client_reader, client_writer) = tokio::io::split(client_side);
+    let client = JmuxProxy::new(Box::new(client_reader), Box::new(client_writer))
+        .with_config(JmuxConfig::client())
+        .with_requester_api(api_request_rx);
+    tokio::spawn(client.run());
+
+    // Around 400 kB/s from the server to the client.
+    let (server_reader, server_writer) = tokio::io::split(server_side);
+    let server_writer = ThrottledWriter {
+        inner: server_writer,
+        chunk_size: 4 * 1024,
+        period: Duration::from_millis(10),
+        next_write: Box::pin(tokio::time::sleep(Duration::ZERO)),
+    };
+    let server = JmuxProxy::new(Box::new(server_reader), Box::new(server_writer)).with_config(JmuxConfig::permissive());
+    tokio::spawn(server.run());
+
+    let mut bulk_local = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (mut bulk_target, _) = target.accept().await.unwrap();
+
+    let mut interactive_local = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (mut interactive_target, _) = target.accept().await.unwrap();
+
+    // The bulk target sends much more than the pipe can carry in the duration of the test.
+    tokio::spawn(async move { bulk_target.write_all(&vec![0xAB; 16 * 1024 * 1024]).await });
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        while bulk_local.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+    });
+
+    // Let the bulk transfer saturate the pipe.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    for _ in 0..3 {
+        interactive_target.write_all(b"key").await.unwrap();
+
+        let mut buf = [0; 3];
+        tokio::time::timeout(Duration::from_secs(1), interactive_local.read_exact(&mut buf))
+            .await
+            .expect("interactive channel starved by the bulk transfer")
+            .unwrap();
+        assert_eq!(&buf, b"key");
+    }
+}
+
+#[tokio::test]
+async fn channel_bandwidth_is_limited() {
+    const RATE: u64 = 100 * 1024;
+    const PAYLOAD_SIZE: usize = 100 * 1024;
+
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let ProxyPair { api_request_tx, .. } = spawn_proxy_pair_with(|server| {
+        server.with_config(JmuxConfig {
+            max_channel_tx_rate: Some(RATE),
+            max_channel_rx_rate: Some(RATE),
+            ..JmuxConfig::permissive()
+        })
+    });
+
+    let local_stream = open_channel(&api_request_tx, &format!("tcp://{target_addr}")).await;
+    let (target_stream, _) = target.accept().await.unwrap();
+
+    let transfer = |mut from: TcpStream, mut to: TcpStream| async move {
+        let payload: Vec<u8> = (0..PAYLOAD_SIZE).map(|i| u8::try_from(i % 251).unwrap()).collect();
+        let writer = tokio::spawn(async move {
+            from.write_all(&payload).await.unwrap();
+            (from, payload)
+        });
+
+        let mut received = vec![0; PAYLOAD_SIZE];
+        to.read_exact(&mut received).await.unwrap();
+
+        let (from, payload) = writer.await.unwrap();
+        assert_eq!(received, payload);
+
+        (from, to)
+    };
+
+    // Data received from the peer and written to the target.
+    let start = tokio::time::Instant::now();
+    let (local_stream, target_stream) = tokio::time::timeout(TIMEOUT, transfer(local_stream, target_stream))
+        .await
+        .unwrap();
+    let rx_elapsed = start.elapsed();
+
+    // Data read from the target and sent to the peer.
+    let start = tokio::time::Instant::now();
+    tokio::time::timeout(TIMEOUT, transfer(target_stream, local_stream))
+        .await
+        .unwrap();
+    let tx_elapsed = start.elapsed();
+
+    // A tenth of a second of traffic is let through at once.
+    let expected = Duration::from_millis(800);
+    assert!(rx_elapsed >= expected, "received by the target in {rx_elapsed:?}");
+    assert!(tx_elapsed >= expected, "sent by the target in {tx_elapsed:?}");
+}
@@ -1,9 +1,12 @@
 #[macro_use]
 extern crate tracing;
 
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use anyhow::Context as _;
 use async_trait::async_trait;
-use job_queue::{DynJob, JobCtx, JobQueue, JobReader, RunnerWaker};
+use job_queue::{DynJob, JobCtx, JobPreview, JobQueue, JobReader, RunnerWaker};
 use libsql::Connection;
 use time::OffsetDateTime;
 use ulid::Ulid;
@@ -11,6 +14,23 @@ use uuid::Uuid;
 
 pub use libsql;
 
+/// Callback invoked with the duration of the key SQL operations, and whether they succeeded
+///
+/// This can be used to record these durations into a metrics system, or to detect slow and failing queries.
+/// Failed operations are reported too, with the time elapsed until the error.
+pub type OperationHook = Arc<dyn Fn(Operation, Duration, bool) + Send + Sync>;
+
+/// Key SQL operations reported to the [`OperationHook`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    ClaimJobs,
+    PushJob,
+    /// Removal of the singleton instance which can't be retried anymore, before pushing a new one
+    CleanupSingleton,
+    PushSingleton,
+    FailJob,
+}
+
 /// Implementation of [`JobQueue`] using libSQL as the backend
 ///
 /// This is inspired by 37signals' Solid Queue:
@@ -30,6 +50,12 @@ pub struct LibSqlJobQueue {
     conn: Connection,
     #[builder(default = 5)]
     max_attempts: u32,
+    /// Identifier recorded on the jobs claimed through this queue
+    #[builder(default = String::from("default"), setter(into))]
+    worker_id: String,
+    /// Hook invoked with the duration of the key SQL operations
+    #[builder(default, setter(strip_option))]
+    operation_hook: Option<OperationHook>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -40,6 +66,24 @@ enum JobStatus {
 }
 
 impl LibSqlJobQueue {
+    fn record_operation<T>(&self, operation: Operation, start: Instant, result: &libsql::Result<T>) {
+        const SLOW_OPERATION_THRESHOLD: Duration = Duration::from_millis(500);
+
+        let elapsed = start.elapsed();
+
+        if let Err(error) = result {
+            debug!(?operation, ?elapsed, %error, "SQL operation failed");
+        } else if elapsed > SLOW_OPERATION_THRESHOLD {
+            debug!(?operation, ?elapsed, "Slow SQL operation");
+        } else {
+            trace!(?operation, ?elapsed, "SQL operation completed");
+        }
+
+        if let Some(hook) = &self.operation_hook {
+            (hook)(operation, elapsed, result.is_ok());
+        }
+    }
+
     async fn apply_pragmas(&self) -> anyhow::Result<()> {
         // Inspiration was taken from https://briandouglas.ie/sqlite-defaults/
         const PRAGMAS: &str = "
@@ -178,7 +222,9 @@ impl JobQueue for LibSqlJobQueue {
     }
 
     async fn reset_claimed_jobs(&self) -> anyhow::Result<()> {
-        let sql_query = "UPDATE job_queue SET status = :queued_status WHERE status = :running_status";
+        let sql_query = "UPDATE job_queue
+            SET status = :queued_status, claimed_by = NULL
+            WHERE status = :running_status";
 
         let params = (
             (":running_status", JobStatus::Running as u32),
@@ -198,6 +244,30 @@ impl JobQueue for LibSqlJobQueue {
         Ok(())
     }
 
+    async fn reset_claimed_jobs_for(&self, worker_id: &str) -> anyhow::Result<()> {
+        let sql_query = "UPDATE job_queue
+            SET status = :queued_status, claimed_by = NULL
+            WHERE status = :running_status AND claimed_by = :worker_id";
+
+        let params = (
+            (":running_status", JobStatus::Running as u32),
+            (":queued_status", JobStatus::Queued as u32),
+            (":worker_id", worker_id),
+        );
+
+        trace!(%sql_query, ?params, "Reset claimed jobs for worker");
+
+        let changed_count = self
+            .conn
+            .execute(sql_query, params)
+            .await
+            .context("failed to execute SQL query")?;
+
+        trace!(changed_count, worker_id, "Jobs reset with success");
+
+        Ok(())
+    }
+
     async fn push_job(&self, job: &DynJob, schedule_for: Option<OffsetDateTime>) -> anyhow::Result<()> {
         let sql_query = "INSERT INTO job_queue
             (id, scheduled_for, failed_attempts, status, name, def)
@@ -221,10 +291,13 @@ impl JobQueue for LibSqlJobQueue {
 
         trace!(%sql_query, ?params, "Pushing a new job");
 
-        self.conn
-            .execute(sql_query, params)
-            .await
-            .context("failed to execute SQL query")?;
+        let start = Instant::now();
+
+        let result = self.conn.execute(sql_query, params).await;
+
+        self.record_operation(Operation::PushJob, start, &result);
+
+        result.context("failed to execute SQL query")?;
 
         // Notify the waker that a new job is ready for processing.
         self.runner_waker.wake();
@@ -232,6 +305,71 @@ impl JobQueue for LibSqlJobQueue {
         Ok(())
     }
 
+    async fn push_singleton(
+        &self,
+        name: &str,
+        job: &DynJob,
+        schedule_for: Option<OffsetDateTime>,
+    ) -> anyhow::Result<bool> {
+        // An instance which can't be retried anymore should not prevent the singleton from being pushed again.
+        let cleanup_sql_query = "DELETE FROM job_queue
+            WHERE singleton_name = :singleton_name AND failed_attempts >= :max_attempts";
+
+        let cleanup_params = ((":singleton_name", name), (":max_attempts", self.max_attempts));
+
+        trace!(sql_query = %cleanup_sql_query, params = ?cleanup_params, "Clearing failed singleton instance");
+
+        let start = Instant::now();
+
+        let result = self.conn.execute(cleanup_sql_query, cleanup_params).await;
+
+        self.record_operation(Operation::CleanupSingleton, start, &result);
+
+        result.context("failed to execute SQL query")?;
+
+        // The unique index on singleton_name makes this a no-op when an instance is already queued or running,
+        // without any race between the workers.
+        let sql_query = "INSERT OR IGNORE INTO job_queue
+            (id, scheduled_for, failed_attempts, status, name, def, singleton_name)
+            VALUES (:id, :scheduled_for, :failed_attempts, :status, :name, jsonb(:def), :singleton_name)";
+
+        let id = Uuid::from(Ulid::new()).to_string();
+
+        let schedule_for = schedule_for.unwrap_or_else(|| OffsetDateTime::now_utc());
+
+        let params = (
+            (":id", id),
+            (":scheduled_for", schedule_for.unix_timestamp()),
+            (":failed_attempts", 0),
+            (":status", JobStatus::Queued as u32),
+            (":name", job.name()),
+            (":def", job.write_json()?),
+            (":singleton_name", name),
+        );
+
+        trace!(%sql_query, ?params, "Pushing a singleton job");
+
+        let start = Instant::now();
+
+        let result = self.conn.execute(sql_query, params).await;
+
+        self.record_operation(Operation::PushSingleton, start, &result);
+
+        let inserted_count = result.context("failed to execute SQL query")?;
+
+        if inserted_count == 0 {
+            debug!(
+                singleton_name = name,
+                "Singleton job is already queued or running; not pushed"
+            );
+            return Ok(false);
+        }
+
+        self.runner_waker.wake();
+
+        Ok(true)
+    }
+
     async fn claim_jobs(&self, reader: &dyn JobReader, number_of_jobs: usize) -> anyhow::Result<Vec<JobCtx>> {
         let number_of_jobs = u32::try_from(number_of_jobs).context("number_of_jobs is too big")?;
 
@@ -242,7 +380,7 @@ impl JobQueue for LibSqlJobQueue {
         // As such, this directive doesn't exist.
 
         let sql_query = "UPDATE job_queue
-            SET status = :running_status
+            SET status = :running_status, claimed_by = :worker_id
             WHERE id IN (
                 SELECT id
                 FROM job_queue
@@ -257,33 +395,42 @@ impl JobQueue for LibSqlJobQueue {
             (":queued_status", JobStatus::Queued as u32),
             (":max_attempts", self.max_attempts),
             (":number_of_jobs", number_of_jobs),
+            (":worker_id", self.worker_id.as_str()),
         );
 
         trace!(%sql_query, ?params, "Claiming jobs");
 
-        let mut rows = self
-            .conn
-            .query(sql_query, params)
-            .await
-            .context("failed to execute SQL query")?;
+        let start = Instant::now();
 
-        let mut jobs = Vec::new();
+        let result = self.conn.query(sql_query, params).await;
 
-        loop {
-            let row = rows.next().await;
+        if result.is_err() {
+            self.record_operation(Operation::ClaimJobs, start, &result);
+        }
 
-            let row = match row {
-                Ok(row) => row,
-                Err(error) => {
-                    error!(%error, "Failed to get next row");
-                    break;
-                }
-            };
+        let mut rows = result.context("failed to execute SQL query")?;
 
-            let Some(row) = row else {
-                break;
-            };
+        // The rows are fetched before being processed, so the recorded duration is the one of the claim itself,
+        // and doesn't include the deletion of the invalid jobs.
+        let mut fetched_rows = Vec::new();
+
+        let fetch_result = loop {
+            match rows.next().await {
+                Ok(Some(row)) => fetched_rows.push(row),
+                Ok(None) => break Ok(()),
+                Err(error) => break Err(error),
+            }
+        };
+
+        self.record_operation(Operation::ClaimJobs, start, &fetch_result);
 
+        if let Err(error) = fetch_result {
+            error!(%error, "Failed to get next row");
+        }
+
+        let mut jobs = Vec::new();
+
+        for row in fetched_rows {
             match libsql::de::from_row::<'_, JobModel>(&row) {
                 Ok(model) => match reader.read_json(&model.name, &model.def) {
                     Ok(job) => jobs.push(JobCtx {
@@ -334,6 +481,7 @@ impl JobQueue for LibSqlJobQueue {
         let sql_query = "UPDATE job_queue
             SET
                 status = :queued_status,
+                claimed_by = NULL,
                 failed_attempts = failed_attempts + 1,
                 scheduled_for = :scheduled_for
             WHERE id = :id";
@@ -346,10 +494,13 @@ impl JobQueue for LibSqlJobQueue {
 
         trace!(%sql_query, ?params, "Marking job as failed");
 
-        self.conn
-            .execute(sql_query, params)
-            .await
-            .context("failed to execute SQL query")?;
+        let start = Instant::now();
+
+        let result = self.conn.execute(sql_query, params).await;
+
+        self.record_operation(Operation::FailJob, start, &result);
+
+        result.context("failed to execute SQL query")?;
 
         Ok(())
     }
@@ -401,6 +552,51 @@ impl JobQueue for LibSqlJobQueue {
 
         Ok(Some(scheduled_for))
     }
+
+    async fn peek_next(&self) -> anyhow::Result<Option<JobPreview>> {
+        let sql_query = "SELECT id, name, scheduled_for, failed_attempts
+            FROM job_queue
+            WHERE status = :queued_status AND failed_attempts < :max_attempts
+            ORDER BY scheduled_for ASC, id ASC
+            LIMIT 1";
+
+        let params = (
+            (":queued_status", JobStatus::Queued as u32),
+            (":max_attempts", self.max_attempts),
+        );
+
+        trace!(%sql_query, ?params, "Peeking the next job");
+
+        let mut rows = self
+            .conn
+            .query(sql_query, params)
+            .await
+            .context("failed to execute SQL query")?;
+
+        let Some(row) = rows.next().await.context("failed to read the row")? else {
+            return Ok(None);
+        };
+
+        let model = libsql::de::from_row::<'_, JobPreviewModel>(&row).context("failed to read the row")?;
+
+        let scheduled_for = OffsetDateTime::from_unix_timestamp(model.scheduled_for)
+            .context("invalid UNIX timestamp for scheduled_for")?;
+
+        return Ok(Some(JobPreview {
+            id: model.id,
+            name: model.name,
+            scheduled_for,
+            failed_attempts: model.failed_attempts,
+        }));
+
+        #[derive(serde::Deserialize, Debug, Clone)]
+        struct JobPreviewModel {
+            id: Uuid,
+            name: String,
+            scheduled_for: i64,
+            failed_attempts: u32,
+        }
+    }
 }
 
 // Typically, migrations should not be modified once released, and we should only be appending to this list.
@@ -423,4 +619,177 @@ const MIGRATIONS: &[&str] = &[
     END;
 
     CREATE INDEX idx_scheduled_for ON job_queue(scheduled_for);",
+    // Migration 1
+    "ALTER TABLE job_queue ADD COLUMN claimed_by TEXT;",
+    // Migration 2
+    "ALTER TABLE job_queue ADD COLUMN singleton_name TEXT;
+
+    CREATE UNIQUE INDEX idx_singleton_name ON job_queue(singleton_name) WHERE singleton_name IS NOT NULL;",
 ];
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    struct DummyJob;
+
+    #[async_trait]
+    impl job_queue::Job for DummyJob {
+        fn name(&self) -> &str {
+            "dummy"
+        }
+
+        fn write_json(&self) -> anyhow::Result<String> {
+            Ok("{}".to_owned())
+        }
+
+        async fn run(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct DummyReader;
+
+    impl JobReader for DummyReader {
+        fn read_json(&self, _: &str, _: &str) -> anyhow::Result<DynJob> {
+            Ok(Box::new(DummyJob))
+        }
+    }
+
+    async fn in_memory_connection() -> Connection {
+        let database = libsql::Builder::new_local(":memory:").build().await.unwrap();
+        database.connect().unwrap()
+    }
+
+    fn queue_for(conn: &Connection, worker_id: &str) -> LibSqlJobQueue {
+        LibSqlJobQueue::builder()
+            .runner_waker(RunnerWaker::new(|| {}))
+            .conn(conn.clone())
+            .worker_id(worker_id)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn reset_claimed_jobs_for_only_affects_the_specified_worker() {
+        let conn = in_memory_connection().await;
+
+        let worker_a = queue_for(&conn, "worker-a");
+        let worker_b = queue_for(&conn, "worker-b");
+
+        worker_a.setup().await.unwrap();
+
+        let job: DynJob = Box::new(DummyJob);
+
+        worker_a.push_job(&job, None).await.unwrap();
+        let claimed_by_a = worker_a.claim_jobs(&DummyReader, 10).await.unwrap();
+        assert_eq!(claimed_by_a.len(), 1);
+
+        worker_b.push_job(&job, None).await.unwrap();
+        let claimed_by_b = worker_b.claim_jobs(&DummyReader, 10).await.unwrap();
+        assert_eq!(claimed_by_b.len(), 1);
+
+        // Worker A restarts: only its own job should be re-queued.
+        worker_a.reset_claimed_jobs_for("worker-a").await.unwrap();
+
+        let reclaimed = worker_b.claim_jobs(&DummyReader, 10).await.unwrap();
+        assert_eq!(reclaimed.len(), 1);
+        assert_eq!(reclaimed[0].id, claimed_by_a[0].id);
+
+        // Nothing left to claim, the job of worker B is still running.
+        assert!(worker_a.claim_jobs(&DummyReader, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn peek_next_does_not_claim_the_job() {
+        let conn = in_memory_connection().await;
+        let queue = queue_for(&conn, "worker");
+
+        queue.setup().await.unwrap();
+
+        assert!(queue.peek_next().await.unwrap().is_none());
+
+        let job: DynJob = Box::new(DummyJob);
+        queue
+            .push_job(&job, Some(OffsetDateTime::now_utc() + time::Duration::hours(1)))
+            .await
+            .unwrap();
+        queue.push_job(&job, None).await.unwrap();
+
+        // The job scheduled first is at the head of the queue, even if it was pushed last.
+        let preview = queue.peek_next().await.unwrap().unwrap();
+        assert_eq!(preview.name, "dummy");
+        assert_eq!(preview.failed_attempts, 0);
+        assert!(preview.scheduled_for <= OffsetDateTime::now_utc());
+
+        // Peeking again returns the same job.
+        assert_eq!(queue.peek_next().await.unwrap(), Some(preview.clone()));
+
+        // And the job is still claimable.
+        let claimed = queue.claim_jobs(&DummyReader, 10).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].id, preview.id);
+    }
+
+    #[tokio::test]
+    async fn singleton_is_not_pushed_while_an_instance_is_running() {
+        let conn = in_memory_connection().await;
+
+        let worker_a = queue_for(&conn, "worker-a");
+        let worker_b = queue_for(&conn, "worker-b");
+
+        worker_a.setup().await.unwrap();
+
+        let job: DynJob = Box::new(DummyJob);
+
+        assert!(worker_a.push_singleton("compaction", &job, None).await.unwrap());
+        let claimed_by_a = worker_a.claim_jobs(&DummyReader, 10).await.unwrap();
+        assert_eq!(claimed_by_a.len(), 1);
+
+        // The instance claimed by worker A is still running.
+        assert!(!worker_a.push_singleton("compaction", &job, None).await.unwrap());
+        assert!(!worker_b.push_singleton("compaction", &job, None).await.unwrap());
+        assert!(worker_b.claim_jobs(&DummyReader, 10).await.unwrap().is_empty());
+
+        // Other singletons and regular jobs are not affected.
+        assert!(worker_b.push_singleton("vacuum", &job, None).await.unwrap());
+        worker_b.push_job(&job, None).await.unwrap();
+        assert_eq!(worker_b.claim_jobs(&DummyReader, 10).await.unwrap().len(), 2);
+
+        // Once the running instance is done, the singleton can be pushed again.
+        worker_a.delete_job(claimed_by_a[0].id).await.unwrap();
+        assert!(worker_b.push_singleton("compaction", &job, None).await.unwrap());
+        assert_eq!(worker_b.claim_jobs(&DummyReader, 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn operation_hook_is_invoked_on_claim() {
+        let conn = in_memory_connection().await;
+
+        let recorded = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let queue = LibSqlJobQueue::builder()
+            .runner_waker(RunnerWaker::new(|| {}))
+            .conn(conn)
+            .operation_hook(Arc::new({
+                let recorded = Arc::clone(&recorded);
+                move |operation, elapsed, success| recorded.lock().unwrap().push((operation, elapsed, success))
+            }))
+            .build();
+
+        // The table doesn't exist yet: the failed query is reported too.
+        assert!(queue.claim_jobs(&DummyReader, 10).await.is_err());
+
+        queue.setup().await.unwrap();
+
+        queue.claim_jobs(&DummyReader, 10).await.unwrap();
+
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].0, Operation::ClaimJobs);
+        assert!(!recorded[0].2);
+        assert_eq!(recorded[1].0, Operation::ClaimJobs);
+        assert!(recorded[1].2);
+    }
+}
@@ -41,11 +41,29 @@ pub trait JobQueue: Send + Sync {
     /// Uses this at startup to re-enqueue jobs that didn't run to completion.
     async fn reset_claimed_jobs(&self) -> anyhow::Result<()>;
 
+    /// Resets the status for the jobs claimed by the specified worker
+    ///
+    /// Unlike `reset_claimed_jobs`, jobs claimed by other workers are left untouched.
+    /// Uses this at startup when several workers are sharing the same queue.
+    async fn reset_claimed_jobs_for(&self, worker_id: &str) -> anyhow::Result<()>;
+
     /// Pushes a new job into the queue
     ///
     /// This function should ideally call `RunnerWaker::wake()` once the job is enqueued.
     async fn push_job(&self, job: &DynJob, schedule_for: Option<OffsetDateTime>) -> anyhow::Result<()>;
 
+    /// Pushes a new job into the queue, unless an instance of the singleton `name` is already queued or running
+    ///
+    /// At most one instance of a given singleton exists at any time, across all the workers sharing the queue.
+    /// This is meant for maintenance tasks which must never run concurrently (e.g.: a global compaction).
+    /// Returns whether the job was actually enqueued.
+    async fn push_singleton(
+        &self,
+        name: &str,
+        job: &DynJob,
+        schedule_for: Option<OffsetDateTime>,
+    ) -> anyhow::Result<bool>;
+
     /// Fetches at most `number_of_jobs` from the queue
     async fn claim_jobs(&self, reader: &dyn JobReader, number_of_jobs: usize) -> anyhow::Result<Vec<JobCtx>>;
 
@@ -62,6 +80,20 @@ pub trait JobQueue: Send + Sync {
 
     /// Retrieves the closest future scheduled date
     async fn next_scheduled_date(&self) -> anyhow::Result<Option<OffsetDateTime>>;
+
+    /// Inspects the job at the head of the queue, without claiming it
+    ///
+    /// This is the queued job with the earliest scheduled date.
+    async fn peek_next(&self) -> anyhow::Result<Option<JobPreview>>;
+}
+
+/// Summary of a queued job, as returned by `JobQueue::peek_next`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobPreview {
+    pub id: Uuid,
+    pub name: String,
+    pub scheduled_for: OffsetDateTime,
+    pub failed_attempts: u32,
 }
 
 pub struct JobCtx {
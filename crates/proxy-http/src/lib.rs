@@ -7,15 +7,18 @@
 //! - [Section 2.3](https://datatracker.ietf.org/doc/html/rfc7230#section-2.3)
 //! - [Section 5.3.2](https://datatracker.ietf.org/doc/html/rfc7230#section-5.3.2)
 //! - [Section 5.7](https://datatracker.ietf.org/doc/html/rfc7230#section-5.7)
-
-// TODO: support for Proxy-Authorization: https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Proxy-Authorization
+//!
+//! And [RFC 7235](https://datatracker.ietf.org/doc/html/rfc7235):
+//! - [Section 3.2](https://datatracker.ietf.org/doc/html/rfc7235#section-3.2)
+//! - [Section 4.3](https://datatracker.ietf.org/doc/html/rfc7235#section-4.3)
+//! - [Section 4.4](https://datatracker.ietf.org/doc/html/rfc7235#section-4.4)
 
 use bytes::{BufMut as _, Bytes, BytesMut};
 use core::fmt;
 use pin_project_lite::pin_project;
 use proxy_types::{DestAddr, ToDestAddr};
 use std::io;
-use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, ReadHalf, WriteHalf};
 
 #[derive(Debug, Copy, Clone)]
 pub enum ErrorCode {
@@ -191,6 +194,7 @@ pub struct HttpRegularProxyRequest<S> {
     read_bytes: Bytes,
     method: String,
     dest_addr: DestAddr,
+    proxy_authorization: Option<String>,
 }
 
 impl<S> HttpRegularProxyRequest<S> {
@@ -203,6 +207,11 @@ impl<S> HttpRegularProxyRequest<S> {
     pub fn method(&self) -> &str {
         &self.method
     }
+
+    /// Value of the `Proxy-Authorization` header in client's request, if any.
+    pub fn proxy_authorization(&self) -> Option<&str> {
+        self.proxy_authorization.as_deref()
+    }
 }
 
 impl<S> HttpRegularProxyRequest<S>
@@ -236,6 +245,7 @@ pub struct HttpsTunnelRequest<S> {
     stream: S,
     read_leftover: Bytes,
     dest_addr: DestAddr,
+    proxy_authorization: Option<String>,
 }
 
 impl<S> HttpsTunnelRequest<S> {
@@ -243,6 +253,11 @@ impl<S> HttpsTunnelRequest<S> {
     pub fn dest_addr(&self) -> &DestAddr {
         &self.dest_addr
     }
+
+    /// Value of the `Proxy-Authorization` header in client's request, if any.
+    pub fn proxy_authorization(&self) -> Option<&str> {
+        self.proxy_authorization.as_deref()
+    }
 }
 
 impl<S> HttpsTunnelRequest<S>
@@ -320,9 +335,41 @@ where
 {
     /// Accepts HTTP forwarding request without requiring any authentication.
     pub async fn accept(mut stream: S) -> io::Result<Self> {
-        let frame = Frame::read(&mut stream).await?;
+        let frame = Frame::read(&mut stream, Bytes::new()).await?;
+        Self::from_frame(stream, frame)
+    }
+
+    /// Accepts HTTP forwarding request, challenging the client for credentials when none are provided.
+    ///
+    /// If the first request has no `Proxy-Authorization` header, a `407 Proxy Authentication Required` response
+    /// with a `Proxy-Authenticate: Basic realm="<realm>"` header is sent, and the follow-up request is read
+    /// from the same connection.
+    ///
+    /// Credentials are not verified here: the caller is expected to check [`Self::proxy_authorization`]
+    /// and to respond with [`ErrorCode::ProxyAuthenticationRequired`] if they are missing or invalid.
+    ///
+    /// The body of a challenged request is not skipped, so this is only suitable for requests without body
+    /// such as CONNECT.
+    pub async fn accept_with_challenge(mut stream: S, realm: &str) -> io::Result<Self> {
+        let frame = Frame::read(&mut stream, Bytes::new()).await?;
+
+        if decode_request(frame.payload())?.proxy_authorization.is_some() {
+            return Self::from_frame(stream, frame);
+        }
+
+        let mut write_buf = BytesMut::new();
+        encode_challenge(&mut write_buf, realm);
+        write_frame(&mut stream, &mut write_buf).await?;
+
+        // The client may have sent the follow-up request without waiting for the challenge
+        let frame = Frame::read(&mut stream, frame.into_read_leftover()).await?;
+        Self::from_frame(stream, frame)
+    }
+
+    fn from_frame(stream: S, frame: Frame) -> io::Result<Self> {
         let request = decode_request(frame.payload())?;
         let dest_addr = request.dest_addr;
+        let proxy_authorization = request.proxy_authorization.map(str::to_owned);
 
         if request.method == "CONNECT" {
             // Request payload is eaten, only leftover must be forwarded
@@ -331,6 +378,7 @@ where
                 stream,
                 dest_addr,
                 read_leftover,
+                proxy_authorization,
             }))
         } else {
             // All read bytes are kept to be forwarded
@@ -341,6 +389,7 @@ where
                 method,
                 dest_addr,
                 read_bytes,
+                proxy_authorization,
             }))
         }
     }
@@ -353,6 +402,14 @@ where
         }
     }
 
+    /// Value of the `Proxy-Authorization` header in client's request, if any.
+    pub fn proxy_authorization(&self) -> Option<&str> {
+        match self {
+            HttpProxyAcceptor::RegularRequest(request) => request.proxy_authorization(),
+            HttpProxyAcceptor::TunnelRequest(request) => request.proxy_authorization(),
+        }
+    }
+
     /// Responds with the given error status code.
     pub async fn failure(self, error_code: ErrorCode) -> io::Result<ProxyStream<S>> {
         match self {
@@ -386,7 +443,7 @@ where
         write_frame(&mut stream, &mut write_buf).await?;
 
         // response
-        let frame = Frame::read(&mut stream).await?;
+        let frame = Frame::read(&mut stream, Bytes::new()).await?;
         let status_code = decode_response(frame.payload())?;
 
         if !(200..300).contains(&status_code) {
@@ -402,6 +459,17 @@ where
     pub fn into_parts(self) -> (S, Bytes) {
         (self.stream, self.read_leftover)
     }
+
+    /// Splits the underlying stream into read and write halves, and returns the leftover bytes alongside
+    ///
+    /// The leftover is handed over as is, without being copied, and is `None` when there are no bytes left.
+    /// It may be given directly to a consumer expecting an `Option<Bytes>`, such as a JMUX channel start request.
+    pub fn into_split(self) -> (ReadHalf<S>, WriteHalf<S>, Option<Bytes>) {
+        let (stream, read_leftover) = self.into_parts();
+        let (reader, writer) = tokio::io::split(stream);
+        let read_leftover = (!read_leftover.is_empty()).then_some(read_leftover);
+        (reader, writer, read_leftover)
+    }
 }
 
 impl<S> AsyncRead for ProxyStream<S>
@@ -514,13 +582,21 @@ struct Frame {
 }
 
 impl Frame {
-    async fn read<S>(stream: &mut S) -> io::Result<Self>
+    /// Reads a frame, starting with the bytes already read from the stream.
+    async fn read<S>(stream: &mut S, already_read: Bytes) -> io::Result<Self>
     where
         S: AsyncRead + Unpin,
     {
-        let mut buffer = BytesMut::new();
+        let mut buffer = BytesMut::from(already_read.as_ref());
         let mut scan_cursor: usize = 0;
 
+        if let Some(headers_end) = find_frame_length(&buffer) {
+            return Ok(Self {
+                buffer: buffer.freeze(),
+                headers_end,
+            });
+        }
+
         let headers_end = loop {
             // Attempt to read more from stream
             buffer.reserve(128);
@@ -595,9 +671,12 @@ fn encode_request(buf: &mut BytesMut, dest: &DestAddr) {
 struct Request<'a> {
     method: &'a str,
     dest_addr: DestAddr,
+    proxy_authorization: Option<&'a str>,
 }
 
 fn decode_request(buf: &[u8]) -> Result<Request<'_>, Error> {
+    let proxy_authorization = find_header(buf, "Proxy-Authorization")?;
+
     let method_end_idx = find(buf, b" ").ok_or(Error::Truncated)?;
     let (method, buf) = buf.split_at(method_end_idx);
     let method = core::str::from_utf8(method).map_err(|_| Error::InvalidPayload)?;
@@ -626,7 +705,34 @@ fn decode_request(buf: &[u8]) -> Result<Request<'_>, Error> {
     }
     .map_err(|_| Error::InvalidPayload)?;
 
-    Ok(Request { method, dest_addr })
+    Ok(Request {
+        method,
+        dest_addr,
+        proxy_authorization,
+    })
+}
+
+/// Finds the value of the given header, comparing header names case-insensitively
+fn find_header<'a>(buf: &'a [u8], name: &str) -> Result<Option<&'a str>, Error> {
+    let headers_start = find(buf, b"\r\n").ok_or(Error::Truncated)? + 2;
+
+    let value = buf[headers_start..]
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let separator_idx = line.iter().position(|&b| b == b':')?;
+            let (header_name, value) = line.split_at(separator_idx);
+            header_name.eq_ignore_ascii_case(name.as_bytes()).then(|| &value[1..])
+        });
+
+    value
+        .map(|value| {
+            core::str::from_utf8(value)
+                .map(str::trim)
+                .map_err(|_| Error::InvalidPayload)
+        })
+        .transpose()
 }
 
 /// Rewrite request to convert request URI from absolute-form to origin-form
@@ -664,6 +770,26 @@ fn encode_response(buf: &mut BytesMut, status_code: StatusCode) {
     put(buf, b"\r\n\r\n");
 }
 
+fn encode_challenge(buf: &mut BytesMut, realm: &str) {
+    let status_code = StatusCode::Failure(ErrorCode::ProxyAuthenticationRequired);
+
+    put(buf, b"HTTP/1.1 ");
+    put(buf, status_code.to_string().as_bytes());
+    put(buf, b"\r\n");
+
+    // The realm is a quoted-string (see RFC 7230, section 3.2.6)
+    put(buf, b"Proxy-Authenticate: Basic realm=\"");
+    for c in realm.chars() {
+        if c == '"' || c == '\\' {
+            put(buf, b"\\");
+        }
+        put(buf, c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    put(buf, b"\"\r\n");
+
+    put(buf, b"\r\n");
+}
+
 fn decode_response(buf: &[u8]) -> Result<u16, Error> {
     let status_line_end_idx = find(buf, b"\r\n").ok_or(Error::Truncated)?;
     let status_line = core::str::from_utf8(&buf[..status_line_end_idx]).map_err(|_| Error::InvalidPayload)?;
@@ -689,6 +815,8 @@ fn find_frame_length(buf: &[u8]) -> Option<usize> {
 
 #[cfg(test)]
 mod tests {
+    #![allow(clippy::unwrap_used)]
+
     use super::*;
     use proptest::prelude::*;
     use proxy_generators as generators;
@@ -815,4 +943,77 @@ mod tests {
         assert_eq!(length, 14);
         assert_eq!(&payload[..length], b"Hello Sir.\r\n\r\n");
     }
+
+    #[tokio::test]
+    async fn client_retries_with_credentials_after_challenge() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        let acceptor = tokio::spawn(HttpProxyAcceptor::accept_with_challenge(server, r#"jet "proxy""#));
+
+        client
+            .write_all(b"CONNECT devolutions.net:443 HTTP/1.1\r\nHost: devolutions.net:443\r\n\r\n")
+            .await
+            .unwrap();
+
+        let challenge = Frame::read(&mut client, Bytes::new()).await.unwrap();
+        assert_eq!(
+            challenge.payload(),
+            b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"jet \\\"proxy\\\"\"\r\n\r\n"
+        );
+
+        client
+            .write_all(b"CONNECT devolutions.net:443 HTTP/1.1\r\nHost: devolutions.net:443\r\nproxy-authorization: Basic dXNlcjpwYXNz\r\n\r\n")
+            .await
+            .unwrap();
+
+        let acceptor = acceptor.await.unwrap().unwrap();
+        assert!(matches!(acceptor, HttpProxyAcceptor::TunnelRequest(_)));
+        assert_eq!(acceptor.proxy_authorization(), Some("Basic dXNlcjpwYXNz"));
+        assert_eq!(acceptor.dest_addr(), &("devolutions.net", 443).to_dest_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn pipelined_bytes_are_returned_as_leftover_on_split() {
+        const CLIENT_HELLO: &[u8] = &[0x16, 0x03, 0x01, 0x00, 0x05, 0x01, 0x00, 0x00, 0x01, 0x03];
+
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        let mut request = b"CONNECT devolutions.net:443 HTTP/1.1\r\nHost: devolutions.net:443\r\n\r\n".to_vec();
+        request.extend_from_slice(CLIENT_HELLO);
+        client.write_all(&request).await.unwrap();
+
+        let HttpProxyAcceptor::TunnelRequest(tunnel_request) = HttpProxyAcceptor::accept(server).await.unwrap() else {
+            panic!("expected a tunnel request");
+        };
+        let (mut reader, mut writer, leftover) = tunnel_request.success().await.unwrap().into_split();
+        assert_eq!(leftover.as_deref(), Some(CLIENT_HELLO));
+
+        let response = Frame::read(&mut client, Bytes::new()).await.unwrap();
+        assert_eq!(response.payload(), b"HTTP/1.1 200 Connection Established\r\n\r\n");
+
+        client.write_all(b"next").await.unwrap();
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"next");
+
+        writer.write_all(b"back").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"back");
+    }
+
+    #[tokio::test]
+    async fn no_leftover_on_split_without_pipelined_bytes() {
+        let (mut client, server) = tokio::io::duplex(1024);
+
+        client
+            .write_all(b"CONNECT devolutions.net:443 HTTP/1.1\r\nHost: devolutions.net:443\r\n\r\n")
+            .await
+            .unwrap();
+
+        let HttpProxyAcceptor::TunnelRequest(tunnel_request) = HttpProxyAcceptor::accept(server).await.unwrap() else {
+            panic!("expected a tunnel request");
+        };
+        let (_, _, leftover) = tunnel_request.success().await.unwrap().into_split();
+        assert!(leftover.is_none());
+    }
 }
@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use crate::session::{ConnectionModeDetails, SessionInfo, SessionMessageSender};
 use crate::subscriber::SubscriberSender;
+use crate::target_addr::TargetAddr;
 use crate::token::{JmuxTokenClaims, RecordingPolicy};
 
 use anyhow::Context as _;
@@ -18,7 +19,7 @@ pub async fn handle(
     sessions: SessionMessageSender,
     subscriber_tx: SubscriberSender,
 ) -> anyhow::Result<()> {
-    use jmux_proxy::{FilteringRule, JmuxConfig};
+    use jmux_proxy::JmuxConfig;
 
     match claims.jet_rec {
         RecordingPolicy::None | RecordingPolicy::Stream => (),
@@ -31,21 +32,8 @@ pub async fn handle(
 
     let main_destination_host = claims.hosts.first().clone();
 
-    let config = JmuxConfig {
-        filtering: FilteringRule::Any(
-            claims
-                .hosts
-                .into_iter()
-                .map(|addr| {
-                    if addr.host() == "*" {
-                        FilteringRule::port(addr.port())
-                    } else {
-                        FilteringRule::wildcard_host(addr.host().to_owned()).and(FilteringRule::port(addr.port()))
-                    }
-                })
-                .collect(),
-        ),
-    };
+    let additional_hosts = claims.hosts.tail().iter().map(TargetAddr::as_addr).collect::<Vec<_>>();
+    let config = JmuxConfig::from_allowed_hosts(claims.hosts.first().as_addr(), &additional_hosts);
 
     let session_id = claims.jet_aid;
 
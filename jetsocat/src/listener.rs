@@ -175,6 +175,9 @@ fn jmux_to_socks_error(code: jmux_proto::ReasonCode) -> proxy_socks::Socks5Failu
         ReasonCode::CONNECTION_REFUSED => Socks5FailureCode::ConnectionRefused,
         ReasonCode::TTL_EXPIRED => Socks5FailureCode::TtlExpired,
         ReasonCode::ADDRESS_TYPE_NOT_SUPPORTED => Socks5FailureCode::AddressTypeNotSupported,
+        ReasonCode::AUTHORIZATION_REVOKED | ReasonCode::SESSION_EXPIRED => {
+            Socks5FailureCode::ConnectionNotAllowedByRuleset
+        }
         _ => Socks5FailureCode::GeneralSocksServerFailure,
     }
 }
@@ -241,7 +244,7 @@ async fn http_process_socket(api_request_tx: ApiRequestSender, incoming: TcpStre
         .send(JmuxApiRequest::Start {
             id,
             stream,
-            leftover: Some(leftover),
+            leftover: (!leftover.is_empty()).then_some(leftover),
         })
         .await;
 
@@ -260,6 +263,7 @@ fn jmux_to_http_error_code(code: jmux_proto::ReasonCode) -> proxy_http::ErrorCod
         ReasonCode::CONNECTION_REFUSED => ErrorCode::BadGateway,
         ReasonCode::TTL_EXPIRED => ErrorCode::RequestTimeout,
         ReasonCode::ADDRESS_TYPE_NOT_SUPPORTED => ErrorCode::BadRequest,
+        ReasonCode::AUTHORIZATION_REVOKED | ReasonCode::SESSION_EXPIRED => ErrorCode::Forbidden,
         _ => ErrorCode::InternalServerError,
     }
 }
@@ -191,6 +191,7 @@ async fn server_side_jmux(port: u16, kind: TransportKind) -> anyhow::Result<()>
         watch_process: None,
         jmux_cfg: JmuxConfig {
             filtering: filtering_rule,
+            ..JmuxConfig::default()
         },
     };
 